@@ -8,7 +8,7 @@ fn get_git_rev() -> Option<String> {
 		.ok()?;
 
 	if output.status.success() {
-		String::from_utf8(output.stdout).ok()
+		String::from_utf8(output.stdout).ok().map(|rev| rev.trim().to_string())
 	} else {
 		None
 	}
@@ -26,4 +26,8 @@ fn main() {
 	if let Some(rev) = get_git_rev() {
 		println!("cargo:rustc-env=GIT_REV={}", rev);
 	}
+
+	// Generating the gRPC service needs `protoc`, which is why it's behind a feature
+	#[cfg(feature = "grpc")]
+	tonic_build::compile_protos("proto/tinyvector.proto").expect("failed to compile the gRPC protos");
 }
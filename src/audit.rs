@@ -0,0 +1,125 @@
+//! Append-only audit trail of every request, enabled by pointing `AUDIT_LOG_PATH` at a file.
+//!
+//! Each request is written as one JSON line. Handlers only push records onto a channel;
+//! a background task appends them to the file and flushes after every batch.
+
+use anyhow::Result;
+use axum::{
+	extract::{MatchedPath, State},
+	http::{HeaderValue, Request},
+	middleware::Next,
+	response::Response,
+};
+use std::env;
+use tokio::{
+	fs::OpenOptions,
+	io::{AsyncWriteExt, BufWriter},
+	sync::mpsc,
+};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+	sender: mpsc::UnboundedSender<AuditRecord>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct AuditRecord {
+	/// RFC 3339 time the request was received
+	timestamp: String,
+	/// Also returned to the client in the `x-request-id` header
+	request_id: String,
+	/// Method and route template, such as `POST /collections/:collection_name/insert`
+	operation: String,
+	/// Path that was requested
+	path: String,
+	collection: Option<String>,
+	status: u16,
+}
+
+impl AuditLog {
+	/// Open the audit log at `AUDIT_LOG_PATH`, or `None` when auditing is off
+	pub async fn from_env() -> Result<Option<Self>> {
+		let Ok(path) = env::var("AUDIT_LOG_PATH") else {
+			return Ok(None);
+		};
+
+		let file = OpenOptions::new().create(true).append(true).open(&path).await?;
+		let (sender, receiver) = mpsc::unbounded_channel();
+		tokio::spawn(write_records(BufWriter::new(file), receiver));
+
+		tracing::info!("Writing audit log to {path}");
+		Ok(Some(Self { sender }))
+	}
+}
+
+async fn write_records(
+	mut file: BufWriter<tokio::fs::File>,
+	mut receiver: mpsc::UnboundedReceiver<AuditRecord>,
+) {
+	while let Some(record) = receiver.recv().await {
+		let mut batch = vec![record];
+		while let Ok(record) = receiver.try_recv() {
+			batch.push(record);
+		}
+
+		let mut lines = Vec::new();
+		for record in &batch {
+			if let Err(err) = serde_json::to_writer(&mut lines, record) {
+				tracing::error!("Couldn't serialize audit record {}: {err}", record.request_id);
+				continue;
+			}
+			lines.push(b'\n');
+		}
+
+		if let Err(err) = async {
+			file.write_all(&lines).await?;
+			file.flush().await
+		}
+		.await
+		{
+			tracing::error!("Couldn't write {} audit records: {err}", batch.len());
+		}
+	}
+}
+
+/// Record the request in the audit log, if there is one, once the response is ready
+pub async fn record<B>(State(audit): State<Option<AuditLog>>, req: Request<B>, next: Next<B>) -> Response {
+	let Some(audit) = audit else {
+		return next.run(req).await;
+	};
+
+	let timestamp = chrono::Utc::now().to_rfc3339();
+	let request_id = Uuid::new_v4().to_string();
+	let route = req
+		.extensions()
+		.get::<MatchedPath>()
+		.map_or_else(|| req.uri().path().to_string(), |path| path.as_str().to_string());
+	let operation = format!("{} {route}", req.method());
+	let path = req.uri().path().to_string();
+	// Only routes templated on a collection name carry one, which rules out e.g. `/collections/delete_batch`
+	let collection = route
+		.starts_with("/collections/:collection_name")
+		.then(|| path.trim_start_matches("/collections/").split('/').next())
+		.flatten()
+		.map(str::to_string);
+
+	let mut response = next.run(req).await;
+
+	if let Ok(value) = HeaderValue::from_str(&request_id) {
+		response.headers_mut().insert("x-request-id", value);
+	}
+	let record = AuditRecord {
+		timestamp,
+		request_id,
+		operation,
+		path,
+		collection,
+		status: response.status().as_u16(),
+	};
+	if audit.sender.send(record).is_err() {
+		tracing::error!("Audit log writer has stopped, dropping audit record");
+	}
+
+	response
+}
@@ -0,0 +1,157 @@
+use axum::Extension;
+use std::{
+	collections::{hash_map::DefaultHasher, HashMap},
+	env,
+	hash::{Hash, Hasher},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use crate::db::SimilarityResult;
+
+/// Number of cached queries past which expired entries get evicted
+const MAX_ENTRIES: usize = 1024;
+
+/// Query vectors are rounded to this many decimals before hashing, so tiny float noise still hits the cache
+const ROUNDING_FACTOR: f32 = 1e6;
+
+#[allow(clippy::module_name_repetitions)]
+pub type QueryCacheExtension = Extension<Arc<QueryCache>>;
+
+/// Cache of similarity results, opt-in through `QUERY_CACHE_TTL_SECS`. The default caches nothing.
+#[derive(Debug, Default)]
+pub struct QueryCache {
+	ttl: Option<Duration>,
+	entries: Mutex<HashMap<u64, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+	generation: u64,
+	created_at: Instant,
+	results: Vec<SimilarityResult>,
+}
+
+impl QueryCache {
+	/// A cache keeping results for `ttl`, or caching nothing without one
+	pub fn new(ttl: Option<Duration>) -> Self {
+		Self {
+			ttl,
+			entries: Mutex::default(),
+		}
+	}
+
+	pub fn from_env() -> anyhow::Result<Self> {
+		let ttl = env::var("QUERY_CACHE_TTL_SECS")
+			.ok()
+			.map(|ttl| ttl.parse())
+			.transpose()?
+			.map(Duration::from_secs);
+
+		Ok(Self::new(ttl))
+	}
+
+	pub fn extension(self) -> QueryCacheExtension {
+		Extension(Arc::new(self))
+	}
+
+	pub fn key(collection_name: &str, query: &[f32], k: usize) -> u64 {
+		let mut hasher = DefaultHasher::new();
+
+		collection_name.hash(&mut hasher);
+		k.hash(&mut hasher);
+		for value in query {
+			((value * ROUNDING_FACTOR).round() / ROUNDING_FACTOR)
+				.to_bits()
+				.hash(&mut hasher);
+		}
+
+		hasher.finish()
+	}
+
+	/// Cached results for `key`, as long as the collection hasn't changed since they were stored
+	pub fn get(&self, key: u64, generation: u64) -> Option<Vec<SimilarityResult>> {
+		let ttl = self.ttl?;
+		let entries = self.entries.lock().unwrap();
+
+		entries
+			.get(&key)
+			.filter(|entry| entry.generation == generation && entry.created_at.elapsed() < ttl)
+			.map(|entry| entry.results.clone())
+	}
+
+	pub fn insert(&self, key: u64, generation: u64, results: &[SimilarityResult]) {
+		let Some(ttl) = self.ttl else {
+			return;
+		};
+		let mut entries = self.entries.lock().unwrap();
+
+		if entries.len() >= MAX_ENTRIES {
+			entries.retain(|_, entry| entry.created_at.elapsed() < ttl);
+		}
+		if entries.len() >= MAX_ENTRIES {
+			entries.clear();
+		}
+
+		entries.insert(
+			key,
+			Entry {
+				generation,
+				results: results.to_vec(),
+				created_at: Instant::now(),
+			},
+		);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use rand::{rngs::StdRng, Rng, SeedableRng};
+	use std::time::Instant;
+
+	use super::*;
+	use crate::{
+		db::{Collection, Embedding},
+		similarity::Distance,
+	};
+
+	/// Latency of a repeated query answered by scanning, and then from the cache.
+	/// Run with `cargo test --release bench_repeated_query -- --ignored --nocapture`.
+	#[test]
+	#[ignore = "benchmark"]
+	fn bench_repeated_query() {
+		const EMBEDDINGS: usize = 100_000;
+		const DIMENSION: usize = 256;
+		const REPEATS: u32 = 100;
+
+		let mut rng = StdRng::seed_from_u64(0);
+		let mut collection = Collection::new(DIMENSION, Distance::DotProduct);
+		collection.embeddings = (0..EMBEDDINGS)
+			.map(|id| Embedding {
+				id: id.to_string(),
+				vector: (0..DIMENSION).map(|_| rng.gen::<f32>()).collect::<Vec<_>>().into(),
+				metadata: None,
+				seq: 0,
+				touched_at: 0,
+			})
+			.collect();
+		let query: Vec<f32> = (0..DIMENSION).map(|_| rng.gen()).collect();
+		let cache = QueryCache::new(Some(Duration::from_secs(60)));
+		let key = QueryCache::key("bench", &query, 10);
+
+		let instant = Instant::now();
+		for _ in 0..REPEATS {
+			std::hint::black_box(collection.get_similarity(&query, 10));
+		}
+		let scanned = instant.elapsed() / REPEATS;
+
+		cache.insert(key, collection.generation, &collection.get_similarity(&query, 10));
+		let instant = Instant::now();
+		for _ in 0..REPEATS {
+			std::hint::black_box(cache.get(key, collection.generation).unwrap());
+		}
+		let cached = instant.elapsed() / REPEATS;
+
+		println!("{EMBEDDINGS} x {DIMENSION}: {scanned:?} per scanned query, {cached:?} per cached one");
+	}
+}
@@ -4,18 +4,111 @@ use rayon::prelude::*;
 use schemars::JsonSchema;
 use anyhow::Context;
 use lazy_static::lazy_static;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use std::{
-	collections::{BinaryHeap, HashMap},
+	collections::{BinaryHeap, HashMap, HashSet},
+	env,
 	fs::{self},
 	path::PathBuf,
-	sync::Arc,
+	sync::{
+		atomic::{AtomicBool, AtomicU64, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::sync::RwLock;
 
-use crate::similarity::{get_cache_attr, get_distance_fn, normalize, Distance, ScoreIndex};
+use crate::{
+	latency::LatencyWindow,
+	mmap,
+	similarity::{
+		get_cache_attr, get_vector_distance_fn, is_binary, is_finite, magnitude, normalize, DimensionStats, Distance,
+		shrink_vec, Normalization, Precision, ScoreIndex, Vector,
+	},
+};
+
+/// Candidates taken from each ranking before they're fused by `hybrid_search`
+const HYBRID_CANDIDATES: usize = 100;
+
+/// Top-k selection sorts every score instead of keeping a heap once `k` is at least `1 / FULL_SORT_FRACTION` of
+/// the embeddings scanned. Selecting from a million random scores on one core (`bench_top_k_selection`), the heap
+/// took 2.6ms against sorting's 50ms at k/n = 0.001, 54ms against 43ms at 0.1 and 399ms against 46ms at 0.9,
+/// crossing over at around 0.08.
+const FULL_SORT_FRACTION: usize = 12;
+
+/// Embeddings scored at once when selecting the top k with a heap, bounding the scores held in memory
+const SCAN_TILE_SIZE: usize = 16 * 1024;
+
+/// Number of results similarity queries return when neither they nor their collection give one
+const DEFAULT_K: usize = 1;
+
+/// Largest metadata value accepted when neither the collection nor `MAX_METADATA_VALUE_BYTES` give a limit
+const DEFAULT_MAX_METADATA_VALUE_BYTES: usize = 64 * 1024;
+
+/// Most metadata keys an embedding can have when neither the collection nor `MAX_METADATA_KEYS` give a limit
+const DEFAULT_MAX_METADATA_KEYS: usize = 256;
+
+/// Longest collection name accepted when `COLLECTION_NAME_MAX_LENGTH` isn't set
+const DEFAULT_MAX_NAME_LENGTH: usize = 64;
+
+/// Paths under `/collections` that a collection name would be shadowed by
+const RESERVED_NAMES: &[&str] = &["delete_batch", "query_multi_collection"];
+
+/// Routes under `/collections/:collection_name`, which an embedding with the same id couldn't be looked up, deleted
+/// or touched past
+const RESERVED_IDS: &[&str] = &[
+	"batch_ops",
+	"changes",
+	"compact",
+	"export",
+	"export.parquet",
+	"find_duplicates",
+	"hybrid",
+	"ids",
+	"import_csv",
+	"import_ndjson",
+	"insert",
+	"insert_batch",
+	"metadata_keys",
+	"query",
+	"query_arithmetic",
+	"query_sparse",
+	"query_stream",
+	"query_text",
+	"querynum",
+	"reembed",
+	"reserve",
+	"sample",
+	"verify",
+	"warmup",
+];
 
 lazy_static! {
 	pub static ref STORE_PATH: PathBuf = PathBuf::from("./storage/db");
+	/// Deletes from a collection after which it's compacted automatically, from `COMPACT_AFTER_DELETES`
+	static ref COMPACT_AFTER_DELETES: Option<usize> = env::var("COMPACT_AFTER_DELETES")
+		.ok()
+		.and_then(|deletes| deletes.parse().ok());
+	/// Fewest embeddings a rayon task scores during a similarity scan, from `SCAN_MIN_CHUNK`. Larger chunks keep each
+	/// thread on a longer contiguous run of vectors, which can cut cross-socket memory traffic on NUMA machines.
+	/// Defaults to 1, leaving the splitting to rayon.
+	static ref SCAN_MIN_CHUNK: usize = env::var("SCAN_MIN_CHUNK")
+		.ok()
+		.and_then(|chunk| chunk.parse().ok())
+		.filter(|&chunk| chunk > 0)
+		.unwrap_or(1);
+	static ref MAX_METADATA_VALUE_BYTES: usize = env::var("MAX_METADATA_VALUE_BYTES")
+		.ok()
+		.and_then(|bytes| bytes.parse().ok())
+		.unwrap_or(DEFAULT_MAX_METADATA_VALUE_BYTES);
+	static ref MAX_METADATA_KEYS: usize = env::var("MAX_METADATA_KEYS")
+		.ok()
+		.and_then(|keys| keys.parse().ok())
+		.unwrap_or(DEFAULT_MAX_METADATA_KEYS);
+	static ref MAX_NAME_LENGTH: usize = env::var("COLLECTION_NAME_MAX_LENGTH")
+		.ok()
+		.and_then(|length| length.parse().ok())
+		.unwrap_or(DEFAULT_MAX_NAME_LENGTH);
 }
 
 #[allow(clippy::module_name_repetitions)]
@@ -26,25 +119,270 @@ pub enum Error {
 	#[error("Collection already exists")]
 	UniqueViolation,
 
+	#[error(
+		"Collection and alias names must be 1 to {} ASCII letters, digits, '-' or '_', other than {}",
+		*MAX_NAME_LENGTH,
+		RESERVED_NAMES.join(" or ")
+	)]
+	InvalidName,
+
+	#[error("{0} is the path of a collection route, so it can't be used as an embedding ID")]
+	ReservedId(String),
+
 	#[error("Collection doesn't exist")]
 	NotFound,
 
 	#[error("The dimension of the vector doesn't match the dimension of the collection")]
 	DimensionMismatch,
 
+	#[error("Alias doesn't exist")]
+	AliasNotFound,
+
+	#[error("Collections using different distance metrics can't be ranked together")]
+	MixedDistances,
+
 	#[error("ID doesn't exist within collection")]
-	IDNotFound
+	IDNotFound,
+
+	#[error("The vector has zero magnitude and can't be normalized to unit length")]
+	InvalidVector,
+
+	#[error("Invalid metadata: {0}")]
+	InvalidMetadata(String),
+
+	#[error("Weighted distance needs one finite, non-negative weight per dimension")]
+	InvalidWeights,
+
+	#[error("Hamming distance compares vectors bit by bit, so they can't be normalized")]
+	HammingNormalization,
+
+	#[error("Vectors in collections using Hamming distance can only contain 0s and 1s")]
+	NonBinaryVector,
+
+	#[error("Vectors can only contain finite numbers")]
+	NonFiniteVector,
+
+	#[error("A value range needs finite bounds, with min no greater than max")]
+	InvalidValueRange,
+
+	#[error("Component {dimension} of the vector ({value}) is outside the collection's value range")]
+	OutOfRange { dimension: usize, value: f32 },
+
+	#[error("Too similar to existing embedding {0}")]
+	NearDuplicate(String),
+
+	#[error("The database is read-only")]
+	ReadOnly,
+
+	#[error("Couldn't allocate room for {0} more embeddings")]
+	CapacityOverflow(usize),
+
+	#[error("Operation {index} of the batch failed: {source}")]
+	BatchFailed {
+		index: usize,
+		#[source]
+		source: Box<Error>,
+	},
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct Db {
 	pub collections: HashMap<String, Collection>,
+	/// Alternative names for collections, resolved wherever a collection's embeddings are read or written
+	pub aliases: HashMap<String, String>,
+	/// Rejects every mutation and never writes to the store
+	#[serde(skip)]
+	pub read_only: bool,
+	/// Accepts mutations but keeps them in memory only, never writing to the store
+	#[serde(skip)]
+	pub ephemeral: bool,
+}
+
+/// Embeddings a similarity query leaves out. Excluded ids and near-exact matches are skipped before the best `k` are picked.
+#[derive(Debug, Clone, Default)]
+pub struct Exclusions {
+	pub ids: HashSet<String>,
+	/// Leave out embeddings scoring within this of the query, the way `Distance::within` compares them
+	pub within: Option<f32>,
+	/// Leave out embeddings that don't score within this of the query, so only matches at least this close are kept
+	pub threshold: Option<f32>,
+	/// Leave out results scoring below this percentile (0 to 100) of every score the query computed.
+	/// It's applied to the best `k`, so a query returns whichever of the two cutoffs is stricter.
+	pub percentile: Option<f32>,
 }
 
+impl Exclusions {
+	pub fn is_empty(&self) -> bool {
+		self.ids.is_empty() && self.within.is_none() && self.threshold.is_none() && self.percentile.is_none()
+	}
+}
+
+/// An embedding matching a query, along with how well it matches
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
 pub struct SimilarityResult {
+	/// Similarity or distance between the query and the embedding, in the collection's `distance` metric.
+	///
+	/// The direction depends on the metric, which the collection's `higher_is_better` reports: `cosine` (-1 to 1)
+	/// and `dot` (unbounded) are higher for closer matches, while `euclidean` and `weighted` (0 upwards) are
+	/// lower for closer matches. Hybrid search scores are fused ranks instead, where higher is always better.
+	pub score: f32,
+	/// 1-based position of the result among everything the query ranked, best first
+	#[serde(default)]
+	pub rank: usize,
+	pub embedding: Embedding,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum BatchOperation {
+	/// Insert an embedding, replacing any existing one with the same id
+	Insert(Embedding),
+	/// Delete the embedding with the given id
+	Delete { id: String },
+}
+
+/// What a bulk insert does with an embedding whose id already exists in the collection
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+	/// Keep the existing embedding
+	Skip,
+	/// Replace the existing embedding
+	#[default]
+	Overwrite,
+	/// Reject the new embedding
+	Error,
+}
+
+/// Outcome of inserting a single embedding as part of a bulk insert
+#[derive(Debug, serde::Serialize, JsonSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum InsertOutcome {
+	Inserted {
+		id: String,
+		/// Embedding evicted to stay within the collection's `max_embeddings`
+		evicted: Option<String>,
+	},
+	Skipped { id: String },
+	Updated { id: String },
+	Error { id: String, reason: String },
+}
+
+impl InsertOutcome {
+	const fn changed(&self) -> bool {
+		matches!(self, Self::Inserted { .. } | Self::Updated { .. })
+	}
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct CollectionSimilarityResult {
+	/// Collection the embedding was found in
+	collection: String,
+	#[serde(flatten)]
+	result: SimilarityResult,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct MultiCollectionResults {
+	/// Top results across all queried collections, best first
+	results: Vec<CollectionSimilarityResult>,
+	/// Requested collections that don't exist
+	missing: Vec<String>,
+}
+
+/// Breakdown of the work done to answer a similarity query
+#[derive(Debug, Default, serde::Serialize, JsonSchema)]
+pub struct QueryTiming {
+	/// Time spent scoring embeddings against the query, in microseconds
+	pub scoring_us: u64,
+	/// Time spent selecting the best `k` scores, in microseconds
+	pub heap_us: u64,
+	/// Time spent handling the request, in microseconds
+	pub total_us: u64,
+	/// Number of embeddings scored
+	pub scanned: usize,
+	/// Whether embeddings were skipped before scoring, such as ones without a vector
+	pub prefiltered: bool,
+	/// Whether the results came from the query cache, in which case nothing was scored
+	pub cache_hit: bool,
+}
+
+/// A problem with a stored embedding found by `Collection::verify`
+#[derive(Debug, serde::Serialize, JsonSchema)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum Violation {
+	/// The vector's length doesn't match the collection's dimension
+	DimensionMismatch { id: String, len: usize },
+	/// Another embedding earlier in the collection has the same id
+	DuplicateId { id: String },
+	/// The vector contains NaN or infinite components
+	NonFinite { id: String },
+	/// The collection expects unit vectors, but this one isn't
+	NotUnitLength { id: String, magnitude: f32 },
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct CompactReport {
+	/// Embeddings in the collection
+	embeddings: usize,
+	/// Embeddings deleted since the previous compaction
+	deletes: usize,
+	/// Approximate bytes of excess capacity released
+	bytes_reclaimed: usize,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct WarmupReport {
+	/// Embeddings whose vectors were read
+	scanned: usize,
+	/// How long the pass took, in microseconds
+	took_us: u64,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct VerifyReport {
+	/// Number of embeddings checked
+	checked: usize,
+	/// Whether no violations were found
+	healthy: bool,
+	violations: Vec<Violation>,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct DuplicatePair {
+	first: String,
+	second: String,
 	score: f32,
-	embedding: Embedding,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct AffectedEmbeddings {
+	/// Number of embeddings affected
+	count: usize,
+	/// Ids of the affected embeddings
+	ids: Vec<String>,
+}
+
+impl AffectedEmbeddings {
+	pub fn from_embeddings<'a>(embeddings: impl IntoIterator<Item = &'a Embedding>) -> Self {
+		let ids: Vec<String> = embeddings
+			.into_iter()
+			.map(|embedding| embedding.id.clone())
+			.collect();
+
+		Self {
+			count: ids.len(),
+			ids,
+		}
+	}
+}
+
+#[derive(Debug, Default, serde::Serialize, JsonSchema)]
+pub struct DeleteSummary {
+	/// Collections that were deleted
+	deleted: Vec<String>,
+	/// Collections that didn't exist
+	not_found: Vec<String>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, JsonSchema)]
@@ -67,240 +405,2195 @@ impl MetadataEqualities {
             _ => None,
         }
     }
+
+	/// Whether a metadata value ordered `ordering` relative to the queried value satisfies this comparison
+	pub const fn holds(&self, ordering: std::cmp::Ordering) -> bool {
+		match self {
+			Self::GreaterEqualThan => ordering.is_ge(),
+			Self::GreaterThan => ordering.is_gt(),
+			Self::LesserEqualThan => ordering.is_le(),
+			Self::LesserThan => ordering.is_lt(),
+			Self::Equal => ordering.is_eq(),
+		}
+	}
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
-pub struct Collection {
-	/// Dimension of the vectors in the collection
-	pub dimension: usize,
-	/// Distance metric used for querying
-	pub distance: Distance,
-	/// Embeddings in the collection
-	#[serde(default)]
-	pub embeddings: Vec<Embedding>,
+/// Value numeric metadata queries compare against. Metadata values are resolved as follows:
+/// - booleans match `"true"`/`"false"` metadata (case-insensitively), and only support `equal`
+/// - integers compare exactly, as `i64`, against integral metadata, and as `f64` against fractional metadata
+/// - any other number compares as `f64`
+#[derive(Debug, Clone, Copy, serde::Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum MetadataNumber {
+	Bool(bool),
+	Integer(i64),
+	Float(f64),
 }
 
-impl Collection {
-	pub fn get_id(&self, id: &String) -> Option<Embedding>{
-		self.embeddings
-		.iter()
-		.find(|embedding| &embedding.id == id)
-		.cloned()
+impl MetadataNumber {
+	/// How `meta_value` orders relative to this value, if they're comparable
+	#[allow(clippy::cast_precision_loss)]
+	pub fn compare(self, meta_value: &str) -> Option<std::cmp::Ordering> {
+		match self {
+			Self::Bool(value) => meta_value
+				.to_lowercase()
+				.parse::<bool>()
+				.ok()
+				.map(|meta_value| meta_value.cmp(&value)),
+			Self::Integer(value) => match meta_value.parse::<i64>() {
+				Ok(meta_value) => Some(meta_value.cmp(&value)),
+				Err(_) => meta_value.parse::<f64>().ok()?.partial_cmp(&(value as f64)),
+			},
+			Self::Float(value) => meta_value.parse::<f64>().ok()?.partial_cmp(&value),
+		}
 	}
+}
 
-	pub fn delete_id(&mut self, id: &String) -> Result<Embedding, Error>{
-		 if let Some(index) = self.embeddings.iter().position(|embedding| &embedding.id == id) {
-            // Remove the embedding from the vector and return it
-            Ok(self.embeddings.remove(index))
-        } else {
-            // If the id is not found, return an error
-            return Err(Error::IDNotFound);
-        }
+/// One comparison of a numeric metadata query
+#[derive(Debug, Clone)]
+pub struct NumberCondition {
+	pub key: String,
+	pub value: MetadataNumber,
+	pub equality: MetadataEqualities,
+}
+
+impl NumberCondition {
+	/// Whether `metadata` has the key with a value satisfying the comparison.
+	/// Lists satisfy it when any of their elements does.
+	fn matches(&self, metadata: &Metadata) -> bool {
+		metadata.get(&self.key).is_some_and(|meta_value| {
+			meta_value
+				.values()
+				.iter()
+				.filter_map(|meta_value| self.value.compare(meta_value))
+				.any(|ordering| self.equality.holds(ordering))
+		})
 	}
-	pub fn get_metadata_string(&self, key: &String, value: &String, k: usize) -> Vec<Embedding>{
-		let filtered_embeddings: Vec<Embedding> = self.embeddings
-            .iter()
-            .filter(|embedding| {
-                if let Some(metadata) = &embedding.metadata {
-                    if let Some(meta_value) = metadata.get(key) {
-                        return meta_value == value;
-                    }
-                }
-                false
-            })
-            .cloned()
-            .collect();
-		
-		filtered_embeddings.into_iter().take(k).collect()
-    }
+}
 
-	pub fn get_metadata_number(&self, key: &str, value: f32, equality: MetadataEqualities, k: usize) -> Vec<Embedding> {
-        // Filter embeddings based on the specified key and value comparison
-        let filtered_embeddings: Vec<Embedding> =  self.embeddings
-            .iter()
-            .filter(|embedding| {
-                if let Some(metadata) = &embedding.metadata {
-                    if let Some(meta_value_str) = metadata.get(key) {
-                        if let Ok(meta_value) = meta_value_str.parse::<f32>() {
-							match equality {
-								MetadataEqualities::GreaterEqualThan => {return meta_value >= value;},
-								MetadataEqualities::GreaterThan => {return meta_value > value;},
-								MetadataEqualities::LesserEqualThan => {return meta_value <= value;}
-								MetadataEqualities::LesserThan => {return meta_value < value;}
-								MetadataEqualities::Equal => {return meta_value == value;}
-							}
-                            
-                        }
-                    }
-                }
-                false
-            })
-            .cloned()
-            .collect();
-		
-		filtered_embeddings.into_iter().take(k).collect()
-    }
+/// How a collection's ids sort, for exports and id listings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdOrder {
+	/// Every id is an integer, so ids sort by value and "2" comes before "10"
+	Numeric,
+	Lexicographic,
+}
 
-	pub fn get_similarity(&self, query: &[f32], k: usize) -> Vec<SimilarityResult> {
-		let memo_attr = get_cache_attr(self.distance, query);
-		let distance_fn = get_distance_fn(self.distance);
+impl IdOrder {
+	pub fn compare(self, a: &str, b: &str) -> std::cmp::Ordering {
+		match self {
+			Self::Numeric => match (a.parse::<i64>(), b.parse::<i64>()) {
+				(Ok(a), Ok(b)) => a.cmp(&b),
+				_ => a.cmp(b),
+			},
+			Self::Lexicographic => a.cmp(b),
+		}
+	}
+}
 
-		let scores = self
-			.embeddings
-			.par_iter()
-			.enumerate()
-			.map(|(index, embedding)| {
-				let score = distance_fn(&embedding.vector, query, memo_attr);
-				ScoreIndex { score, index }
-			})
-			.collect::<Vec<_>>();
+/// Bounds every component of inserted vectors has to stay within
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq)]
+pub struct ValueRange {
+	pub min: f32,
+	pub max: f32,
+	/// What happens to vectors with components outside the range
+	#[serde(default)]
+	pub out_of_range: OutOfRange,
+}
 
-		let mut heap = BinaryHeap::new();
-		for score_index in scores {
-			if heap.len() < k || score_index < *heap.peek().unwrap() {
-				heap.push(score_index);
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfRange {
+	/// Reject the insert, naming the first offending component
+	#[default]
+	Reject,
+	/// Move offending components to the nearest bound and accept the vector. NaN components are still rejected,
+	/// as there's no nearest bound to move them to.
+	Clamp,
+}
 
-				if heap.len() > k {
-					heap.pop();
-				}
+impl ValueRange {
+	/// Check `vector` against the range, clamping it in place when the range says to
+	fn apply(&self, vector: &mut [f32]) -> Result<(), Error> {
+		for (dimension, value) in vector.iter_mut().enumerate() {
+			if (self.min..=self.max).contains(value) {
+				continue;
 			}
+			if self.out_of_range == OutOfRange::Reject || value.is_nan() {
+				return Err(Error::OutOfRange {
+					dimension,
+					value: *value,
+				});
+			}
+			*value = value.clamp(self.min, self.max);
 		}
 
-		heap.into_sorted_vec()
-			.into_iter()
-			.map(|ScoreIndex { score, index }| SimilarityResult {
-				score,
-				embedding: self.embeddings[index].clone(),
-			})
-			.collect()
+		Ok(())
 	}
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
-pub struct Embedding {
-	pub id: String,
-	pub vector: Vec<f32>,
-	pub metadata: Option<HashMap<String, String>>,
+/// Type a metadata value must have under a collection's metadata schema
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataType {
+	/// Any string
+	String,
+	/// A string that parses as a number
+	Number,
 }
 
-impl Db {
-	pub fn new() -> Self {
-		Self {
-			collections: HashMap::new(),
+impl MetadataType {
+	fn accepts(self, value: &str) -> bool {
+		match self {
+			Self::String => true,
+			Self::Number => value.parse::<f64>().is_ok(),
 		}
 	}
+}
 
-	pub fn extension(self) -> DbExtension {
-		Extension(Arc::new(RwLock::new(self)))
-	}
-
-	pub fn create_collection(
-		&mut self,
-		name: String,
-		dimension: usize,
-		distance: Distance,
-	) -> Result<Collection, Error> {
-		if self.collections.contains_key(&name) {
-			return Err(Error::UniqueViolation);
-		}
+pub type Metadata = HashMap<String, MetadataValue>;
 
-		let collection = Collection {
-			dimension,
-			distance,
-			embeddings: Vec::new(),
-		};
+/// A metadata value, either a single string or a list of them for multi-valued keys like tags.
+/// Filters match a list when any of its elements would match on its own.
+#[derive(Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[schemars(untagged)]
+pub enum MetadataValue {
+	Single(String),
+	List(Vec<String>),
+}
 
-		self.collections.insert(name, collection.clone());
-		self.save();
-		Ok(collection)
+impl MetadataValue {
+	/// The value's elements, a single one unless it's a list
+	pub fn values(&self) -> &[String] {
+		match self {
+			Self::Single(value) => std::slice::from_ref(value),
+			Self::List(values) => values,
+		}
 	}
 
-	pub fn delete_collection(&mut self, name: &str) -> Result<(), Error> {
-		if !self.collections.contains_key(name) {
-			return Err(Error::NotFound);
+	pub fn as_single(&self) -> Option<&str> {
+		match self {
+			Self::Single(value) => Some(value),
+			Self::List(_) => None,
 		}
+	}
+}
 
-		self.collections.remove(name);
-		self.save();
-		Ok(())
+impl From<String> for MetadataValue {
+	fn from(value: String) -> Self {
+		Self::Single(value)
 	}
+}
 
-	pub fn insert_into_collection(
-		&mut self,
-		collection_name: &str,
-		mut embedding: Embedding,
-	) -> Result<(), Error> {
-		let collection = self
-			.collections
-			.get_mut(collection_name)
-			.ok_or(Error::NotFound)?;
+/// Metadata values as clients send them, where a plain string is a single value
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ClientMetadataValue {
+	Single(String),
+	List(Vec<String>),
+}
 
-		if embedding.vector.len() != collection.dimension {
-			return Err(Error::DimensionMismatch);
-		}
+#[derive(serde::Deserialize)]
+enum StoredMetadataValue {
+	Single(String),
+	List(Vec<String>),
+}
 
-		// Normalize the vector if the distance metric is cosine, so we can use dot product later
-		if collection.distance == Distance::Cosine {
-			embedding.vector = normalize(&embedding.vector);
+impl serde::Serialize for MetadataValue {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		match (self, serializer.is_human_readable()) {
+			(Self::Single(value), true) => value.serialize(serializer),
+			(Self::List(values), true) => values.serialize(serializer),
+			(Self::Single(value), false) => serializer.serialize_newtype_variant("StoredMetadataValue", 0, "Single", value),
+			(Self::List(values), false) => serializer.serialize_newtype_variant("StoredMetadataValue", 1, "List", values),
 		}
+	}
+}
 
-		if collection.embeddings.iter().any(|e| e.id == embedding.id) {
-			let _ = collection.delete_id(&embedding.id);
+impl<'de> serde::Deserialize<'de> for MetadataValue {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		if deserializer.is_human_readable() {
+			return Ok(match ClientMetadataValue::deserialize(deserializer)? {
+				ClientMetadataValue::Single(value) => Self::Single(value),
+				ClientMetadataValue::List(values) => Self::List(values),
+			});
 		}
 
-		collection.embeddings.push(embedding);
-		self.save();
-		Ok(())
+		Ok(match StoredMetadataValue::deserialize(deserializer)? {
+			StoredMetadataValue::Single(value) => Self::Single(value),
+			StoredMetadataValue::List(values) => Self::List(values),
+		})
 	}
+}
 
-	pub fn collection_delete_id(&mut self, collection_name: &str, id: &String) -> Result<Embedding, Error>{
-		let collection = self
-			.collections
-			.get_mut(collection_name)
-			.ok_or(Error::NotFound)?;
-		let result = collection.delete_id(id);
-		self.save();
-		result
-	}
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct MetadataKey {
+	pub key: String,
+	/// Number of embeddings with a value for the key
+	pub count: usize,
+	/// `number` when every value of the key parses as a number, list elements included, `string` otherwise
+	pub inferred_type: MetadataType,
+}
 
+/// How a metadata string value is compared against the queried value
+#[derive(Debug, Clone, Copy, Default, serde::Serialize, serde::Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StringMatch {
+	/// The value equals the query
+	#[default]
+	Exact,
+	/// The value contains the query
+	Contains,
+	/// The value starts with the query
+	Prefix,
+	/// The value contains the query, ignoring case
+	Icontains,
+}
 
-	pub fn get_collection(&self, name: &str) -> Option<&Collection> {
-		self.collections.get(name)
+impl StringMatch {
+	pub fn matches(self, meta_value: &str, value: &str) -> bool {
+		match self {
+			Self::Exact => meta_value == value,
+			Self::Contains => meta_value.contains(value),
+			Self::Prefix => meta_value.starts_with(value),
+			Self::Icontains => meta_value.to_lowercase().contains(&value.to_lowercase()),
+		}
 	}
+}
 
-	fn load_from_store() -> anyhow::Result<Self> {
-		if !STORE_PATH.exists() {
-			tracing::debug!("Creating database store");
-			fs::create_dir_all(STORE_PATH.parent().context("Invalid store path")?)?;
+/// Inserts, deletes and queries a collection has served since the server started, kept in memory only
+#[derive(Debug, Default)]
+pub struct Counters {
+	inserts: AtomicU64,
+	deletes: AtomicU64,
+	queries: AtomicU64,
+}
 
-			return Ok(Self::new());
-		}
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct CounterSummary {
+	/// Embeddings inserted or updated, including seeds and each embedding of a batch
+	inserts: u64,
+	/// Embeddings deleted by id, not counting evictions or deleting the collection
+	deletes: u64,
+	/// Similarity, hybrid and metadata queries
+	queries: u64,
+}
 
-		tracing::debug!("Loading database from store");
-		let db = fs::read(STORE_PATH.as_path())?;
-		Ok(bincode::deserialize(&db[..])?)
+impl Counters {
+	fn count_insert(&self) {
+		self.inserts.fetch_add(1, Ordering::Relaxed);
 	}
 
-	fn save_to_store(&self) -> anyhow::Result<()> {
-		let db = bincode::serialize(self)?;
-
-		fs::write(STORE_PATH.as_path(), db)?;
+	fn count_delete(&self) {
+		self.deletes.fetch_add(1, Ordering::Relaxed);
+	}
 
-		Ok(())
+	fn count_query(&self) {
+		self.queries.fetch_add(1, Ordering::Relaxed);
 	}
 
-	pub fn save(&self){
-		self.save_to_store().ok();
+	pub fn summary(&self) -> CounterSummary {
+		CounterSummary {
+			inserts: self.inserts.load(Ordering::Relaxed),
+			deletes: self.deletes.load(Ordering::Relaxed),
+			queries: self.queries.load(Ordering::Relaxed),
+		}
 	}
 }
 
-impl Drop for Db {
-	fn drop(&mut self) {
-		tracing::info!("Saving database to store");
-		self.save_to_store().ok();
+impl Clone for Counters {
+	fn clone(&self) -> Self {
+		let CounterSummary { inserts, deletes, queries } = self.summary();
+
+		Self {
+			inserts: AtomicU64::new(inserts),
+			deletes: AtomicU64::new(deletes),
+			queries: AtomicU64::new(queries),
+		}
 	}
 }
 
-pub fn from_store() -> anyhow::Result<Db> {
-	Db::load_from_store()
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
+pub struct Collection {
+	/// Dimension of the vectors in the collection
+	pub dimension: usize,
+	/// Distance metric used for querying
+	pub distance: Distance,
+	/// Embeddings in the collection
+	#[serde(default)]
+	pub embeddings: Vec<Embedding>,
+	/// Normalization applied to inserted and query vectors
+	#[serde(default)]
+	pub normalization: Normalization,
+	/// Running statistics used by the `standardize` normalization
+	#[serde(default)]
+	#[schemars(skip)]
+	pub stats: DimensionStats,
+	/// Precision vectors are stored in, which can also be given as `storage` when creating a collection
+	#[serde(default, alias = "storage")]
+	pub precision: Precision,
+	/// Metadata keys every embedding must have, and the type of their values. Any metadata is accepted when unset.
+	#[serde(default)]
+	pub metadata_schema: Option<HashMap<String, MetadataType>>,
+	/// Allow embeddings with an empty vector, which are only reachable through metadata queries
+	#[serde(default)]
+	pub allow_empty_vectors: bool,
+	/// Cap on the number of embeddings. Inserting beyond it evicts the embedding inserted, updated or touched longest ago,
+	/// so the collection acts as a sliding window, or an LRU cache when reads touch what they use.
+	#[serde(default)]
+	#[schemars(range(min = 1))]
+	pub max_embeddings: Option<usize>,
+	/// Accumulate distances in `f64` instead of `f32`. Storage is unchanged, and queries get noticeably slower,
+	/// but rankings of near-ties in high-dimensional collections stop depending on rounding error.
+	#[serde(default)]
+	pub high_precision: bool,
+	/// Reject inserts scoring within this threshold of an existing embedding, interpreted according to the distance metric.
+	/// Every insert then runs a nearest-neighbour query first, so inserting costs as much as querying.
+	#[serde(default)]
+	pub dedup_threshold: Option<f32>,
+	/// Right-pad vectors shorter than `dimension` with zeros on insertion, instead of rejecting them.
+	/// Padding happens before normalization, so cosine collections normalize the padded vector, which has the same length.
+	/// Longer vectors are still rejected.
+	#[serde(default)]
+	pub pad_vectors: bool,
+	/// Range every component of inserted vectors has to be in, checked before normalization.
+	/// Out-of-range vectors are rejected, unless `out_of_range` is `clamp`. Any values are accepted when unset.
+	#[serde(default)]
+	pub value_range: Option<ValueRange>,
+	/// Largest metadata value accepted, in bytes, counting every element of a list.
+	/// Defaults to `MAX_METADATA_VALUE_BYTES`, or 64 KiB without it.
+	#[serde(default)]
+	pub max_metadata_value_bytes: Option<usize>,
+	/// Most metadata keys an embedding can have. Defaults to `MAX_METADATA_KEYS`, or 256 without it.
+	#[serde(default)]
+	pub max_metadata_keys: Option<usize>,
+	/// Number of results similarity queries return when they don't give `k`. Defaults to 1.
+	#[serde(default)]
+	#[schemars(range(min = 1))]
+	pub default_k: Option<usize>,
+	/// Unix timestamp (in seconds) of when the collection was created, set by the server
+	#[serde(default)]
+	pub created_at: u64,
+	/// Unix timestamp (in seconds) of the last mutation to the collection, set by the server
+	#[serde(default)]
+	pub updated_at: u64,
+	/// Sequence number given to the latest inserted or updated embedding, set by the server
+	#[serde(default)]
+	pub last_seq: u64,
+	/// Changes on every mutation, so cached query results can be invalidated
+	#[serde(skip)]
+	pub generation: u64,
+	/// Latencies of recent similarity queries
+	#[serde(skip)]
+	#[schemars(skip)]
+	pub latencies: LatencyWindow,
+	/// Operations served since the server started
+	#[serde(skip)]
+	#[schemars(skip)]
+	pub counters: Counters,
+	/// Embeddings deleted since the collection was last compacted
+	#[serde(skip)]
+	#[schemars(skip)]
+	deletes_since_compaction: usize,
+}
+
+impl Collection {
+	pub fn new(dimension: usize, distance: Distance) -> Self {
+		let created_at = now();
+
+		Self {
+			dimension,
+			distance,
+			embeddings: Vec::new(),
+			normalization: Normalization::None,
+			stats: DimensionStats::default(),
+			precision: Precision::F32,
+			metadata_schema: None,
+			allow_empty_vectors: false,
+			max_embeddings: None,
+			high_precision: false,
+			dedup_threshold: None,
+			pad_vectors: false,
+			value_range: None,
+			max_metadata_value_bytes: None,
+			max_metadata_keys: None,
+			default_k: None,
+			created_at,
+			updated_at: created_at,
+			last_seq: 0,
+			generation: next_generation(),
+			latencies: LatencyWindow::default(),
+			counters: Counters::default(),
+			deletes_since_compaction: 0,
+		}
+	}
+
+	/// Whether stored vectors end up unit length, which zero vectors can't be scaled to
+	fn needs_unit_length(&self) -> bool {
+		self.distance == Distance::Cosine || self.normalization == Normalization::L2
+	}
+
+	/// Numeric when every id in the collection is an integer, lexicographic otherwise
+	pub fn id_order(&self) -> IdOrder {
+		if !self.embeddings.is_empty() && self.embeddings.iter().all(|embedding| embedding.id.parse::<i64>().is_ok()) {
+			IdOrder::Numeric
+		} else {
+			IdOrder::Lexicographic
+		}
+	}
+
+	/// Number of embeddings with a vector, which are the ones similarity queries rank
+	pub fn searchable_count(&self) -> usize {
+		if self.allow_empty_vectors {
+			self.embeddings.iter().filter(|embedding| !embedding.vector.is_empty()).count()
+		} else {
+			self.embeddings.len()
+		}
+	}
+
+	/// Whether inserted vectors are rescaled before they're stored, so reading them back doesn't return what was sent
+	pub fn normalizes_vectors(&self) -> bool {
+		self.distance == Distance::Cosine || self.normalization != Normalization::None
+	}
+
+	/// Number of results a similarity query asking for `k` gets, falling back to the collection's `default_k`
+	pub fn k(&self, k: Option<usize>) -> usize {
+		k.or(self.default_k).unwrap_or(DEFAULT_K)
+	}
+
+	/// Apply the collection's normalization to a query vector
+	pub fn normalize_query(&self, query: &[f32]) -> Vec<f32> {
+		self.normalize_with(query, &self.stats)
+	}
+
+	/// Apply the collection's normalization to a vector, standardizing it with `stats`
+	fn normalize_with(&self, query: &[f32], stats: &DimensionStats) -> Vec<f32> {
+		let query = match self.normalization {
+			Normalization::None => query.to_vec(),
+			Normalization::L2 => normalize(query),
+			Normalization::Standardize => stats.standardize(query),
+		};
+
+		// Cosine is computed as a dot product, so its vectors always need to be unit length
+		if self.distance == Distance::Cosine {
+			normalize(&query)
+		} else {
+			query
+		}
+	}
+
+	/// Every metadata key in the collection with the number of embeddings that have it, sorted by key.
+	/// Keys are typed `number` when all of their values parse as one.
+	pub fn metadata_keys(&self) -> Vec<MetadataKey> {
+		let mut keys: HashMap<&str, (usize, bool)> = HashMap::new();
+		for metadata in self.embeddings.iter().filter_map(|embedding| embedding.metadata.as_ref()) {
+			for (key, value) in metadata {
+				let (count, numeric) = keys.entry(key.as_str()).or_insert((0, true));
+				*count += 1;
+				*numeric &= value.values().iter().all(|value| MetadataType::Number.accepts(value));
+			}
+		}
+
+		let mut keys: Vec<MetadataKey> = keys
+			.into_iter()
+			.map(|(key, (count, numeric))| MetadataKey {
+				key: key.to_string(),
+				count,
+				inferred_type: if numeric { MetadataType::Number } else { MetadataType::String },
+			})
+			.collect();
+		keys.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+		keys
+	}
+
+	/// Largest metadata value accepted, in bytes, from the collection or the environment
+	pub fn metadata_value_limit(&self) -> usize {
+		self.max_metadata_value_bytes.unwrap_or(*MAX_METADATA_VALUE_BYTES)
+	}
+
+	/// Most metadata keys an embedding can have, from the collection or the environment
+	pub fn metadata_key_limit(&self) -> usize {
+		self.max_metadata_keys.unwrap_or(*MAX_METADATA_KEYS)
+	}
+
+	/// Lists satisfy a key's type when each of their elements does
+	fn validate_metadata(&self, metadata: Option<&Metadata>) -> Result<(), Error> {
+		if let Some(metadata) = metadata {
+			let max_keys = self.metadata_key_limit();
+			if metadata.len() > max_keys {
+				return Err(Error::InvalidMetadata(format!(
+					"{} keys is more than the {max_keys} allowed",
+					metadata.len()
+				)));
+			}
+
+			let max_bytes = self.metadata_value_limit();
+			for (key, value) in metadata {
+				let bytes: usize = value.values().iter().map(String::len).sum();
+				if bytes > max_bytes {
+					return Err(Error::InvalidMetadata(format!(
+						"value of {key} is {bytes} bytes, more than the {max_bytes} allowed"
+					)));
+				}
+			}
+		}
+
+		let Some(schema) = &self.metadata_schema else {
+			return Ok(());
+		};
+
+		for (key, metadata_type) in schema {
+			let value = metadata
+				.and_then(|metadata| metadata.get(key))
+				.ok_or_else(|| Error::InvalidMetadata(format!("missing required key {key}")))?;
+
+			if !value.values().iter().all(|value| metadata_type.accepts(value)) {
+				return Err(Error::InvalidMetadata(format!(
+					"value of {key} isn't a {metadata_type:?}"
+				)));
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Make room for at least `additional` more embeddings without reallocating, never past `max_embeddings`.
+	/// Returns the resulting capacity.
+	pub fn reserve(&mut self, additional: usize) -> Result<usize, Error> {
+		let additional = self
+			.max_embeddings
+			.map_or(additional, |max| additional.min(max.saturating_sub(self.embeddings.len())));
+		self.embeddings
+			.try_reserve(additional)
+			.map_err(|_| Error::CapacityOverflow(additional))?;
+
+		Ok(self.embeddings.capacity())
+	}
+
+	/// Validate, normalize and store an embedding, replacing any existing one with the same id.
+	/// Returns the id of the embedding evicted to stay within `max_embeddings`, if one was, which is the one with the
+	/// oldest `touched_at`.
+	fn insert(&mut self, mut embedding: Embedding) -> Result<Option<String>, Error> {
+		if RESERVED_IDS.contains(&embedding.id.as_str()) {
+			return Err(Error::ReservedId(embedding.id));
+		}
+		self.validate_metadata(embedding.metadata.as_ref())?;
+		let replacing = self.embeddings.iter().any(|e| e.id == embedding.id);
+
+		let metadata_only = self.allow_empty_vectors && embedding.vector.is_empty();
+		if !metadata_only && self.pad_vectors && embedding.vector.len() < self.dimension {
+			let mut vector = embedding.vector.to_f32().into_owned();
+			vector.resize(self.dimension, 0.0);
+			embedding.vector = Vector::F32(vector);
+		}
+		if !metadata_only && embedding.vector.len() != self.dimension {
+			return Err(Error::DimensionMismatch);
+		}
+
+		if !metadata_only {
+			let mut vector = embedding.vector.to_f32();
+			if !is_finite(&vector) {
+				return Err(Error::NonFiniteVector);
+			}
+			if let Some(range) = &self.value_range {
+				range.apply(vector.to_mut())?;
+			}
+			if self.needs_unit_length() && magnitude(&vector) <= f32::EPSILON {
+				return Err(Error::InvalidVector);
+			}
+			if self.distance == Distance::Hamming && !is_binary(&vector) {
+				return Err(Error::NonBinaryVector);
+			}
+
+			// The running statistics only take in the vector once nothing can reject it. A replaced vector was stored
+			// standardized, so it can't be taken back out of them, and replacing leaves them as they are instead of
+			// counting the id twice.
+			let stats = (self.normalization == Normalization::Standardize && !replacing).then(|| self.stats.with(&vector));
+			let vector = self.normalize_with(&vector, stats.as_ref().unwrap_or(&self.stats));
+			// Normalizing can still overflow, such as when standardizing by a tiny deviation
+			if !is_finite(&vector) {
+				return Err(Error::NonFiniteVector);
+			}
+			if let Some(duplicate) = self.find_near_duplicate(&vector, &embedding.id) {
+				return Err(Error::NearDuplicate(duplicate));
+			}
+			if let Some(stats) = stats {
+				self.stats = stats;
+			}
+			embedding.vector = self.precision.store(vector);
+		}
+
+		if replacing {
+			self.remove_id(&embedding.id);
+		}
+
+		self.last_seq += 1;
+		embedding.seq = self.last_seq;
+		embedding.touched_at = now();
+		self.embeddings.push(embedding);
+		self.counters.count_insert();
+
+		if !self.max_embeddings.is_some_and(|max| self.embeddings.len() > max) {
+			return Ok(None);
+		}
+		// Embeddings are kept in insertion order, so of those touched in the same second, the oldest comes first
+		let stalest = self
+			.embeddings
+			.iter()
+			.enumerate()
+			.min_by_key(|(_, embedding)| embedding.touched_at)
+			.map(|(index, _)| index);
+		Ok(stalest.map(|index| self.embeddings.remove(index).id))
+	}
+
+	/// Id of an embedding other than `id` within `dedup_threshold` of `vector`
+	fn find_near_duplicate(&self, vector: &[f32], id: &str) -> Option<String> {
+		let threshold = self.dedup_threshold?;
+
+		// The closest match may be the embedding being replaced, so look one further
+		self.rank_by_similarity(vector, 2, &Exclusions::default(), true)
+			.0
+			.into_iter()
+			.map(|ScoreIndex { score, index }| (score, &self.embeddings[index]))
+			.find(|(_, other)| other.id != id)
+			.filter(|&(score, _)| self.distance.within(score, threshold))
+			.map(|(_, other)| other.id.clone())
+	}
+
+	fn touch(&mut self) {
+		self.updated_at = now();
+		self.generation = next_generation();
+	}
+
+	pub fn get_id(&self, id: &String) -> Option<Embedding>{
+		self.embeddings
+		.iter()
+		.find(|embedding| &embedding.id == id)
+		.cloned()
+	}
+
+	/// Remove an embedding without counting it as a delete, as when it's being replaced
+	fn remove_id(&mut self, id: &str) -> Option<Embedding> {
+		let index = self.embeddings.iter().position(|embedding| embedding.id == id)?;
+		Some(self.embeddings.remove(index))
+	}
+
+	/// Delete an embedding, compacting the collection once `COMPACT_AFTER_DELETES` have been deleted since it last was
+	pub fn delete_id(&mut self, id: &String) -> Result<Embedding, Error>{
+		 if let Some(embedding) = self.remove_id(id) {
+            self.deletes_since_compaction += 1;
+            if COMPACT_AFTER_DELETES.is_some_and(|deletes| self.deletes_since_compaction >= deletes) {
+                let report = self.compact();
+                tracing::debug!("Compacted after {} deletes, reclaiming {} bytes", report.deletes, report.bytes_reclaimed);
+            }
+            Ok(embedding)
+        } else {
+            // If the id is not found, return an error
+            return Err(Error::IDNotFound);
+        }
+	}
+
+	/// Release the memory embeddings, their vectors and their metadata hold beyond what they need
+	pub fn compact(&mut self) -> CompactReport {
+		let mut bytes_reclaimed = shrink_vec(&mut self.embeddings);
+		for embedding in &mut self.embeddings {
+			bytes_reclaimed += embedding.vector.shrink_to_fit();
+
+			if let Some(metadata) = &mut embedding.metadata {
+				let capacity = metadata.capacity();
+				metadata.shrink_to_fit();
+				bytes_reclaimed += (capacity - metadata.capacity()) * std::mem::size_of::<(String, MetadataValue)>();
+			}
+		}
+
+		CompactReport {
+			embeddings: self.embeddings.len(),
+			deletes: std::mem::take(&mut self.deletes_since_compaction),
+			bytes_reclaimed,
+		}
+	}
+
+	/// Up to `k` embeddings matching the filter, along with how many matched in total.
+	/// List values match when any of their elements does.
+	pub fn get_metadata_string(&self, key: &String, value: &String, match_type: StringMatch, k: usize) -> (Vec<Embedding>, usize){
+		self.counters.count_query();
+		let filtered_embeddings: Vec<Embedding> = self.embeddings
+            .iter()
+            .filter(|embedding| {
+                if let Some(metadata) = &embedding.metadata {
+                    if let Some(meta_value) = metadata.get(key) {
+                        return meta_value.values().iter().any(|meta_value| match_type.matches(meta_value, value));
+                    }
+                }
+                false
+            })
+            .cloned()
+            .collect();
+		
+		let matched = filtered_embeddings.len();
+		(filtered_embeddings.into_iter().take(k).collect(), matched)
+    }
+
+	/// Up to `k` embeddings satisfying every condition, along with how many matched in total.
+	/// Embeddings missing any of the keys don't match, and list values satisfy a condition when any of their elements does.
+	pub fn get_metadata_number(&self, conditions: &[NumberCondition], k: usize) -> (Vec<Embedding>, usize) {
+		self.counters.count_query();
+		let mut matched = 0;
+		let mut results = Vec::new();
+		for embedding in &self.embeddings {
+			let Some(metadata) = &embedding.metadata else { continue };
+			if !conditions.iter().all(|condition| condition.matches(metadata)) {
+				continue;
+			}
+
+			matched += 1;
+			if results.len() < k {
+				results.push(embedding.clone());
+			}
+		}
+
+		(results, matched)
+	}
+
+	pub fn get_similarity(&self, query: &[f32], k: usize) -> Vec<SimilarityResult> {
+		self.explain_similarity(query, k, &Exclusions::default(), true).0
+	}
+
+	/// Same as `get_similarity` leaving out `exclusions`, also reporting where the time went
+	/// `parallel` only decides how the scan is spread across threads, the results are the same either way.
+	pub fn explain_similarity(
+		&self,
+		query: &[f32],
+		k: usize,
+		exclusions: &Exclusions,
+		parallel: bool,
+	) -> (Vec<SimilarityResult>, QueryTiming) {
+		self.counters.count_query();
+		let (mut ranking, timing) = self.rank_by_similarity(query, k, exclusions, parallel);
+		if let Some(percentile) = exclusions.percentile {
+			self.cut_at_percentile(&mut ranking, timing.scanned, percentile);
+		}
+
+		let results = ranking.into_iter().zip(1..).map(|(score, rank)| self.result(score, rank)).collect();
+		(results, timing)
+	}
+
+	/// The result for a score `rank` returned, with a copy of the embedding it belongs to
+	pub fn result(&self, ScoreIndex { score, index }: ScoreIndex, rank: usize) -> SimilarityResult {
+		SimilarityResult {
+			score,
+			rank,
+			embedding: self.embeddings[index].clone(),
+		}
+	}
+
+	/// Keep the part of `ranking` in the best `100 - percentile`% of the `scanned` scores, along with anything tied
+	/// with the last score that made it. The ranking holds the best scores, so the cutoff is among them unless `k` is.
+	#[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+	fn cut_at_percentile(&self, ranking: &mut Vec<ScoreIndex>, scanned: usize, percentile: f32) {
+		let keep = (scanned as f64 * f64::from(100.0 - percentile) / 100.0).ceil() as usize;
+
+		match keep.checked_sub(1) {
+			None => ranking.clear(),
+			Some(last) => {
+				if let Some(cutoff) = ranking.get(last).map(|score_index| score_index.score) {
+					ranking.retain(|score_index| self.distance.within(score_index.score, cutoff));
+				}
+			},
+		}
+	}
+
+	/// Run a throwaway query over every vector, so the pages they're on are resident and the scan's threads are
+	/// started before the first real query
+	pub fn warmup(&self) -> WarmupReport {
+		let instant = Instant::now();
+		let scanned = self
+			.embeddings
+			.iter()
+			.find(|embedding| !embedding.vector.is_empty())
+			.map_or(0, |embedding| {
+				let query = embedding.vector.to_f32();
+				self.rank_by_similarity(&query, 1, &Exclusions::default(), true).1.scanned
+			});
+
+		WarmupReport {
+			scanned,
+			took_us: micros(instant.elapsed()),
+		}
+	}
+
+	/// Scores and indices of the `k` embeddings closest to `query` that aren't excluded, best first, for callers that
+	/// turn them into results one at a time rather than all at once. The percentile cutoff isn't applied.
+	pub fn rank(&self, query: &[f32], k: usize, exclusions: &Exclusions) -> Vec<ScoreIndex> {
+		self.counters.count_query();
+		self.rank_by_similarity(query, k, exclusions, true).0
+	}
+
+	/// Indices of the `k` embeddings closest to `query` that aren't excluded, best first.
+	/// Tiles are scored across rayon's threads when `parallel` is set, and on the calling thread otherwise.
+	fn rank_by_similarity(
+		&self,
+		query: &[f32],
+		k: usize,
+		exclusions: &Exclusions,
+		parallel: bool,
+	) -> (Vec<ScoreIndex>, QueryTiming) {
+		let memo_attr = get_cache_attr(&self.distance, query);
+		let distance_fn = get_vector_distance_fn(&self.distance, self.high_precision);
+		let score = |index: usize, embedding: &Embedding| {
+			if embedding.vector.is_empty() || exclusions.ids.contains(&embedding.id) {
+				return None;
+			}
+
+			let score = distance_fn(&embedding.vector, query, memo_attr);
+			if exclusions.within.is_some_and(|bound| self.distance.within(score, bound))
+				|| exclusions.threshold.is_some_and(|bound| !self.distance.within(score, bound))
+			{
+				return None;
+			}
+
+			Some(ScoreIndex {
+				score: self.distance.rank_key(score),
+				index,
+			})
+		};
+		let score_tile = |offset: usize, tile: &[Embedding]| -> Vec<ScoreIndex> {
+			if parallel {
+				tile.par_iter()
+					.with_min_len(*SCAN_MIN_CHUNK)
+					.enumerate()
+					.filter_map(|(index, embedding)| score(offset + index, embedding))
+					.collect()
+			} else {
+				tile.iter()
+					.enumerate()
+					.filter_map(|(index, embedding)| score(offset + index, embedding))
+					.collect()
+			}
+		};
+
+		let mut scoring = Duration::ZERO;
+		let mut heap_time = Duration::ZERO;
+		let mut scanned = 0;
+
+		let ranking = if k.saturating_mul(FULL_SORT_FRACTION) >= self.searchable_count() {
+			// Most scores end up in the results anyway, so there's nothing to gain from tiling
+			let instant = Instant::now();
+			let mut scores = score_tile(0, &self.embeddings);
+			scoring = instant.elapsed();
+			scanned = scores.len();
+
+			let instant = Instant::now();
+			scores.sort_unstable();
+			scores.truncate(k);
+			heap_time = instant.elapsed();
+			scores
+		} else {
+			// Scoring a tile at a time keeps the scratch space at `SCAN_TILE_SIZE` scores, however big the collection
+			let mut heap = BinaryHeap::new();
+			for (tile_index, tile) in self.embeddings.chunks(SCAN_TILE_SIZE).enumerate() {
+				let instant = Instant::now();
+				let scores = score_tile(tile_index * SCAN_TILE_SIZE, tile);
+				scoring += instant.elapsed();
+				scanned += scores.len();
+
+				let instant = Instant::now();
+				push_top_k(&mut heap, k, scores);
+				heap_time += instant.elapsed();
+			}
+			heap.into_sorted_vec()
+		};
+
+		let timing = QueryTiming {
+			scoring_us: micros(scoring),
+			heap_us: micros(heap_time),
+			scanned,
+			prefiltered: scanned < self.embeddings.len(),
+			..QueryTiming::default()
+		};
+		// `rank_key` is its own inverse, turning the keys back into scores
+		let ranking = ranking
+			.into_iter()
+			.map(|ScoreIndex { score, index }| ScoreIndex {
+				score: self.distance.rank_key(score),
+				index,
+			})
+			.collect();
+		(ranking, timing)
+	}
+
+	/// Fuse vector similarity to `query` with a case-insensitive substring match of `text` against metadata values
+	/// (only the value of `key`, when given) using reciprocal rank fusion. Each embedding scores
+	///
+	/// `vector_weight / (60 + vector_rank) + text_weight / (60 + text_rank)`
+	///
+	/// where ranks start at 1, text matches are ranked by how often `text` occurs, and a ranking an embedding
+	/// doesn't appear in contributes nothing. Each ranking is cut off at `max(k, 100)` candidates.
+	#[allow(clippy::cast_precision_loss)]
+	pub fn hybrid_search(
+		&self,
+		query: &[f32],
+		text: &str,
+		key: Option<&str>,
+		k: usize,
+		(vector_weight, text_weight): (f32, f32),
+	) -> Vec<SimilarityResult> {
+		const RRF_K: f32 = 60.0;
+		let depth = k.max(HYBRID_CANDIDATES);
+		let rrf = |weight: f32, rank: usize| weight / (RRF_K + (rank + 1) as f32);
+		self.counters.count_query();
+
+		let mut fused: HashMap<usize, f32> = HashMap::new();
+		for (rank, ScoreIndex { index, .. }) in self.rank_by_similarity(query, depth, &Exclusions::default(), true).0.into_iter().enumerate() {
+			*fused.entry(index).or_default() += rrf(vector_weight, rank);
+		}
+		for (rank, index) in self.rank_by_text(text, key, depth).into_iter().enumerate() {
+			*fused.entry(index).or_default() += rrf(text_weight, rank);
+		}
+
+		let mut fused: Vec<(usize, f32)> = fused.into_iter().collect();
+		fused.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+		fused.truncate(k);
+
+		fused
+			.into_iter()
+			.zip(1..)
+			.map(|((index, score), rank)| SimilarityResult {
+				score,
+				rank,
+				embedding: self.embeddings[index].clone(),
+			})
+			.collect()
+	}
+
+	/// Indices of the embeddings whose metadata contains `text`, most occurrences first
+	fn rank_by_text(&self, text: &str, key: Option<&str>, limit: usize) -> Vec<usize> {
+		if text.is_empty() {
+			return Vec::new();
+		}
+
+		let needle = text.to_lowercase();
+		let mut matches: Vec<(usize, usize)> = self
+			.embeddings
+			.iter()
+			.enumerate()
+			.filter_map(|(index, embedding)| {
+				let occurrences: usize = embedding
+					.metadata
+					.as_ref()?
+					.iter()
+					.filter(|(metadata_key, _)| key.map_or(true, |key| key == metadata_key.as_str()))
+					.flat_map(|(_, value)| value.values())
+					.map(|value| value.to_lowercase().matches(&needle).count())
+					.sum();
+
+				(occurrences > 0).then_some((index, occurrences))
+			})
+			.collect();
+
+		// Stable, so ties keep insertion order
+		matches.sort_by(|a, b| b.1.cmp(&a.1));
+		matches.into_iter().take(limit).map(|(index, _)| index).collect()
+	}
+
+	/// Check every embedding for problems that would make queries misbehave
+	pub fn verify(&self) -> VerifyReport {
+		let tolerance = match self.precision {
+			Precision::F32 => 1e-4,
+			Precision::F16 => 1e-2,
+		};
+		let mut seen = HashSet::with_capacity(self.embeddings.len());
+		let mut violations = Vec::new();
+
+		for embedding in &self.embeddings {
+			let id = embedding.id.clone();
+			if !seen.insert(embedding.id.as_str()) {
+				violations.push(Violation::DuplicateId { id: id.clone() });
+			}
+
+			let vector = embedding.vector.to_f32();
+			if vector.is_empty() && self.allow_empty_vectors {
+				continue;
+			}
+
+			if vector.len() != self.dimension {
+				violations.push(Violation::DimensionMismatch {
+					id,
+					len: vector.len(),
+				});
+			} else if !is_finite(&vector) {
+				violations.push(Violation::NonFinite { id });
+			} else if self.needs_unit_length() {
+				let magnitude = magnitude(&vector);
+				if (magnitude - 1.0).abs() > tolerance {
+					violations.push(Violation::NotUnitLength { id, magnitude });
+				}
+			}
+		}
+
+		VerifyReport {
+			checked: self.embeddings.len(),
+			healthy: violations.is_empty(),
+			violations,
+		}
+	}
+
+	/// Pick `n` embeddings uniformly at random in a single pass, using reservoir sampling
+	pub fn sample(&self, n: usize, seed: Option<u64>) -> Vec<Embedding> {
+		let mut rng = seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+		let mut reservoir: Vec<&Embedding> = Vec::with_capacity(n.min(self.embeddings.len()));
+
+		for (index, embedding) in self.embeddings.iter().enumerate() {
+			if index < n {
+				reservoir.push(embedding);
+			} else {
+				let replace = rng.gen_range(0..=index);
+				if replace < n {
+					reservoir[replace] = embedding;
+				}
+			}
+		}
+
+		reservoir.into_iter().cloned().collect()
+	}
+
+	/// Embeddings scoring within `threshold` of the query, closest first
+	pub fn get_within_threshold(&self, query: &[f32], threshold: f32) -> Vec<SimilarityResult> {
+		self.get_similarity(query, self.embeddings.len())
+			.into_iter()
+			.filter(|result| self.distance.within(result.score, threshold))
+			.collect()
+	}
+
+	/// Every pair of embeddings scoring within `threshold` of each other. This is O(n²).
+	pub fn get_duplicate_pairs(&self, threshold: f32) -> Vec<DuplicatePair> {
+		let distance_fn = &get_vector_distance_fn(&self.distance, self.high_precision);
+
+		self.embeddings
+			.par_iter()
+			.enumerate()
+			.filter(|(_, embedding)| !embedding.vector.is_empty())
+			.flat_map_iter(|(index, embedding)| {
+				let vector = embedding.vector.to_f32();
+				let memo_attr = get_cache_attr(&self.distance, &vector);
+
+				self.embeddings[index + 1..].iter().filter_map(move |other| {
+					if other.vector.is_empty() {
+						return None;
+					}
+
+					let score = distance_fn(&other.vector, &vector, memo_attr);
+
+					self.distance
+						.within(score, threshold)
+						.then(|| DuplicatePair {
+							first: embedding.id.clone(),
+							second: other.id.clone(),
+							score,
+						})
+				})
+			})
+			.collect()
+	}
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema)]
+pub struct Embedding {
+	pub id: String,
+	pub vector: Vector,
+	pub metadata: Option<Metadata>,
+	/// Position of the embedding's latest insert or update in the collection's history, set by the server.
+	/// It only ever grows, so embeddings changed since a sync are the ones with a greater `seq`.
+	#[serde(default)]
+	pub seq: u64,
+	/// Unix timestamp (in seconds) of the embedding's latest insert, update or touch, set by the server
+	#[serde(default)]
+	pub touched_at: u64,
+}
+
+impl Db {
+	pub fn new() -> Self {
+		Self {
+			collections: HashMap::new(),
+			aliases: HashMap::new(),
+			read_only: false,
+			ephemeral: false,
+		}
+	}
+
+	/// An empty database that's never saved, whatever the environment says, so tests don't leave a store behind
+	#[cfg_attr(not(test), allow(dead_code))]
+	pub fn in_memory() -> Self {
+		Self {
+			ephemeral: true,
+			..Self::new()
+		}
+	}
+
+	const fn ensure_writable(&self) -> Result<(), Error> {
+		if self.read_only {
+			return Err(Error::ReadOnly);
+		}
+
+		Ok(())
+	}
+
+	pub fn extension(self) -> DbExtension {
+		Extension(Arc::new(RwLock::new(self)))
+	}
+
+	/// Create a collection from the settings in `collection`, with room for at least `reserve` embeddings.
+	/// Server-managed fields are reset.
+	///
+	/// The name is checked and claimed without giving up `&mut self`, so of several creations racing for one name,
+	/// exactly one succeeds and the rest get `UniqueViolation`, whichever order they take the lock in.
+	pub fn create_collection(
+		&mut self,
+		name: String,
+		mut collection: Collection,
+		reserve: usize,
+	) -> Result<Collection, Error> {
+		self.ensure_writable()?;
+		if !is_valid_name(&name) {
+			return Err(Error::InvalidName);
+		}
+		if self.collections.contains_key(&name) || self.aliases.contains_key(&name) {
+			return Err(Error::UniqueViolation);
+		}
+		if let Distance::Weighted(weights) = &collection.distance {
+			if weights.len() != collection.dimension
+				|| weights.iter().any(|weight| !weight.is_finite() || *weight < 0.0)
+			{
+				return Err(Error::InvalidWeights);
+			}
+		}
+		if collection.distance == Distance::Hamming && collection.normalization != Normalization::None {
+			return Err(Error::HammingNormalization);
+		}
+		if collection.value_range.is_some_and(|range| {
+			!range.min.is_finite() || !range.max.is_finite() || range.min > range.max
+		}) {
+			return Err(Error::InvalidValueRange);
+		}
+
+		let created_at = now();
+		let seeds = std::mem::take(&mut collection.embeddings);
+		let mut collection = Collection {
+			created_at,
+			stats: DimensionStats::default(),
+			updated_at: created_at,
+			last_seq: 0,
+			generation: next_generation(),
+			..collection
+		};
+
+		collection.reserve(reserve.max(seeds.len()))?;
+
+		// Seeds go through the same validation and normalization as inserts, and the collection
+		// only becomes visible once all of them made it in
+		for (index, embedding) in seeds.into_iter().enumerate() {
+			collection.insert(embedding).map_err(|source| Error::BatchFailed {
+				index,
+				source: Box::new(source),
+			})?;
+		}
+
+		// Cloning doesn't keep the reserved capacity, so the stored collection is the original
+		let created = collection.clone();
+		self.collections.insert(name, collection);
+		self.save();
+		Ok(created)
+	}
+
+	/// Delete a collection, returning the embeddings it held. With `dry_run`, nothing is removed.
+	pub fn delete_collection(&mut self, name: &str, dry_run: bool) -> Result<AffectedEmbeddings, Error> {
+		self.ensure_writable()?;
+		let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+		let affected = AffectedEmbeddings::from_embeddings(&collection.embeddings);
+
+		if !dry_run {
+			self.collections.remove(name);
+			self.aliases.retain(|_, target| target != name);
+			self.save();
+		}
+		Ok(affected)
+	}
+
+	pub fn delete_collections(&mut self, names: Vec<String>, dry_run: bool) -> Result<DeleteSummary, Error> {
+		self.ensure_writable()?;
+		let mut summary = DeleteSummary::default();
+
+		for name in names {
+			let exists = if dry_run {
+				self.collections.contains_key(&name)
+			} else {
+				self.aliases.retain(|_, target| target != &name);
+				self.collections.remove(&name).is_some()
+			};
+
+			if exists {
+				summary.deleted.push(name);
+			} else {
+				summary.not_found.push(name);
+			}
+		}
+
+		if !dry_run && !summary.deleted.is_empty() {
+			self.save();
+		}
+		Ok(summary)
+	}
+
+	pub fn insert_into_collection(
+		&mut self,
+		collection_name: &str,
+		embedding: Embedding,
+	) -> Result<Option<String>, Error> {
+		self.ensure_writable()?;
+		let collection = self.get_collection_mut(collection_name).ok_or(Error::NotFound)?;
+
+		let evicted = collection.insert(embedding)?;
+		collection.touch();
+		self.save();
+		Ok(evicted)
+	}
+
+	/// Insert each embedding on its own, so a failing record doesn't stop the others from being inserted
+	pub fn insert_many(
+		&mut self,
+		collection_name: &str,
+		embeddings: Vec<Embedding>,
+		on_conflict: OnConflict,
+	) -> Result<Vec<InsertOutcome>, Error> {
+		self.ensure_writable()?;
+		let collection = self.get_collection_mut(collection_name).ok_or(Error::NotFound)?;
+
+		let outcomes: Vec<InsertOutcome> = embeddings
+			.into_iter()
+			.map(|embedding| {
+				let id = embedding.id.clone();
+				let exists = collection.embeddings.iter().any(|e| e.id == id);
+
+				match (exists, on_conflict) {
+					(true, OnConflict::Skip) => InsertOutcome::Skipped { id },
+					(true, OnConflict::Error) => InsertOutcome::Error {
+						id,
+						reason: "An embedding with this id already exists".to_string(),
+					},
+					_ => match collection.insert(embedding) {
+						Ok(_) if exists => InsertOutcome::Updated { id },
+						Ok(evicted) => InsertOutcome::Inserted { id, evicted },
+						Err(error) => InsertOutcome::Error {
+							id,
+							reason: error.to_string(),
+						},
+					},
+				}
+			})
+			.collect();
+
+		if outcomes.iter().any(InsertOutcome::changed) {
+			collection.touch();
+			self.save();
+		}
+		Ok(outcomes)
+	}
+
+	/// Apply `operations` in order to a copy of the collection, only swapping it in if all of them succeed.
+	/// With `dry_run`, the operations are validated but the copy is discarded.
+	pub fn apply_batch(
+		&mut self,
+		collection_name: &str,
+		operations: Vec<BatchOperation>,
+		dry_run: bool,
+	) -> Result<(), Error> {
+		self.ensure_writable()?;
+		let collection_name = self.resolve(collection_name).to_string();
+		let mut staged = self
+			.collections
+			.get(&collection_name)
+			.ok_or(Error::NotFound)?
+			.clone();
+
+		for (index, operation) in operations.into_iter().enumerate() {
+			let result = match operation {
+				BatchOperation::Insert(embedding) => staged.insert(embedding).map(|_| ()),
+				BatchOperation::Delete { id } => staged.delete_id(&id).map(|_| staged.counters.count_delete()),
+			};
+
+			result.map_err(|source| Error::BatchFailed {
+				index,
+				source: Box::new(source),
+			})?;
+		}
+
+		if dry_run {
+			return Ok(());
+		}
+
+		staged.touch();
+		self.collections.insert(collection_name, staged);
+		self.save();
+		Ok(())
+	}
+
+	/// Grow a collection's capacity ahead of a bulk load, returning the resulting capacity.
+	/// Nothing is saved, since capacity isn't persisted.
+	pub fn reserve_collection(&mut self, collection_name: &str, additional: usize) -> Result<usize, Error> {
+		self.ensure_writable()?;
+		let collection = self.get_collection_mut(collection_name).ok_or(Error::NotFound)?;
+
+		collection.reserve(additional)
+	}
+
+	/// Compact a collection's memory, saving the store afterwards. The embeddings themselves don't change.
+	pub fn compact_collection(&mut self, collection_name: &str) -> Result<CompactReport, Error> {
+		self.ensure_writable()?;
+		let collection = self.get_collection_mut(collection_name).ok_or(Error::NotFound)?;
+
+		let report = collection.compact();
+		self.save();
+		Ok(report)
+	}
+
+	/// Mark an embedding as just used, without changing its data or its `seq`, returning its new `touched_at`.
+	/// Cached query results aren't invalidated, so they can report an older `touched_at`.
+	pub fn touch_embedding(&mut self, collection_name: &str, id: &str) -> Result<u64, Error> {
+		self.ensure_writable()?;
+		let collection = self.get_collection_mut(collection_name).ok_or(Error::NotFound)?;
+
+		let embedding = collection
+			.embeddings
+			.iter_mut()
+			.find(|embedding| embedding.id == id)
+			.ok_or(Error::IDNotFound)?;
+		embedding.touched_at = now();
+		let touched_at = embedding.touched_at;
+
+		self.save();
+		Ok(touched_at)
+	}
+
+	/// Delete an embedding from a collection, returning it. With `dry_run`, nothing is removed.
+	pub fn collection_delete_id(&mut self, collection_name: &str, id: &String, dry_run: bool) -> Result<Embedding, Error>{
+		self.ensure_writable()?;
+		let collection = self.get_collection_mut(collection_name).ok_or(Error::NotFound)?;
+
+		if dry_run {
+			return collection.get_id(id).ok_or(Error::IDNotFound);
+		}
+
+		let result = collection.delete_id(id);
+		if result.is_ok() {
+			collection.counters.count_delete();
+			collection.touch();
+		}
+		self.save();
+		result
+	}
+
+
+	/// Give every collection a new generation, so nothing cached from before the database was swapped out is served
+	pub fn invalidate_generations(&mut self) {
+		for collection in self.collections.values_mut() {
+			collection.generation = next_generation();
+		}
+	}
+
+	/// Find embeddings whose vector would break similarity queries, having the wrong length or NaN or infinite components,
+	/// and drop them when `repair` is set. Returns the number found.
+	pub fn check_integrity(&mut self, repair: bool) -> usize {
+		let mut found = 0;
+
+		for (name, collection) in &mut self.collections {
+			let (dimension, allow_empty_vectors) = (collection.dimension, collection.allow_empty_vectors);
+			let is_corrupt = |embedding: &Embedding| {
+				let vector = embedding.vector.to_f32();
+				!(vector.is_empty() && allow_empty_vectors)
+					&& (vector.len() != dimension || !vector.iter().all(|value| value.is_finite()))
+			};
+
+			for embedding in collection.embeddings.iter().filter(|embedding| is_corrupt(embedding)) {
+				tracing::warn!(
+					"Embedding {} in {name} has a corrupt vector of length {}",
+					embedding.id,
+					embedding.vector.len()
+				);
+				found += 1;
+			}
+
+			if repair {
+				let before = collection.embeddings.len();
+				collection.embeddings.retain(|embedding| !is_corrupt(embedding));
+				if collection.embeddings.len() < before {
+					tracing::warn!("Dropped {} corrupt embeddings from {name}", before - collection.embeddings.len());
+					collection.updated_at = now();
+				}
+			}
+		}
+
+		found
+	}
+
+	/// The name of the collection `name` refers to, following an alias if there's one
+	pub fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+		self.aliases.get(name).map_or(name, String::as_str)
+	}
+
+	pub fn get_collection(&self, name: &str) -> Option<&Collection> {
+		self.collections.get(self.resolve(name))
+	}
+
+	fn get_collection_mut(&mut self, name: &str) -> Option<&mut Collection> {
+		let name = self.resolve(name).to_string();
+		self.collections.get_mut(&name)
+	}
+
+	/// Point `alias` at `collection`, replacing whatever it pointed at before.
+	/// Aliases stand in for collection names in URL paths, so they're held to the same rules.
+	pub fn set_alias(&mut self, alias: String, collection: String) -> Result<(), Error> {
+		self.ensure_writable()?;
+		if !is_valid_name(&alias) {
+			return Err(Error::InvalidName);
+		}
+		if self.collections.contains_key(&alias) {
+			return Err(Error::UniqueViolation);
+		}
+		if !self.collections.contains_key(&collection) {
+			return Err(Error::NotFound);
+		}
+
+		if let Some(previous) = self.aliases.insert(alias, collection.clone()) {
+			self.bump_generation(&previous);
+		}
+		self.bump_generation(&collection);
+		self.save();
+		Ok(())
+	}
+
+	/// Remove `alias`, returning the collection it pointed at. The collection itself is kept.
+	pub fn delete_alias(&mut self, alias: &str) -> Result<String, Error> {
+		self.ensure_writable()?;
+		let collection = self.aliases.remove(alias).ok_or(Error::AliasNotFound)?;
+
+		self.bump_generation(&collection);
+		self.save();
+		Ok(collection)
+	}
+
+	/// Invalidate what's cached for a collection whose aliases changed, so no query through an alias is answered
+	/// from before the change
+	fn bump_generation(&mut self, name: &str) {
+		if let Some(collection) = self.collections.get_mut(name) {
+			collection.generation = next_generation();
+		}
+	}
+
+	/// Query several collections and merge their results into a single top-k, skipping missing collections.
+	/// Without `k`, each collection contributes its `default_k` results and the merged list keeps the largest of them.
+	/// A collection named more than once, directly or through aliases, is only queried under the first of its names.
+	/// Scores are only comparable within a metric, so the collections must all use the same `distance`.
+	pub fn query_collections(
+		&self,
+		names: &[String],
+		query: &[f32],
+		k: Option<usize>,
+	) -> Result<MultiCollectionResults, Error> {
+		let mut missing = Vec::new();
+		let mut queried = HashSet::new();
+		let mut collections = Vec::new();
+		for name in names {
+			match self.get_collection(name) {
+				Some(collection) if queried.insert(self.resolve(name)) => collections.push((name, collection)),
+				Some(_) => {},
+				None => missing.push(name.clone()),
+			}
+		}
+
+		if let Some((_, first)) = collections.first() {
+			if collections.iter().any(|(_, collection)| collection.distance != first.distance) {
+				return Err(Error::MixedDistances);
+			}
+		}
+
+		let mut results = Vec::new();
+		let mut limit = k.unwrap_or(0);
+		for (name, collection) in collections {
+			if collection.dimension != query.len() {
+				return Err(Error::DimensionMismatch);
+			}
+
+			let collection_k = collection.k(k);
+			limit = limit.max(collection_k);
+			let query = collection.normalize_query(query);
+			results.extend(collection.get_similarity(&query, collection_k).into_iter().map(|result| {
+				let key = collection.distance.rank_key(result.score);
+				let result = CollectionSimilarityResult {
+					collection: name.clone(),
+					result,
+				};
+				(key, result)
+			}));
+		}
+
+		// Same order as `get_similarity`, so merging doesn't change how a single collection ranks
+		results.sort_by(|a, b| b.0.total_cmp(&a.0));
+		results.truncate(limit);
+		// Ranks were per collection until now
+		let results = results
+			.into_iter()
+			.zip(1..)
+			.map(|((_, mut result), rank)| {
+				result.result.rank = rank;
+				result
+			})
+			.collect();
+
+		Ok(MultiCollectionResults { results, missing })
+	}
+
+	pub fn load_from_store() -> anyhow::Result<Self> {
+		if !STORE_PATH.exists() {
+			tracing::debug!("Creating database store");
+			fs::create_dir_all(STORE_PATH.parent().context("Invalid store path")?)?;
+
+			return Ok(Self::new());
+		}
+
+		tracing::debug!("Loading database from store");
+		let db = fs::read(STORE_PATH.as_path())?;
+		match bincode::deserialize(&db[..]) {
+			Ok(db) => Ok(db),
+			Err(err) => {
+				tracing::warn!("Couldn't load store ({err}), retrying with the legacy format");
+				Ok(bincode::deserialize::<legacy::Db>(&db[..])?.into())
+			},
+		}
+	}
+
+	fn save_to_store(&self) -> anyhow::Result<()> {
+		let db = bincode::serialize(self)?;
+
+		fs::write(STORE_PATH.as_path(), db)?;
+
+		Ok(())
+	}
+
+	/// Write the database to the store, logging and counting failures rather than returning them
+	pub fn save(&self){
+		if self.read_only || self.ephemeral {
+			return;
+		}
+
+		let instant = Instant::now();
+		let result = self.save_to_store();
+		PERSISTENCE.record_save(instant.elapsed(), result.err());
+	}
+}
+
+impl Drop for Db {
+	fn drop(&mut self) {
+		if self.read_only || self.ephemeral {
+			return;
+		}
+
+		tracing::info!("Saving database to store");
+		self.save();
+	}
+}
+
+/// Saves and loads of the store since the server started. There's one store for every collection, so they're global.
+static PERSISTENCE: Persistence = Persistence {
+	saves: AtomicU64::new(0),
+	save_failures: AtomicU64::new(0),
+	last_save_us: AtomicU64::new(0),
+	load_us: AtomicU64::new(0),
+	last_error: Mutex::new(None),
+};
+
+struct Persistence {
+	saves: AtomicU64,
+	save_failures: AtomicU64,
+	last_save_us: AtomicU64,
+	load_us: AtomicU64,
+	/// Cleared by the next successful save
+	last_error: Mutex<Option<String>>,
+}
+
+impl Persistence {
+	fn record_save(&self, elapsed: Duration, error: Option<anyhow::Error>) {
+		self.saves.fetch_add(1, Ordering::Relaxed);
+		self.last_save_us.store(micros(elapsed), Ordering::Relaxed);
+
+		let error = error.map(|err| {
+			tracing::error!("Couldn't save the database to {}: {err:#}", STORE_PATH.display());
+			self.save_failures.fetch_add(1, Ordering::Relaxed);
+			format!("{err:#}")
+		});
+		*self.last_error.lock().unwrap() = error;
+	}
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct PersistenceStats {
+	/// Saves attempted since the server started
+	pub saves: u64,
+	/// Saves that failed since the server started
+	pub save_failures: u64,
+	/// How long the latest save took, in microseconds
+	pub last_save_us: Option<u64>,
+	/// How long loading the store took at startup, in microseconds
+	pub load_us: u64,
+	/// Why the latest save failed, if it did
+	pub last_error: Option<String>,
+}
+
+impl PersistenceStats {
+	/// Whether the latest save succeeded, or there hasn't been one yet
+	pub const fn healthy(&self) -> bool {
+		self.last_error.is_none()
+	}
+}
+
+pub fn persistence_stats() -> PersistenceStats {
+	let saves = PERSISTENCE.saves.load(Ordering::Relaxed);
+
+	PersistenceStats {
+		saves,
+		save_failures: PERSISTENCE.save_failures.load(Ordering::Relaxed),
+		last_save_us: (saves > 0).then(|| PERSISTENCE.last_save_us.load(Ordering::Relaxed)),
+		load_us: PERSISTENCE.load_us.load(Ordering::Relaxed),
+		last_error: PERSISTENCE.last_error.lock().unwrap().clone(),
+	}
+}
+
+/// Set while the store is loaded behind a server that's already accepting connections
+static LOADING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the store is still being loaded, so the database being served is an empty placeholder
+pub fn is_loading() -> bool {
+	LOADING.load(Ordering::Acquire)
+}
+
+pub fn set_loading(loading: bool) {
+	LOADING.store(loading, Ordering::Release);
+}
+
+/// Load the database using the storage backend selected by `DB_STORAGE` (`heap`, the default, or `mmap`).
+///
+/// With `DB_READONLY=true`, the database is loaded read-only, so query-only replicas never write back to a shared store.
+/// With `DB_PERSIST=off`, writes are accepted but never saved, which keeps disk I/O out of benchmarks.
+///
+/// `STARTUP_CHECK` scans every vector for the wrong length or NaN and infinite components before serving:
+/// `refuse` fails to start if any are found, and `repair` drops them and saves the store. It's `off` by default.
+pub fn from_store() -> anyhow::Result<Db> {
+	let instant = Instant::now();
+	let mut db = match env::var("DB_STORAGE").as_deref() {
+		Ok("heap") | Err(_) => Db::load_from_store()?,
+		Ok("mmap") => mmap::load()?,
+		Ok(other) => anyhow::bail!("Unknown DB_STORAGE {other}, expected heap or mmap"),
+	};
+	let elapsed = instant.elapsed();
+	tracing::info!("Loaded database from store in {elapsed:?}");
+	PERSISTENCE.load_us.store(micros(elapsed), Ordering::Relaxed);
+	// Generations aren't stored, so every loaded collection would otherwise share the same one
+	db.invalidate_generations();
+
+	if env::var("DB_READONLY")
+		.ok()
+		.map(|read_only| read_only.parse::<bool>())
+		.transpose()?
+		.unwrap_or(false)
+	{
+		tracing::info!("Database is read-only");
+		db.read_only = true;
+	}
+
+	match env::var("DB_PERSIST").as_deref() {
+		Ok("on") | Err(_) => {},
+		Ok("off") => {
+			tracing::warn!("Persistence is off, changes will be lost on shutdown");
+			db.ephemeral = true;
+		},
+		Ok(other) => anyhow::bail!("Unknown DB_PERSIST {other}, expected on or off"),
+	}
+
+	match env::var("STARTUP_CHECK").as_deref() {
+		Ok("off") | Err(_) => {},
+		Ok("refuse") => match db.check_integrity(false) {
+			0 => tracing::info!("Store passed the startup check"),
+			found => anyhow::bail!("Store has {found} corrupt embeddings, refusing to start"),
+		},
+		Ok("repair") => {
+			if db.check_integrity(true) > 0 {
+				db.save();
+			}
+		},
+		Ok(other) => anyhow::bail!("Unknown STARTUP_CHECK {other}, expected off, refuse or repair"),
+	}
+
+	Ok(db)
+}
+
+/// Whether `name` is usable as a collection name: it ends up in URL paths and possibly file names, so only
+/// `[A-Za-z0-9_-]{1,COLLECTION_NAME_MAX_LENGTH}` is allowed, other than the `RESERVED_NAMES` of other routes
+fn is_valid_name(name: &str) -> bool {
+	(1..=*MAX_NAME_LENGTH).contains(&name.len())
+		&& name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+		&& !RESERVED_NAMES.contains(&name)
+}
+
+pub fn micros(duration: Duration) -> u64 {
+	u64::try_from(duration.as_micros()).unwrap_or(u64::MAX)
+}
+
+/// Keep the `k` best of `heap` and `scores` in `heap`, whose top is the worst of them
+fn push_top_k(heap: &mut BinaryHeap<ScoreIndex>, k: usize, scores: impl IntoIterator<Item = ScoreIndex>) {
+	for score_index in scores {
+		if heap.len() < k || heap.peek().is_some_and(|worst| score_index < *worst) {
+			heap.push(score_index);
+
+			if heap.len() > k {
+				heap.pop();
+			}
+		}
+	}
+}
+
+fn next_generation() -> u64 {
+	static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+	GENERATION.fetch_add(1, Ordering::Relaxed)
+}
+
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |duration| duration.as_secs())
+}
+
+/// Store layout written before collections tracked timestamps, so older stores keep loading.
+mod legacy {
+	use std::collections::HashMap;
+
+	use crate::similarity::Distance;
+
+	#[derive(serde::Deserialize)]
+	pub struct Db {
+		collections: HashMap<String, Collection>,
+	}
+
+	#[derive(serde::Deserialize)]
+	struct Collection {
+		dimension: usize,
+		distance: Distance,
+		embeddings: Vec<Embedding>,
+	}
+
+	#[derive(serde::Deserialize)]
+	struct Embedding {
+		id: String,
+		vector: Vec<f32>,
+		metadata: Option<HashMap<String, String>>,
+	}
+
+	impl From<Db> for super::Db {
+		fn from(db: Db) -> Self {
+			let collections = db
+				.collections
+				.into_iter()
+				.map(|(name, collection)| (name, collection.into()))
+				.collect();
+
+			Self {
+				collections,
+				aliases: HashMap::new(),
+				read_only: false,
+				ephemeral: false,
+			}
+		}
+	}
+
+	impl From<Collection> for super::Collection {
+		fn from(collection: Collection) -> Self {
+			let embeddings: Vec<super::Embedding> = collection
+				.embeddings
+				.into_iter()
+				.zip(1..)
+				.map(|(embedding, seq)| super::Embedding {
+					seq,
+					..embedding.into()
+				})
+				.collect();
+
+			Self {
+				last_seq: embeddings.last().map_or(0, |embedding| embedding.seq),
+				embeddings,
+				created_at: 0,
+				updated_at: 0,
+				..Self::new(collection.dimension, collection.distance)
+			}
+		}
+	}
+
+	impl From<Embedding> for super::Embedding {
+		fn from(embedding: Embedding) -> Self {
+			Self {
+				id: embedding.id,
+				vector: embedding.vector.into(),
+				metadata: embedding.metadata.map(|metadata| {
+					metadata.into_iter().map(|(key, value)| (key, value.into())).collect()
+				}),
+				seq: 0,
+				touched_at: 0,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn embedding(id: &str, vector: Vec<f32>) -> Embedding {
+		Embedding {
+			id: id.to_string(),
+			vector: vector.into(),
+			metadata: None,
+			seq: 0,
+			touched_at: 0,
+		}
+	}
+
+	#[test]
+	fn distinct_large_norm_vectors_arent_near_duplicates() {
+		let mut collection = Collection {
+			dedup_threshold: Some(0.5),
+			..Collection::new(2, Distance::Euclidean)
+		};
+
+		collection.insert(embedding("far", vec![1000.0, 0.0])).unwrap();
+		// 999 apart, which used to score 0 as the stored vector's norm was left out of the distance
+		collection.insert(embedding("near_origin", vec![1.0, 0.0])).unwrap();
+
+		assert!(matches!(
+			collection.insert(embedding("copy", vec![1000.0, 0.1])),
+			Err(Error::NearDuplicate(id)) if id == "far"
+		));
+	}
+
+	#[test]
+	fn zero_vectors_are_rejected_from_cosine_collections() {
+		let mut collection = Collection::new(3, Distance::Cosine);
+
+		assert!(matches!(
+			collection.insert(embedding("zero", vec![0.0; 3])),
+			Err(Error::InvalidVector)
+		));
+		assert!(collection.embeddings.is_empty());
+	}
+
+	#[test]
+	fn non_finite_vectors_are_rejected() {
+		let mut collection = Collection::new(2, Distance::DotProduct);
+
+		for vector in [vec![f32::NAN, 0.0], vec![0.0, f32::INFINITY]] {
+			assert!(matches!(
+				collection.insert(embedding("broken", vector)),
+				Err(Error::NonFiniteVector)
+			));
+		}
+		assert!(collection.embeddings.is_empty());
+	}
+
+	#[test]
+	fn route_paths_are_reserved() {
+		let mut collection = Collection::new(2, Distance::DotProduct);
+		assert!(matches!(
+			collection.insert(embedding("export", vec![1.0, 0.0])),
+			Err(Error::ReservedId(id)) if id == "export"
+		));
+		assert!(collection.embeddings.is_empty());
+
+		let mut db = Db::in_memory();
+		for name in RESERVED_NAMES {
+			assert!(matches!(
+				db.create_collection((*name).to_string(), Collection::new(2, Distance::DotProduct), 0),
+				Err(Error::InvalidName)
+			));
+		}
+	}
+
+	#[test]
+	fn aliases_are_held_to_collection_name_rules() {
+		let mut db = Db::in_memory();
+		db.create_collection("cases".to_string(), Collection::new(2, Distance::DotProduct), 0).unwrap();
+
+		for alias in ["", "../cases", "delete_batch"] {
+			assert!(matches!(
+				db.set_alias(alias.to_string(), "cases".to_string()),
+				Err(Error::InvalidName)
+			));
+		}
+		assert!(db.aliases.is_empty());
+	}
+
+	#[test]
+	fn nan_scores_rank_last() {
+		let mut collection = Collection::new(2, Distance::DotProduct);
+		collection.insert(embedding("overflowing", vec![1e30, 1e30])).unwrap();
+		collection.insert(embedding("finite", vec![1.0, 0.0])).unwrap();
+
+		// Scores `inf + -inf` against the first embedding
+		let ranking = collection.rank(&[1e30, -1e30], 2, &Exclusions::default());
+		let scores: Vec<f32> = ranking.iter().map(|score| score.score).collect();
+		assert_eq!(ranking[0].index, 1);
+		assert!(scores[1].is_nan());
+	}
+
+	#[test]
+	fn rejected_and_replacing_vectors_leave_the_statistics_alone() {
+		let mut collection = Collection {
+			normalization: Normalization::Standardize,
+			dedup_threshold: Some(0.5),
+			..Collection::new(1, Distance::Euclidean)
+		};
+		collection.insert(embedding("low", vec![0.0])).unwrap();
+		collection.insert(embedding("high", vec![2.0])).unwrap();
+		let standardized = collection.normalize_query(&[2.0]);
+
+		assert!(matches!(
+			collection.insert(embedding("copy", vec![2.0])),
+			Err(Error::NearDuplicate(id)) if id == "high"
+		));
+		assert_eq!(collection.normalize_query(&[2.0]), standardized);
+
+		collection.insert(embedding("high", vec![10.0])).unwrap();
+		assert_eq!(collection.normalize_query(&[2.0]), standardized);
+	}
+
+	#[test]
+	fn replacing_an_embedding_isnt_a_delete() {
+		let mut collection = Collection::new(2, Distance::DotProduct);
+		collection.insert(embedding("replaced", vec![1.0, 0.0])).unwrap();
+		collection.insert(embedding("replaced", vec![0.0, 1.0])).unwrap();
+		collection.insert(embedding("deleted", vec![1.0, 1.0])).unwrap();
+		collection.delete_id(&"deleted".to_string()).unwrap();
+
+		assert_eq!(collection.compact().deletes, 1);
+	}
+
+	#[test]
+	fn collections_named_twice_are_queried_once() {
+		let mut db = Db::in_memory();
+		let mut collection = Collection::new(2, Distance::DotProduct);
+		collection.insert(embedding("only", vec![1.0, 0.0])).unwrap();
+		db.create_collection("cases".to_string(), collection, 0).unwrap();
+		db.set_alias("current".to_string(), "cases".to_string()).unwrap();
+
+		let names = ["cases", "current", "cases"].map(String::from);
+		let merged = db.query_collections(&names, &[1.0, 0.0], Some(10)).unwrap();
+		assert_eq!(merged.results.len(), 1);
+		assert_eq!(merged.results[0].collection, "cases");
+	}
+
+	#[test]
+	fn collections_with_different_metrics_arent_merged() {
+		let mut db = Db::in_memory();
+		for (name, distance) in [("similar", Distance::Cosine), ("near", Distance::Euclidean)] {
+			db.create_collection(name.to_string(), Collection::new(2, distance), 0).unwrap();
+		}
+
+		let names = ["similar", "near"].map(String::from);
+		assert!(matches!(
+			db.query_collections(&names, &[1.0, 0.0], None),
+			Err(Error::MixedDistances)
+		));
+	}
+
+	/// Top-10 results of f16 storage overlap f32's by at least this much on average, over random unit vectors
+	const F16_MIN_RECALL: f32 = 0.95;
+
+	#[test]
+	#[allow(clippy::cast_precision_loss)]
+	fn f16_storage_keeps_recall_close_to_f32() {
+		const EMBEDDINGS: usize = 2000;
+		const DIMENSION: usize = 64;
+		const QUERIES: usize = 50;
+		const K: usize = 10;
+
+		let mut rng = StdRng::seed_from_u64(0);
+		let mut random_vector = || (0..DIMENSION).map(|_| rng.gen_range(-1.0..1.0)).collect::<Vec<f32>>();
+
+		let mut full = Collection::new(DIMENSION, Distance::Cosine);
+		let mut half = Collection {
+			precision: Precision::F16,
+			..Collection::new(DIMENSION, Distance::Cosine)
+		};
+		for id in 0..EMBEDDINGS {
+			let vector = random_vector();
+			full.insert(embedding(&id.to_string(), vector.clone())).unwrap();
+			half.insert(embedding(&id.to_string(), vector)).unwrap();
+		}
+
+		let top_ids = |collection: &Collection, query: &[f32]| -> HashSet<String> {
+			let query = collection.normalize_query(query);
+			collection
+				.get_similarity(&query, K)
+				.into_iter()
+				.map(|result| result.embedding.id)
+				.collect()
+		};
+		let overlap: usize = (0..QUERIES)
+			.map(|_| {
+				let query = random_vector();
+				top_ids(&full, &query).intersection(&top_ids(&half, &query)).count()
+			})
+			.sum();
+
+		let recall = overlap as f32 / (QUERIES * K) as f32;
+		assert!(recall >= F16_MIN_RECALL, "f16 recall@{K} was {recall}");
+	}
+
+	#[test]
+	fn read_only_databases_arent_compacted() {
+		let mut db = Db::in_memory();
+		db.create_collection("cases".to_string(), Collection::new(2, Distance::Cosine), 0)
+			.unwrap();
+		db.read_only = true;
+
+		assert!(matches!(db.compact_collection("cases"), Err(Error::ReadOnly)));
+	}
+
+	#[test]
+	fn zero_k_ranks_nothing() {
+		let mut collection = Collection::new(2, Distance::Cosine);
+		collection.insert(embedding("only", vec![1.0, 0.0])).unwrap();
+
+		assert!(collection.get_similarity(&[1.0, 0.0], 0).is_empty());
+	}
+
+	/// Top-k selection with the heap and with a full sort, at a few k/n ratios, behind `FULL_SORT_FRACTION`.
+	/// Run with `cargo test --release bench_top_k_selection -- --ignored --nocapture`.
+	#[test]
+	#[ignore = "benchmark"]
+	#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+	fn bench_top_k_selection() {
+		const SCORES: usize = 1_000_000;
+
+		let mut rng = StdRng::seed_from_u64(0);
+		let scores: Vec<f32> = (0..SCORES).map(|_| rng.gen()).collect();
+		let score_indices = || {
+			scores
+				.iter()
+				.enumerate()
+				.map(|(index, &score)| ScoreIndex { score, index })
+				.collect::<Vec<_>>()
+		};
+
+		for ratio in [0.001, 0.1, 0.9] {
+			let k = (SCORES as f64 * ratio) as usize;
+
+			let candidates = score_indices();
+			let instant = Instant::now();
+			let mut heap = BinaryHeap::new();
+			push_top_k(&mut heap, k, candidates);
+			let heap_ranking = heap.into_sorted_vec();
+			let heap_time = instant.elapsed();
+
+			let mut sort_ranking = score_indices();
+			let instant = Instant::now();
+			sort_ranking.sort_unstable();
+			sort_ranking.truncate(k);
+			let sort_time = instant.elapsed();
+
+			assert!(heap_ranking == sort_ranking);
+			println!("k/n = {ratio}: heap {heap_time:?}, full sort {sort_time:?}");
+		}
+	}
+
+	/// Peak resident memory, in KiB
+	#[cfg(target_os = "linux")]
+	fn peak_rss_kib() -> u64 {
+		fs::read_to_string("/proc/self/status")
+			.unwrap()
+			.lines()
+			.find_map(|line| line.strip_prefix("VmHWM:"))
+			.and_then(|kib| kib.trim().trim_end_matches("kB").trim().parse().ok())
+			.unwrap()
+	}
+
+	/// How much a query over a large collection raises peak memory, which tiling bounds at `SCAN_TILE_SIZE` scores.
+	/// Run alone with `cargo test --release bench_query_peak_rss -- --ignored --nocapture`, as other tests' allocations
+	/// count towards the peak too.
+	#[test]
+	#[ignore = "benchmark"]
+	#[cfg(target_os = "linux")]
+	#[allow(clippy::cast_precision_loss)]
+	fn bench_query_peak_rss() {
+		const EMBEDDINGS: usize = 4_000_000;
+		const DIMENSION: usize = 16;
+
+		let mut collection = Collection::new(DIMENSION, Distance::DotProduct);
+		collection.embeddings = (0..EMBEDDINGS)
+			.map(|id| embedding(&id.to_string(), (0..DIMENSION).map(|i| ((id * 31 + i * 7) % 1000) as f32).collect()))
+			.collect();
+
+		let before = peak_rss_kib();
+		std::hint::black_box(collection.get_similarity(&[1.0; DIMENSION], 10));
+		let after = peak_rss_kib();
+
+		println!("Peak RSS {before} KiB before the query and {after} KiB after, {} KiB more", after - before);
+	}
+
+	/// Latency of parallel queries over a large collection with the `SCAN_MIN_CHUNK` the process was started with.
+	/// Compare chunk sizes with runs like
+	/// `SCAN_MIN_CHUNK=4096 cargo test --release bench_scan_min_chunk -- --ignored --nocapture`.
+	#[test]
+	#[ignore = "benchmark"]
+	fn bench_scan_min_chunk() {
+		const EMBEDDINGS: usize = 1_000_000;
+		const DIMENSION: usize = 128;
+		const QUERIES: u32 = 20;
+
+		let mut rng = StdRng::seed_from_u64(0);
+		let mut collection = Collection::new(DIMENSION, Distance::DotProduct);
+		collection.embeddings = (0..EMBEDDINGS)
+			.map(|id| embedding(&id.to_string(), (0..DIMENSION).map(|_| rng.gen()).collect()))
+			.collect();
+		let query: Vec<f32> = (0..DIMENSION).map(|_| rng.gen()).collect();
+
+		let instant = Instant::now();
+		for _ in 0..QUERIES {
+			std::hint::black_box(collection.get_similarity(&query, 10));
+		}
+
+		println!(
+			"SCAN_MIN_CHUNK={} over {} threads: {:?} per query",
+			*SCAN_MIN_CHUNK,
+			rayon::current_num_threads(),
+			instant.elapsed() / QUERIES
+		);
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn exactly_one_concurrent_create_succeeds() {
+		let Extension(db) = Db::in_memory().extension();
+
+		let creates = (0..2).map(|_| {
+			let db = Arc::clone(&db);
+			tokio::spawn(async move {
+				let collection = Collection::new(2, Distance::Cosine);
+				db.write().await.create_collection("cases".to_string(), collection, 0).map(|_| ())
+			})
+		});
+		let results: Vec<_> = futures_util::future::join_all(creates)
+			.await
+			.into_iter()
+			.map(Result::unwrap)
+			.collect();
+
+		assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+		assert!(results.iter().any(|result| matches!(result, Err(Error::UniqueViolation))));
+	}
 }
@@ -4,11 +4,29 @@ use axum::{
 	response::{IntoResponse, Response},
 	Json,
 };
+use axum_jsonschema::JsonSchemaRejection;
+use lazy_static::lazy_static;
 use serde_json::{json, Value};
+use std::env;
+use uuid::Uuid;
+
+lazy_static! {
+	/// Whether server errors are sent to clients as they are, or replaced by a reference to the logged detail.
+	/// `ERROR_DETAIL=minimal` replaces them, and `full`, the default, doesn't.
+	static ref MINIMAL_DETAIL: bool = match env::var("ERROR_DETAIL").as_deref() {
+		Ok("full") | Err(_) => false,
+		Ok("minimal") => true,
+		Ok(other) => {
+			tracing::warn!("Unknown ERROR_DETAIL {other}, expected full or minimal, sending full details");
+			false
+		},
+	};
+}
 
 #[derive(Debug)]
 pub struct HTTPError {
 	detail: Value,
+	field: Option<String>,
 	status_code: StatusCode,
 }
 
@@ -16,6 +34,7 @@ impl HTTPError {
 	pub fn new(detail: &str) -> Self {
 		Self {
 			detail: detail.into(),
+			field: None,
 			status_code: StatusCode::UNPROCESSABLE_ENTITY,
 		}
 	}
@@ -24,14 +43,66 @@ impl HTTPError {
 		self.status_code = status_code;
 		self
 	}
+
+	/// Point the error at the request field that caused it
+	pub fn with_field(mut self, field: impl Into<String>) -> Self {
+		self.field = Some(field.into());
+		self
+	}
 }
 
 impl IntoResponse for HTTPError {
+	/// With `ERROR_DETAIL=minimal`, server errors only tell the client a reference to quote, under which the detail
+	/// is logged. Client errors are sent in full either way, as they're about the request.
 	fn into_response(self) -> Response {
-		(self.status_code, Json(json!({ "error": self.detail }))).into_response()
+		if *MINIMAL_DETAIL && self.status_code.is_server_error() {
+			let reference = Uuid::new_v4().to_string();
+			tracing::error!(%reference, detail = %self.detail, field = ?self.field, "Server error");
+
+			let body = json!({ "error": "Internal server error", "reference": reference });
+			return (self.status_code, Json(body)).into_response();
+		}
+
+		let body = match self.field {
+			Some(field) => json!({ "error": self.detail, "field": field }),
+			None => json!({ "error": self.detail }),
+		};
+
+		(self.status_code, Json(body)).into_response()
 	}
 }
 
 impl OperationOutput for HTTPError {
 	type Inner = Self;
 }
+
+impl From<JsonSchemaRejection> for HTTPError {
+	fn from(rejection: JsonSchemaRejection) -> Self {
+		match rejection {
+			JsonSchemaRejection::Json(rejection) => {
+				Self::new(&rejection.body_text()).with_status(rejection.status())
+			},
+			JsonSchemaRejection::Serde(error) => {
+				Self::new(&error.inner().to_string()).with_field(error.path().to_string())
+			},
+			JsonSchemaRejection::Schema(errors) => {
+				let errors = serde_json::to_value(errors).unwrap_or_default();
+				let first = errors.get(0);
+				let error = Self::new(
+					first
+						.and_then(|error| error.get("error"))
+						.and_then(Value::as_str)
+						.unwrap_or("Request body doesn't match the schema"),
+				);
+
+				match first
+					.and_then(|error| error.get("instanceLocation"))
+					.and_then(Value::as_str)
+				{
+					Some(field) if !field.is_empty() => error.with_field(field),
+					_ => error,
+				}
+			},
+		}
+	}
+}
@@ -0,0 +1,170 @@
+//! Writes collections out as Parquet, for loading into dataframe and analytics tools.
+
+use anyhow::Context;
+use arrow_array::{
+	builder::{Float32Builder, ListBuilder, StringBuilder},
+	ArrayRef, RecordBatch,
+};
+use arrow_schema::{DataType, Field, Schema};
+use axum::body::Bytes;
+use parquet::{arrow::ArrowWriter, errors::ParquetError};
+use std::{
+	borrow::Cow,
+	io::{self, Write},
+	sync::Arc,
+};
+use tokio::sync::{mpsc, RwLock};
+
+use crate::db::{Collection, Db, Embedding, MetadataValue};
+
+/// Embeddings written per Parquet row group, which is also how many are read under each lock on the database
+const ROW_GROUP_SIZE: usize = 8192;
+
+/// The encoded file is sent on in chunks of roughly this many bytes
+const CHUNK_BYTES: usize = 64 * 1024;
+
+/// Prefix for the columns metadata keys are flattened into, so keys can't collide with `id` or `vector`
+const METADATA_PREFIX: &str = "metadata.";
+
+/// Columns of a collection's Parquet export: an `id` column, one nullable string column per metadata key, named
+/// `metadata.<key>`, and a `vector` list column.
+///
+/// Embeddings without a vector have a null `vector`, and ones without a given key a null in its column.
+/// List metadata values are written as JSON arrays.
+pub struct ParquetSchema {
+	schema: Arc<Schema>,
+	keys: Vec<String>,
+}
+
+impl ParquetSchema {
+	pub fn new(collection: &Collection) -> Self {
+		let mut keys: Vec<String> = collection.metadata_keys().into_iter().map(|key| key.key).collect();
+		keys.sort_unstable();
+
+		let mut fields = vec![Field::new("id", DataType::Utf8, false)];
+		fields.extend(keys.iter().map(|key| Field::new(format!("{METADATA_PREFIX}{key}"), DataType::Utf8, true)));
+		fields.push(Field::new(
+			"vector",
+			DataType::List(Arc::new(Field::new("item", DataType::Float32, true))),
+			true,
+		));
+
+		Self {
+			schema: Arc::new(Schema::new(fields)),
+			keys,
+		}
+	}
+
+	fn record_batch(&self, embeddings: &[Embedding]) -> Result<RecordBatch, ParquetError> {
+		let mut ids = StringBuilder::new();
+		let mut metadata: Vec<StringBuilder> = self.keys.iter().map(|_| StringBuilder::new()).collect();
+		let mut vectors = ListBuilder::new(Float32Builder::new());
+
+		for embedding in embeddings {
+			ids.append_value(&embedding.id);
+
+			for (key, column) in self.keys.iter().zip(&mut metadata) {
+				column.append_option(embedding.metadata.as_ref().and_then(|metadata| metadata.get(key)).map(column_value));
+			}
+
+			if embedding.vector.is_empty() {
+				vectors.append_null();
+			} else {
+				vectors.values().append_slice(&embedding.vector.to_f32());
+				vectors.append(true);
+			}
+		}
+
+		let mut columns: Vec<ArrayRef> = vec![Arc::new(ids.finish())];
+		columns.extend(metadata.iter_mut().map(|column| Arc::new(column.finish()) as ArrayRef));
+		columns.push(Arc::new(vectors.finish()));
+
+		Ok(RecordBatch::try_new(Arc::clone(&self.schema), columns)?)
+	}
+}
+
+/// Encode the collection called `name` as a Parquet file written to `out`, a row group at a time.
+///
+/// Each row group is copied out of the collection under its own read lock, which is released before it's encoded and
+/// written, so writers only ever wait for one row group to be read. Expected to run on a blocking thread. Fails if
+/// the collection's generation is no longer `generation`, as the rows already written no longer describe it.
+pub fn write_parquet<W: Write + Send>(
+	db: &RwLock<Db>,
+	name: &str,
+	generation: u64,
+	schema: &ParquetSchema,
+	out: W,
+) -> anyhow::Result<W> {
+	let mut writer = ArrowWriter::try_new(out, Arc::clone(&schema.schema), None)?;
+
+	let mut next = 0;
+	loop {
+		let batch = {
+			let db = db.blocking_read();
+			let collection = db
+				.get_collection(name)
+				.filter(|collection| collection.generation == generation)
+				.context("The collection changed during the export")?;
+
+			let end = collection.embeddings.len().min(next + ROW_GROUP_SIZE);
+			if next >= end {
+				break;
+			}
+			let batch = schema.record_batch(&collection.embeddings[next..end])?;
+			next = end;
+			batch
+		};
+
+		writer.write(&batch)?;
+		writer.flush()?;
+	}
+
+	let mut out = writer.into_inner()?;
+	out.flush()?;
+	Ok(out)
+}
+
+/// Passes what's written to it on to a streamed response body, in chunks of about `CHUNK_BYTES`.
+/// Writes fail once the body has been dropped, such as when the client disconnects.
+pub struct ChannelWriter {
+	sender: mpsc::Sender<io::Result<Bytes>>,
+	buffer: Vec<u8>,
+}
+
+impl ChannelWriter {
+	pub fn new(sender: mpsc::Sender<io::Result<Bytes>>) -> Self {
+		Self {
+			sender,
+			buffer: Vec::with_capacity(CHUNK_BYTES),
+		}
+	}
+}
+
+impl Write for ChannelWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.buffer.extend_from_slice(buf);
+		if self.buffer.len() >= CHUNK_BYTES {
+			self.flush()?;
+		}
+
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		if self.buffer.is_empty() {
+			return Ok(());
+		}
+
+		let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_BYTES));
+		self.sender
+			.blocking_send(Ok(Bytes::from(chunk)))
+			.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The response body was dropped"))
+	}
+}
+
+fn column_value(value: &MetadataValue) -> Cow<'_, str> {
+	match value {
+		MetadataValue::Single(value) => Cow::Borrowed(value),
+		MetadataValue::List(values) => Cow::Owned(serde_json::to_string(values).unwrap_or_default()),
+	}
+}
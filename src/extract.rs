@@ -0,0 +1,63 @@
+use aide::{gen::GenContext, openapi::Operation, OperationInput};
+use axum::{
+	async_trait,
+	body::Bytes,
+	extract::FromRequest,
+	http::{header, Request, StatusCode},
+	response::IntoResponse,
+};
+use axum_jsonschema::JsonSchemaRejection;
+use serde::de::DeserializeOwned;
+
+use crate::{errors::HTTPError, response::Format};
+
+/// JSON request body validated against its schema, rejected with the same error shape as the rest of the API.
+///
+/// Bodies sent with `Content-Type: application/msgpack` are accepted too. They're checked by deserializing them
+/// rather than against the schema, so their errors don't point at the offending field.
+pub struct JsonBody<T>(pub T);
+
+#[async_trait]
+impl<S, B, T> FromRequest<S, B> for JsonBody<T>
+where
+	axum_jsonschema::Json<T>: FromRequest<S, B, Rejection = JsonSchemaRejection>,
+	Bytes: FromRequest<S, B>,
+	T: DeserializeOwned,
+	S: Send + Sync,
+	B: Send + 'static,
+{
+	type Rejection = HTTPError;
+
+	async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+		if !Format::is_msgpack(req.headers(), header::CONTENT_TYPE) {
+			return axum_jsonschema::Json::<T>::from_request(req, state)
+				.await
+				.map(|axum_jsonschema::Json(value)| Self(value))
+				.map_err(HTTPError::from);
+		}
+
+		let body = Bytes::from_request(req, state).await.map_err(|rejection| {
+			let response = rejection.into_response();
+			HTTPError::new("Couldn't read the request body").with_status(response.status())
+		})?;
+
+		// Going through a `Value` reads types that are stored differently from how clients send them, like vectors,
+		// the way they're written in JSON
+		let value: serde_json::Value = rmp_serde::from_slice(&body).map_err(|err| {
+			HTTPError::new(&format!("Invalid MessagePack body: {err}")).with_status(StatusCode::BAD_REQUEST)
+		})?;
+
+		serde_json::from_value(value)
+			.map(Self)
+			.map_err(|err| HTTPError::new(&err.to_string()))
+	}
+}
+
+impl<T> OperationInput for JsonBody<T>
+where
+	axum_jsonschema::Json<T>: OperationInput,
+{
+	fn operation_input(ctx: &mut GenContext, operation: &mut Operation) {
+		axum_jsonschema::Json::<T>::operation_input(ctx, operation);
+	}
+}
@@ -0,0 +1,256 @@
+//! gRPC interface to the collection operations, served next to the REST API when built with the `grpc` feature.
+//!
+//! Requests go straight to the shared `Db`, so they skip the HTTP middleware: there's no query cache, audit log or
+//! idempotency here, and `ERROR_DETAIL` doesn't apply.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use tokio::sync::RwLock;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+	db::{self, Collection, Db, InsertOutcome, Metadata, MetadataValue, OnConflict},
+	shutdown::Shutdown,
+	similarity::{Distance, Normalization},
+};
+
+#[allow(clippy::pedantic, clippy::nursery)]
+mod proto {
+	tonic::include_proto!("tinyvector");
+}
+
+use proto::tinyvector_server::{Tinyvector, TinyvectorServer};
+
+/// Serve the gRPC interface on `addr` until shutdown starts, in the background
+pub fn spawn(db: Arc<RwLock<Db>>, addr: SocketAddr, shutdown: &Shutdown) -> tokio::task::JoinHandle<()> {
+	let mut signal = shutdown.sender.subscribe();
+
+	tokio::spawn(async move {
+		tracing::info!("Starting gRPC server on {addr}...");
+		let result = Server::builder()
+			.add_service(TinyvectorServer::new(Service { db }))
+			.serve_with_shutdown(addr, async move {
+				signal.recv().await.ok();
+			})
+			.await;
+
+		if let Err(err) = result {
+			tracing::error!("gRPC server failed: {err}");
+		}
+	})
+}
+
+struct Service {
+	db: Arc<RwLock<Db>>,
+}
+
+#[tonic::async_trait]
+impl Tinyvector for Service {
+	async fn create_collection(
+		&self,
+		request: Request<proto::CreateCollectionRequest>,
+	) -> Result<Response<proto::CreateCollectionResponse>, Status> {
+		ready()?;
+		let req = request.into_inner();
+		let distance = match proto::Distance::from_i32(req.distance) {
+			Some(proto::Distance::Euclidean) => Distance::Euclidean,
+			Some(proto::Distance::Cosine) => Distance::Cosine,
+			Some(proto::Distance::Dot) => Distance::DotProduct,
+			Some(proto::Distance::Weighted) => Distance::Weighted(req.weights),
+			Some(proto::Distance::Hamming) => Distance::Hamming,
+			Some(proto::Distance::Unspecified) | None => {
+				return Err(Status::invalid_argument("A distance is needed"))
+			},
+		};
+		let normalization = match proto::Normalization::from_i32(req.normalization) {
+			Some(proto::Normalization::None) => Normalization::None,
+			Some(proto::Normalization::L2) => Normalization::L2,
+			Some(proto::Normalization::Standardize) => Normalization::Standardize,
+			None => return Err(Status::invalid_argument("Unknown normalization")),
+		};
+
+		let collection = Collection {
+			normalization,
+			max_embeddings: req.max_embeddings.map(usize_from).transpose()?,
+			allow_empty_vectors: req.allow_empty_vectors,
+			default_k: req.default_k.map(usize_from).transpose()?,
+			..Collection::new(usize_from(req.dimension)?, distance)
+		};
+
+		let mut db = self.db.write().await;
+		db.create_collection(req.name, collection, 0).map_err(status)?;
+
+		Ok(Response::new(proto::CreateCollectionResponse {}))
+	}
+
+	async fn delete_collection(
+		&self,
+		request: Request<proto::DeleteCollectionRequest>,
+	) -> Result<Response<proto::DeleteCollectionResponse>, Status> {
+		ready()?;
+		let req = request.into_inner();
+
+		let mut db = self.db.write().await;
+		let deleted = db
+			.get_collection(&req.name)
+			.map_or(0, |collection| collection.embeddings.len());
+		db.delete_collection(&req.name, false).map_err(status)?;
+
+		Ok(Response::new(proto::DeleteCollectionResponse { deleted: deleted as u64 }))
+	}
+
+	async fn insert(&self, request: Request<proto::InsertRequest>) -> Result<Response<proto::InsertResponse>, Status> {
+		ready()?;
+		let req = request.into_inner();
+		let embedding = req
+			.embedding
+			.ok_or_else(|| Status::invalid_argument("An embedding is needed"))?;
+
+		let mut db = self.db.write().await;
+		let evicted = db
+			.insert_into_collection(&req.collection, from_proto(embedding))
+			.map_err(status)?;
+
+		Ok(Response::new(proto::InsertResponse { evicted }))
+	}
+
+	async fn insert_batch(
+		&self,
+		request: Request<proto::InsertBatchRequest>,
+	) -> Result<Response<proto::InsertBatchResponse>, Status> {
+		ready()?;
+		let req = request.into_inner();
+		let embeddings = req.embeddings.into_iter().map(from_proto).collect();
+
+		let mut db = self.db.write().await;
+		let outcomes = db
+			.insert_many(&req.collection, embeddings, OnConflict::Overwrite)
+			.map_err(status)?;
+		drop(db);
+
+		let mut response = proto::InsertBatchResponse::default();
+		for (index, outcome) in (0..).zip(outcomes) {
+			match outcome {
+				InsertOutcome::Error { reason, .. } => response.failures.push(proto::InsertFailure {
+					index,
+					error: reason,
+				}),
+				_ => response.inserted += 1,
+			}
+		}
+		Ok(Response::new(response))
+	}
+
+	async fn query(&self, request: Request<proto::QueryRequest>) -> Result<Response<proto::QueryResponse>, Status> {
+		ready()?;
+		let req = request.into_inner();
+		let k = req.k.map(usize_from).transpose()?;
+		if k == Some(0) {
+			return Err(Status::invalid_argument("k must be at least 1"));
+		}
+
+		let db = self.db.read().await;
+		let collection = db
+			.get_collection(&req.collection)
+			.ok_or_else(|| Status::not_found("Collection not found"))?;
+		if req.query.len() != collection.dimension {
+			return Err(Status::invalid_argument("Query dimension mismatch"));
+		}
+
+		let query = collection.normalize_query(&req.query);
+		let results = collection
+			.get_similarity(&query, collection.k(k))
+			.into_iter()
+			.map(|result| proto::SimilarityResult {
+				score: result.score,
+				rank: result.rank as u64,
+				embedding: Some(to_proto(result.embedding)),
+			})
+			.collect();
+
+		Ok(Response::new(proto::QueryResponse { results }))
+	}
+
+	async fn delete_embedding(
+		&self,
+		request: Request<proto::DeleteEmbeddingRequest>,
+	) -> Result<Response<proto::DeleteEmbeddingResponse>, Status> {
+		ready()?;
+		let req = request.into_inner();
+
+		let mut db = self.db.write().await;
+		db.collection_delete_id(&req.collection, &req.id, false)
+			.map_err(status)?;
+
+		Ok(Response::new(proto::DeleteEmbeddingResponse {}))
+	}
+}
+
+/// Turn requests away until the store has loaded, like the REST data routes
+fn ready() -> Result<(), Status> {
+	if db::is_loading() {
+		return Err(Status::unavailable("The database is still loading"));
+	}
+
+	Ok(())
+}
+
+/// The gRPC status matching the HTTP status the REST API answers the error with
+fn status(error: db::Error) -> Status {
+	let message = error.to_string();
+
+	match error {
+		db::Error::NotFound | db::Error::IDNotFound | db::Error::AliasNotFound => Status::not_found(message),
+		db::Error::UniqueViolation | db::Error::NearDuplicate(_) => Status::already_exists(message),
+		db::Error::ReadOnly => Status::permission_denied(message),
+		db::Error::CapacityOverflow(_) => Status::resource_exhausted(message),
+		_ => Status::invalid_argument(message),
+	}
+}
+
+fn usize_from(value: u64) -> Result<usize, Status> {
+	usize::try_from(value).map_err(|_| Status::out_of_range(format!("{value} is too large")))
+}
+
+fn from_proto(embedding: proto::Embedding) -> db::Embedding {
+	let metadata: Metadata = embedding
+		.metadata
+		.into_iter()
+		.filter_map(|(key, value)| {
+			let value = match value.value? {
+				proto::metadata_value::Value::Single(value) => MetadataValue::Single(value),
+				proto::metadata_value::Value::List(list) => MetadataValue::List(list.values),
+			};
+			Some((key, value))
+		})
+		.collect();
+
+	db::Embedding {
+		id: embedding.id,
+		vector: embedding.vector.into(),
+		metadata: (!metadata.is_empty()).then_some(metadata),
+		seq: 0,
+		touched_at: 0,
+	}
+}
+
+fn to_proto(embedding: db::Embedding) -> proto::Embedding {
+	let metadata: HashMap<String, proto::MetadataValue> = embedding
+		.metadata
+		.unwrap_or_default()
+		.into_iter()
+		.map(|(key, value)| {
+			let value = match value {
+				MetadataValue::Single(value) => proto::metadata_value::Value::Single(value),
+				MetadataValue::List(values) => proto::metadata_value::Value::List(proto::StringList { values }),
+			};
+			(key, proto::MetadataValue { value: Some(value) })
+		})
+		.collect();
+
+	proto::Embedding {
+		id: embedding.id,
+		vector: embedding.vector.to_f32().into_owned(),
+		metadata,
+		seq: embedding.seq,
+	}
+}
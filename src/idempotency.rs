@@ -0,0 +1,314 @@
+//! Replays the response to a write whose `Idempotency-Key` was already seen, instead of applying the write again.
+
+use axum::{
+	body::{boxed, Bytes, Full, HttpBody},
+	extract::State,
+	http::{HeaderMap, Method, Request, StatusCode},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use std::{
+	collections::HashMap,
+	env,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use crate::errors::HTTPError;
+
+/// Most keys remembered at once. Expired ones are evicted first, then the oldest.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Responses larger than this, or streamed without a known length, are passed through without being remembered
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+/// How long keys are remembered when `IDEMPOTENCY_TTL_SECS` isn't set
+const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Total size of the response bodies remembered when `IDEMPOTENCY_MAX_BYTES` isn't set
+const DEFAULT_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Idempotency(Arc<Keys>);
+
+#[derive(Debug)]
+struct Keys {
+	ttl: Duration,
+	max_bytes: usize,
+	store: Mutex<Store>,
+}
+
+#[derive(Debug, Default)]
+struct Store {
+	entries: HashMap<String, Entry>,
+	/// Size of every remembered response body together
+	bytes: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+	created_at: Instant,
+	/// `None` while the first request with the key is still being handled
+	response: Option<CachedResponse>,
+}
+
+#[derive(Debug, Clone)]
+struct CachedResponse {
+	status: StatusCode,
+	headers: HeaderMap,
+	body: Bytes,
+}
+
+impl Entry {
+	fn bytes(&self) -> usize {
+		self.response.as_ref().map_or(0, |response| response.body.len())
+	}
+}
+
+impl Store {
+	fn remove(&mut self, key: &str) {
+		if let Some(entry) = self.entries.remove(key) {
+			self.bytes -= entry.bytes();
+		}
+	}
+
+	fn remove_expired(&mut self, ttl: Duration) {
+		let bytes = &mut self.bytes;
+		self.entries.retain(|_, entry| {
+			let live = entry.created_at.elapsed() < ttl;
+			if !live {
+				*bytes -= entry.bytes();
+			}
+			live
+		});
+	}
+
+	/// Remove the oldest entry `evictable` accepts, returning whether there was one
+	fn remove_oldest(&mut self, evictable: impl Fn(&Entry) -> bool) -> bool {
+		let oldest = self
+			.entries
+			.iter()
+			.filter(|(_, entry)| evictable(entry))
+			.min_by_key(|(_, entry)| entry.created_at)
+			.map(|(key, _)| key.clone());
+
+		let Some(oldest) = oldest else {
+			return false;
+		};
+		self.remove(&oldest);
+		true
+	}
+}
+
+enum Claim {
+	/// The key is new, and its response should be stored once it's ready
+	New,
+	Replay(CachedResponse),
+	InProgress,
+}
+
+impl Idempotency {
+	/// Keys are remembered for `IDEMPOTENCY_TTL_SECS`, a day by default. Memory is bounded by `IDEMPOTENCY_MAX_BYTES`
+	/// (64 MiB by default) of response bodies across at most 10,000 keys: past either, the oldest keys are forgotten,
+	/// so unique keys sent to fill the store only push out what was remembered before them.
+	pub fn from_env() -> anyhow::Result<Self> {
+		let ttl = env::var("IDEMPOTENCY_TTL_SECS")
+			.ok()
+			.map(|ttl| ttl.parse())
+			.transpose()?
+			.map_or(DEFAULT_TTL, Duration::from_secs);
+		let max_bytes = env::var("IDEMPOTENCY_MAX_BYTES")
+			.ok()
+			.map(|max_bytes| max_bytes.parse())
+			.transpose()?
+			.unwrap_or(DEFAULT_MAX_BYTES);
+
+		Ok(Self(Arc::new(Keys {
+			ttl,
+			max_bytes,
+			store: Mutex::default(),
+		})))
+	}
+
+	/// Largest response body that's remembered
+	fn max_body_bytes(&self) -> usize {
+		MAX_BODY_BYTES.min(self.0.max_bytes)
+	}
+
+	fn claim(&self, key: &str) -> Claim {
+		let mut store = self.0.store.lock().unwrap();
+
+		match store.entries.get(key) {
+			Some(entry) if entry.created_at.elapsed() < self.0.ttl => {
+				return entry.response.clone().map_or(Claim::InProgress, Claim::Replay);
+			},
+			_ => {},
+		}
+
+		if store.entries.len() >= MAX_ENTRIES {
+			store.remove_expired(self.0.ttl);
+		}
+		if store.entries.len() >= MAX_ENTRIES {
+			store.remove_oldest(|_| true);
+		}
+		store.remove(key);
+		store.entries.insert(
+			key.to_string(),
+			Entry {
+				created_at: Instant::now(),
+				response: None,
+			},
+		);
+		Claim::New
+	}
+
+	fn complete(&self, key: &str, response: CachedResponse) {
+		let mut store = self.0.store.lock().unwrap();
+		let store = &mut *store;
+
+		let Some(entry) = store.entries.get_mut(key) else {
+			return;
+		};
+		store.bytes += response.body.len();
+		entry.response = Some(response);
+
+		// Only remembered responses take up the budget, so keys still being handled aren't evicted for it
+		while store.bytes > self.0.max_bytes && store.remove_oldest(|entry| entry.response.is_some()) {}
+	}
+
+	/// Forget `key`, so a retry gets applied
+	fn release(&self, key: &str) {
+		self.0.store.lock().unwrap().remove(key);
+	}
+}
+
+impl IntoResponse for CachedResponse {
+	fn into_response(self) -> Response {
+		let mut response = (self.status, self.headers, self.body).into_response();
+		response
+			.headers_mut()
+			.insert("idempotent-replayed", "true".parse().unwrap());
+		response
+	}
+}
+
+/// Answer writes carrying an `Idempotency-Key` header that was seen within `IDEMPOTENCY_TTL_SECS` (a day by default)
+/// with the response to the first of them, without handling them again.
+///
+/// Keys are scoped to the method and path. A repeat arriving while the first request is still being handled gets a 409.
+/// Server errors aren't remembered, and neither are streamed responses or ones over 1 MiB (or `IDEMPOTENCY_MAX_BYTES`,
+/// if that's smaller), so retrying those applies the write again. Responses are remembered before they're compressed, so a replay is encoded for the client asking.
+pub async fn deduplicate<B>(State(idempotency): State<Idempotency>, req: Request<B>, next: Next<B>) -> Response {
+	let is_write = matches!(*req.method(), Method::POST | Method::PUT | Method::PATCH | Method::DELETE);
+	let Some(key) = req
+		.headers()
+		.get("idempotency-key")
+		.and_then(|key| key.to_str().ok())
+		.filter(|_| is_write)
+	else {
+		return next.run(req).await;
+	};
+	let key = format!("{} {} {key}", req.method(), req.uri().path());
+
+	match idempotency.claim(&key) {
+		Claim::Replay(response) => return response.into_response(),
+		Claim::InProgress => {
+			return HTTPError::new("A request with this idempotency key is still being handled")
+				.with_status(StatusCode::CONFLICT)
+				.into_response()
+		},
+		Claim::New => {},
+	}
+
+	// Releases the key if this request doesn't get to store a response, such as when the client disconnects
+	let pending = Pending {
+		idempotency,
+		key,
+		completed: false,
+	};
+
+	let response = next.run(req).await;
+	if response.status().is_server_error() {
+		return response;
+	}
+
+	let (parts, mut body) = response.into_parts();
+	// Buffering a streamed body would hold all of it in memory, so those go through as they are, and `pending` forgets
+	// the key
+	let max_body_bytes = u64::try_from(idempotency.max_body_bytes()).unwrap_or(u64::MAX);
+	if body.size_hint().upper().map_or(true, |upper| upper > max_body_bytes) {
+		return Response::from_parts(parts, body);
+	}
+
+	let mut buffer = Vec::new();
+	while let Some(chunk) = body.data().await {
+		match chunk {
+			Ok(chunk) => buffer.extend_from_slice(&chunk),
+			Err(err) => {
+				return HTTPError::new(&err.to_string())
+					.with_status(StatusCode::INTERNAL_SERVER_ERROR)
+					.into_response();
+			},
+		}
+	}
+
+	let body = Bytes::from(buffer);
+	pending.complete(CachedResponse {
+		status: parts.status,
+		headers: parts.headers.clone(),
+		body: body.clone(),
+	});
+	Response::from_parts(parts, boxed(Full::from(body)))
+}
+
+struct Pending {
+	idempotency: Idempotency,
+	key: String,
+	completed: bool,
+}
+
+impl Pending {
+	fn complete(mut self, response: CachedResponse) {
+		self.idempotency.complete(&self.key, response);
+		self.completed = true;
+	}
+}
+
+impl Drop for Pending {
+	fn drop(&mut self) {
+		if !self.completed {
+			self.idempotency.release(&self.key);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn response(body: &'static str) -> CachedResponse {
+		CachedResponse {
+			status: StatusCode::CREATED,
+			headers: HeaderMap::new(),
+			body: Bytes::from(body),
+		}
+	}
+
+	#[test]
+	fn the_oldest_responses_are_forgotten_past_the_byte_budget() {
+		let idempotency = Idempotency(Arc::new(Keys {
+			ttl: DEFAULT_TTL,
+			max_bytes: 10,
+			store: Mutex::default(),
+		}));
+
+		for key in ["first", "second"] {
+			assert!(matches!(idempotency.claim(key), Claim::New));
+			idempotency.complete(key, response("sixsix"));
+		}
+
+		assert!(matches!(idempotency.claim("second"), Claim::Replay(_)));
+		assert!(matches!(idempotency.claim("first"), Claim::New));
+		assert_eq!(idempotency.0.store.lock().unwrap().bytes, 6);
+	}
+}
@@ -0,0 +1,56 @@
+use schemars::JsonSchema;
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use crate::db::micros;
+
+/// Number of recent queries latency percentiles are computed over
+const WINDOW_SIZE: usize = 256;
+
+/// Latencies of a collection's most recent queries, kept in memory only
+#[derive(Debug, Default)]
+pub struct LatencyWindow(Mutex<VecDeque<Duration>>);
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct LatencySummary {
+	/// Number of queries the percentiles are computed over
+	samples: usize,
+	/// Median query latency, in microseconds
+	p50_us: u64,
+	/// 95th percentile query latency, in microseconds
+	p95_us: u64,
+}
+
+impl LatencyWindow {
+	pub fn record(&self, latency: Duration) {
+		let mut latencies = self.0.lock().unwrap();
+
+		if latencies.len() == WINDOW_SIZE {
+			latencies.pop_front();
+		}
+		latencies.push_back(latency);
+	}
+
+	/// Percentiles over the window, or `None` before the first query
+	pub fn summary(&self) -> Option<LatencySummary> {
+		let mut latencies: Vec<Duration> = self.0.lock().unwrap().iter().copied().collect();
+		if latencies.is_empty() {
+			return None;
+		}
+
+		latencies.sort_unstable();
+		// Nearest-rank percentile
+		let percentile = |pct: usize| micros(latencies[(latencies.len() * pct + 99) / 100 - 1]);
+
+		Some(LatencySummary {
+			samples: latencies.len(),
+			p50_us: percentile(50),
+			p95_us: percentile(95),
+		})
+	}
+}
+
+impl Clone for LatencyWindow {
+	fn clone(&self) -> Self {
+		Self(Mutex::new(self.0.lock().unwrap().clone()))
+	}
+}
@@ -10,13 +10,26 @@ use std::path::PathBuf;
 use clap::Parser;
 
 
+mod audit;
+mod cache;
 mod db;
 mod errors;
+mod export;
+mod extract;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod idempotency;
+mod latency;
+mod mmap;
+mod replica;
+mod response;
 mod routes;
 mod server;
 mod shutdown;
 mod similarity;
 #[cfg(feature = "llm")]
+mod reembed;
+#[cfg(feature = "llm")]
 mod rustllm;
 
 
@@ -29,6 +42,72 @@ pub struct LLMModelArgs {
     pub tokenizer_path: Option<PathBuf>,
     #[arg(long, short = 'r')]
     pub tokenizer_repository: Option<String>,
+    /// Maximum number of tokens the model can attend to (defaults to 2048)
+    #[arg(long)]
+    pub context_size: Option<usize>,
+    /// Offload the model to the GPU, if llm was built with GPU support
+    #[arg(long)]
+    pub use_gpu: bool,
+    /// Number of layers to offload to the GPU when --use-gpu is set (defaults to all of them)
+    #[arg(long)]
+    pub n_gpu_layers: Option<usize>,
+    /// Sampling temperature, higher values are more random
+    #[arg(long, env = "LLM_TEMPERATURE")]
+    pub temperature: Option<f32>,
+    /// Only sample from the k most likely tokens
+    #[arg(long, env = "LLM_TOP_K")]
+    pub top_k: Option<usize>,
+    /// Only sample from the most likely tokens whose probabilities add up to p
+    #[arg(long, env = "LLM_TOP_P")]
+    pub top_p: Option<f32>,
+    /// Penalty applied to recently generated tokens
+    #[arg(long, env = "LLM_REPEAT_PENALTY")]
+    pub repeat_penalty: Option<f32>,
+    /// Name requests use to select the primary model, which is used when they don't name one
+    #[arg(long, default_value = "default")]
+    pub model_name: String,
+    /// An additional model to load, as `name=architecture:path`. Can be repeated.
+    #[arg(long = "extra-model")]
+    pub extra_models: Vec<ModelSpec>,
+    /// Inference and embedding requests handled at once, across all models. Requests beyond this get a 429.
+    #[arg(long, env = "MAX_INFERENCE_CONCURRENCY", default_value_t = 1, value_parser = parse_concurrency)]
+    pub max_inference_concurrency: usize,
+}
+
+/// An additional model, loaded with its embedded tokenizer and the primary model's parameters
+#[cfg(feature = "llm")]
+#[derive(Clone, Debug)]
+pub struct ModelSpec {
+    pub name: String,
+    pub architecture: llm::ModelArchitecture,
+    pub path: PathBuf,
+}
+
+#[cfg(feature = "llm")]
+impl std::str::FromStr for ModelSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("Invalid model {spec}, expected name=architecture:path");
+        let (name, model) = spec.split_once('=').ok_or_else(invalid)?;
+        let (architecture, path) = model.split_once(':').ok_or_else(invalid)?;
+
+        Ok(Self {
+            name: name.to_string(),
+            architecture: architecture.parse().map_err(|err| format!("{err}"))?,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// At least one request has to be allowed at once, or every inference and embedding request would get a 429
+#[cfg(feature = "llm")]
+fn parse_concurrency(value: &str) -> std::result::Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(concurrency) => Ok(concurrency),
+        Err(err) => Err(format!("{err}")),
+    }
 }
 
 #[cfg(feature = "llm")]
@@ -50,6 +129,27 @@ impl LLMModelArgs {
             (None, None) => llm::TokenizerSource::Embedded,
         }
     }
+    pub fn to_inference_parameters(&self) -> llm::InferenceParameters {
+        let defaults = llm::samplers::TopPTopK::default();
+        llm::InferenceParameters {
+            sampler: std::sync::Arc::new(llm::samplers::TopPTopK {
+                temperature: self.temperature.unwrap_or(defaults.temperature),
+                top_k: self.top_k.unwrap_or(defaults.top_k),
+                top_p: self.top_p.unwrap_or(defaults.top_p),
+                repeat_penalty: self.repeat_penalty.unwrap_or(defaults.repeat_penalty),
+                ..defaults
+            }),
+        }
+    }
+    pub fn to_model_parameters(&self) -> llm::ModelParameters {
+        let defaults = llm::ModelParameters::default();
+        llm::ModelParameters {
+            context_size: self.context_size.unwrap_or(defaults.context_size),
+            use_gpu: self.use_gpu,
+            gpu_layers: self.n_gpu_layers,
+            ..defaults
+        }
+    }
 }
 
 #[cfg(feature = "llm")]
@@ -0,0 +1,123 @@
+//! Read-only storage backend keeping vector data in a memory-mapped file, so datasets larger than RAM can be served.
+//!
+//! The first start in this mode converts the regular store into an index (everything but the vectors)
+//! and a flat file of native-endian `f32`s. Later starts only read the index and map the vector file,
+//! letting the OS page vectors in as `get_similarity` touches them.
+
+use anyhow::Context;
+use memmap2::Mmap;
+use std::{
+	collections::HashMap,
+	fs::{self, File},
+	io::{BufWriter, Write},
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+
+use crate::{
+	db::{Collection, Db, STORE_PATH},
+	similarity::{MappedVector, Vector},
+};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Index {
+	collections: HashMap<String, Collection>,
+	aliases: HashMap<String, String>,
+	/// Byte offset and length of every embedding's vector, in the same order as the collection's embeddings
+	offsets: HashMap<String, Vec<(usize, usize)>>,
+}
+
+fn index_path() -> PathBuf {
+	STORE_PATH.with_extension("index")
+}
+
+fn vectors_path() -> PathBuf {
+	STORE_PATH.with_extension("vectors")
+}
+
+pub fn load() -> anyhow::Result<Db> {
+	if is_stale(&index_path())? {
+		tracing::info!("Building memory-mapped vector store");
+		build()?;
+	}
+
+	tracing::debug!("Loading memory-mapped vector store");
+	let file = File::open(vectors_path())?;
+	// SAFETY: the vector file is only written while building, which happens before it gets mapped
+	let map = Arc::new(unsafe { Mmap::map(&file)? });
+	let Index {
+		mut collections,
+		aliases,
+		offsets,
+	} = bincode::deserialize(&fs::read(index_path())?)?;
+
+	for (name, collection) in &mut collections {
+		let offsets = offsets
+			.get(name)
+			.with_context(|| format!("Missing vector offsets for collection {name}"))?;
+
+		for (embedding, &(offset, len)) in collection.embeddings.iter_mut().zip(offsets) {
+			embedding.vector = Vector::Mapped(MappedVector {
+				map: Arc::clone(&map),
+				offset,
+				len,
+			});
+		}
+	}
+
+	Ok(Db {
+		collections,
+		aliases,
+		read_only: true,
+		ephemeral: false,
+	})
+}
+
+/// Whether the index is missing or older than the store it was built from
+fn is_stale(index_path: &Path) -> anyhow::Result<bool> {
+	if !index_path.exists() {
+		return Ok(true);
+	}
+	if !STORE_PATH.exists() {
+		return Ok(false);
+	}
+
+	Ok(fs::metadata(STORE_PATH.as_path())?.modified()? > fs::metadata(index_path)?.modified()?)
+}
+
+fn build() -> anyhow::Result<()> {
+	let mut db = Db::load_from_store()?;
+	// The store is only being read from, so don't write it back when `db` is dropped
+	db.read_only = true;
+
+	let mut vectors = BufWriter::new(File::create(vectors_path())?);
+	let mut offsets = HashMap::with_capacity(db.collections.len());
+	let mut position = 0;
+
+	for (name, collection) in &mut db.collections {
+		let mut collection_offsets = Vec::with_capacity(collection.embeddings.len());
+
+		for embedding in &mut collection.embeddings {
+			let vector = embedding.vector.to_f32();
+			let bytes: &[u8] = bytemuck::cast_slice(&vector);
+
+			vectors.write_all(bytes)?;
+			collection_offsets.push((position, vector.len()));
+			position += bytes.len();
+
+			embedding.vector = Vector::F32(Vec::new());
+		}
+
+		offsets.insert(name.clone(), collection_offsets);
+	}
+	vectors.flush()?;
+
+	let index = Index {
+		collections: std::mem::take(&mut db.collections),
+		aliases: std::mem::take(&mut db.aliases),
+		offsets,
+	};
+	fs::write(index_path(), bincode::serialize(&index)?)?;
+
+	Ok(())
+}
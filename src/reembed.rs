@@ -0,0 +1,230 @@
+//! Background jobs regenerating a collection's vectors with an LLM, from source text kept in each embedding's metadata.
+
+use axum::Extension;
+use schemars::JsonSchema;
+use std::{
+	collections::{HashMap, VecDeque},
+	sync::{Arc, Mutex},
+};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::{
+	db::{Db, Embedding, InsertOutcome, MetadataValue, OnConflict},
+	rustllm::LLMModels,
+	similarity::Vector,
+};
+
+/// Embeddings re-embedded between writes to the database, which hold the write lock and save the store
+const CHUNK_SIZE: usize = 256;
+
+/// Progress is kept for this many stopped jobs, after which the oldest one's is forgotten
+const KEPT_STOPPED_JOBS: usize = 100;
+
+#[allow(clippy::module_name_repetitions)]
+pub type ReembedJobsExtension = Extension<Arc<ReembedJobs>>;
+
+/// Progress of every running re-embedding job, and of the last `KEPT_STOPPED_JOBS` that stopped
+#[derive(Debug, Default)]
+pub struct ReembedJobs {
+	jobs: Mutex<Jobs>,
+}
+
+#[derive(Debug, Default)]
+struct Jobs {
+	progress: HashMap<Uuid, ReembedProgress>,
+	/// Jobs that finished or failed, oldest first
+	stopped: VecDeque<Uuid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+	Running,
+	Finished,
+	Failed,
+}
+
+#[derive(Debug, Clone, serde::Serialize, JsonSchema)]
+pub struct ReembedProgress {
+	/// Collection being re-embedded, with any alias resolved
+	pub collection: String,
+	pub state: JobState,
+	/// Embeddings in the collection when the job started
+	pub total: usize,
+	/// Embeddings whose vector has been regenerated
+	pub reembedded: usize,
+	/// Embeddings without a single source text under the job's metadata key, which are left as they are
+	pub skipped: usize,
+	/// Embeddings updated or deleted while the job ran, which keep the vector they were updated with
+	pub changed: usize,
+	/// Embeddings the collection rejected the new vector of, such as near-duplicates
+	pub failed: usize,
+	/// Why the job stopped, when it failed
+	pub error: Option<String>,
+}
+
+/// What a job re-embeds, and with which model
+pub struct ReembedRequest {
+	pub collection: String,
+	pub text_key: String,
+	pub model: Option<String>,
+}
+
+impl ReembedJobs {
+	pub fn extension(self) -> ReembedJobsExtension {
+		Extension(Arc::new(self))
+	}
+
+	pub fn get(&self, id: Uuid) -> Option<ReembedProgress> {
+		self.jobs.lock().unwrap().progress.get(&id).cloned()
+	}
+
+	fn update(&self, id: Uuid, update: impl FnOnce(&mut ReembedProgress)) {
+		if let Some(progress) = self.jobs.lock().unwrap().progress.get_mut(&id) {
+			update(progress);
+		}
+	}
+
+	/// Record that a job stopped, forgetting the oldest stopped job once there are more than `KEPT_STOPPED_JOBS`
+	fn stop(&self, id: Uuid, update: impl FnOnce(&mut ReembedProgress)) {
+		let mut jobs = self.jobs.lock().unwrap();
+		if let Some(progress) = jobs.progress.get_mut(&id) {
+			update(progress);
+		}
+
+		jobs.stopped.push_back(id);
+		while jobs.stopped.len() > KEPT_STOPPED_JOBS {
+			if let Some(oldest) = jobs.stopped.pop_front() {
+				jobs.progress.remove(&oldest);
+			}
+		}
+	}
+
+	/// Start re-embedding in the background, returning the id its progress is tracked under.
+	/// The collection and model are expected to exist already.
+	pub fn start(
+		self: &Arc<Self>,
+		db: Arc<RwLock<Db>>,
+		models: Arc<LLMModels>,
+		request: ReembedRequest,
+	) -> Uuid {
+		let id = Uuid::new_v4();
+		self.jobs.lock().unwrap().progress.insert(
+			id,
+			ReembedProgress {
+				collection: request.collection.clone(),
+				state: JobState::Running,
+				total: 0,
+				reembedded: 0,
+				skipped: 0,
+				changed: 0,
+				failed: 0,
+				error: None,
+			},
+		);
+
+		let jobs = Arc::clone(self);
+		tokio::task::spawn_blocking(move || {
+			let result = jobs.run(id, &db, &models, &request);
+			jobs.stop(id, |progress| match result {
+				Ok(()) => progress.state = JobState::Finished,
+				Err(error) => {
+					tracing::error!("Re-embedding {} failed: {error}", request.collection);
+					progress.state = JobState::Failed;
+					progress.error = Some(error);
+				},
+			});
+		});
+
+		id
+	}
+
+	fn run(
+		&self,
+		id: Uuid,
+		db: &RwLock<Db>,
+		models: &LLMModels,
+		request: &ReembedRequest,
+	) -> Result<(), String> {
+		let model = models.get(request.model.as_deref()).ok_or("Unknown model")?;
+
+		// Only the source text is copied out, so the job doesn't hold on to a second copy of every vector. Each
+		// embedding's `seq` tells whether it changed by the time its new vector is written.
+		let sources: Vec<(String, u64, String)> = {
+			let db = db.blocking_read();
+			let collection = db
+				.get_collection(&request.collection)
+				.ok_or("Collection was deleted")?;
+			self.update(id, |progress| progress.total = collection.embeddings.len());
+
+			collection
+				.embeddings
+				.iter()
+				.filter_map(|embedding| {
+					let text = embedding
+						.metadata
+						.as_ref()
+						.and_then(|metadata| metadata.get(&request.text_key))
+						.and_then(MetadataValue::as_single)?;
+					Some((embedding.id.clone(), embedding.seq, text.to_string()))
+				})
+				.collect()
+		};
+		self.update(id, |progress| progress.skipped = progress.total - sources.len());
+
+		for chunk in sources.chunks(CHUNK_SIZE) {
+			let vectors: Vec<Vec<f32>> = {
+				let model = model.blocking_read();
+				chunk.iter().map(|(_, _, text)| model.get_embeddings(text)).collect()
+			};
+
+			let (outcomes, changed) = {
+				let mut db = db.blocking_write();
+				let collection = db
+					.get_collection(&request.collection)
+					.ok_or("Collection was deleted")?;
+
+				// Embeddings are only overwritten as they were when their text was read, so an update made since keeps
+				// its metadata and vector, and a delete isn't undone
+				let current: HashMap<&str, &Embedding> = collection
+					.embeddings
+					.iter()
+					.map(|embedding| (embedding.id.as_str(), embedding))
+					.collect();
+				let embeddings: Vec<Embedding> = chunk
+					.iter()
+					.zip(vectors)
+					.filter_map(|((id, seq, _), vector)| {
+						let embedding = current.get(id.as_str()).filter(|embedding| embedding.seq == *seq)?;
+						Some(Embedding {
+							id: id.clone(),
+							vector: Vector::F32(vector),
+							metadata: embedding.metadata.clone(),
+							seq: 0,
+							touched_at: 0,
+						})
+					})
+					.collect();
+				let changed = chunk.len() - embeddings.len();
+
+				let outcomes = db
+					.insert_many(&request.collection, embeddings, OnConflict::Overwrite)
+					.map_err(|error| error.to_string())?;
+				(outcomes, changed)
+			};
+
+			let failed = outcomes
+				.iter()
+				.filter(|outcome| matches!(outcome, InsertOutcome::Error { .. }))
+				.count();
+			self.update(id, |progress| {
+				progress.reembedded += outcomes.len() - failed;
+				progress.changed += changed;
+				progress.failed += failed;
+			});
+		}
+
+		Ok(())
+	}
+}
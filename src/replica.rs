@@ -0,0 +1,81 @@
+//! Keeps read-only replicas that share a store with a writer up to date, by reloading the store whenever it changes on disk.
+
+use anyhow::Result;
+use axum::Extension;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{env, time::Duration};
+use tokio::sync::mpsc;
+
+use crate::db::{Db, DbExtension, STORE_PATH};
+
+/// How long the store has to stay untouched before it's reloaded, when `RELOAD_DEBOUNCE_MS` isn't set
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reload the database whenever the store file changes, if it's a read-only heap-backed replica.
+///
+/// Reloads wait until the store has gone `RELOAD_DEBOUNCE_MS` without changing, so a save in progress
+/// isn't read half-written. A store that fails to load anyway is skipped, and the current database keeps being served.
+pub fn watch_store(Extension(db): &DbExtension) -> Result<()> {
+	if !db.try_read().map_or(false, |db| db.read_only) {
+		return Ok(());
+	}
+	// Memory-mapped replicas rebuild their vector file on load, which can't happen while it's mapped
+	if env::var("DB_STORAGE").is_ok_and(|storage| storage != "heap") {
+		tracing::warn!("Store changes are only picked up by heap-backed replicas");
+		return Ok(());
+	}
+
+	let debounce = env::var("RELOAD_DEBOUNCE_MS")
+		.ok()
+		.map(|debounce| debounce.parse())
+		.transpose()?
+		.map_or(DEFAULT_DEBOUNCE, Duration::from_millis);
+
+	let (sender, mut changes) = mpsc::unbounded_channel();
+	let store_name = STORE_PATH.file_name().map(ToOwned::to_owned);
+	let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+		Ok(event)
+			if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+				&& event.paths.iter().any(|path| path.file_name() == store_name.as_deref()) =>
+		{
+			sender.send(()).ok();
+		},
+		Ok(_) => {},
+		Err(err) => tracing::warn!("Couldn't watch store: {err}"),
+	})?;
+	// The directory is watched, so the store being replaced rather than rewritten is noticed too
+	watcher.watch(
+		STORE_PATH.parent().unwrap_or(STORE_PATH.as_path()),
+		RecursiveMode::NonRecursive,
+	)?;
+
+	let db = db.clone();
+	tokio::spawn(async move {
+		// Watching stops once the watcher is dropped
+		let _watcher = watcher;
+
+		while changes.recv().await.is_some() {
+			loop {
+				match tokio::time::timeout(debounce, changes.recv()).await {
+					Ok(Some(())) => continue,
+					Ok(None) => return,
+					Err(_) => break,
+				}
+			}
+
+			match tokio::task::spawn_blocking(Db::load_from_store).await {
+				Ok(Ok(mut fresh)) => {
+					fresh.read_only = true;
+					fresh.invalidate_generations();
+					*db.write().await = fresh;
+					tracing::info!("Reloaded database from store");
+				},
+				Ok(Err(err)) => tracing::warn!("Couldn't reload store, keeping the current database: {err}"),
+				Err(err) => tracing::error!("Store reload panicked: {err}"),
+			}
+		}
+	});
+
+	tracing::info!("Watching store for changes");
+	Ok(())
+}
@@ -0,0 +1,196 @@
+use aide::{
+	gen::GenContext,
+	openapi::{Operation, Response as ApiResponse},
+	OperationInput, OperationOutput,
+};
+use axum::{
+	async_trait,
+	body::{boxed, Empty},
+	extract::FromRequestParts,
+	http::{header, request::Parts, HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+};
+use axum_jsonschema::Json;
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{Number, Value};
+use std::{convert::Infallible, env};
+
+use crate::errors::HTTPError;
+
+/// Rounding beyond this many decimals doesn't change an `f64`
+const MAX_DECIMALS: u8 = 15;
+
+lazy_static! {
+	/// Decimals responses are rounded to when a request doesn't ask for any, from `RESPONSE_PRECISION`
+	static ref DEFAULT_PRECISION: Option<u8> = env::var("RESPONSE_PRECISION")
+		.ok()
+		.and_then(|precision| precision.parse().ok());
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, JsonSchema)]
+pub struct Rounding {
+	/// Round floats in the response to this many decimals. Defaults to full precision.
+	precision: Option<u8>,
+}
+
+impl Rounding {
+	fn decimals(self) -> Option<u8> {
+		self.precision.or(*DEFAULT_PRECISION)
+	}
+}
+
+/// Status to answer a query with when nothing matched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, JsonSchema)]
+pub enum EmptyStatus {
+	/// An empty list
+	#[default]
+	#[serde(rename = "200")]
+	Ok,
+	/// No body at all
+	#[serde(rename = "204")]
+	NoContent,
+	/// An error saying nothing matched
+	#[serde(rename = "404")]
+	NotFound,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, JsonSchema)]
+pub struct OnEmpty {
+	/// Status code to respond with when there are no results. Defaults to 200 with an empty list.
+	#[serde(default)]
+	empty: EmptyStatus,
+}
+
+impl OnEmpty {
+	/// `response`, or the requested status instead when `is_empty`. Headers survive a 204, but not a 404.
+	pub fn respond(self, is_empty: bool, response: impl IntoResponse) -> Response {
+		match (is_empty, self.empty) {
+			(false, _) | (true, EmptyStatus::Ok) => response.into_response(),
+			(true, EmptyStatus::NoContent) => {
+				let (mut parts, _) = response.into_response().into_parts();
+				parts.status = StatusCode::NO_CONTENT;
+				parts.headers.remove(header::CONTENT_TYPE);
+				Response::from_parts(parts, boxed(Empty::new()))
+			},
+			(true, EmptyStatus::NotFound) => HTTPError::new("No results matched the query")
+				.with_status(StatusCode::NOT_FOUND)
+				.into_response(),
+		}
+	}
+}
+
+/// Encoding a response is sent in, picked from the request's `Accept` header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+	#[default]
+	Json,
+	/// For `Accept: application/msgpack`
+	MessagePack,
+}
+
+impl Format {
+	pub fn is_msgpack(headers: &HeaderMap, name: header::HeaderName) -> bool {
+		headers
+			.get(name)
+			.and_then(|value| value.to_str().ok())
+			.is_some_and(|value| value.contains("application/msgpack") || value.contains("application/x-msgpack"))
+	}
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Format {
+	type Rejection = Infallible;
+
+	async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+		if Self::is_msgpack(&parts.headers, header::ACCEPT) {
+			Ok(Self::MessagePack)
+		} else {
+			Ok(Self::Json)
+		}
+	}
+}
+
+impl OperationInput for Format {}
+
+/// JSON response with its floats, such as vector components and scores, rounded to the requested precision.
+/// It's sent as MessagePack instead for clients that accept it, which keeps `f32`s at four bytes each when not rounded.
+pub struct RoundedJson<T>(pub T, pub Rounding, pub Format);
+
+impl<T: Serialize> IntoResponse for RoundedJson<T> {
+	fn into_response(self) -> Response {
+		let Self(body, rounding, format) = self;
+
+		let rounded = match rounding.decimals() {
+			None => None,
+			Some(decimals) => match serde_json::to_value(&body) {
+				Ok(mut value) => {
+					round(&mut value, 10f64.powi(i32::from(decimals.min(MAX_DECIMALS))));
+					Some(value)
+				},
+				Err(err) => {
+					return HTTPError::new(&err.to_string())
+						.with_status(StatusCode::INTERNAL_SERVER_ERROR)
+						.into_response()
+				},
+			},
+		};
+
+		match (format, rounded) {
+			(Format::Json, None) => Json(body).into_response(),
+			(Format::Json, Some(value)) => Json(value).into_response(),
+			(Format::MessagePack, None) => msgpack(&body),
+			(Format::MessagePack, Some(value)) => msgpack(&value),
+		}
+	}
+}
+
+/// Structs are written as maps and vectors as plain lists, so the MessagePack body has the same shape as the JSON one
+fn msgpack<T: Serialize>(body: &T) -> Response {
+	let mut buffer = Vec::new();
+	let mut serializer = rmp_serde::Serializer::new(&mut buffer)
+		.with_struct_map()
+		.with_human_readable();
+
+	match body.serialize(&mut serializer) {
+		Ok(()) => ([(header::CONTENT_TYPE, "application/msgpack")], buffer).into_response(),
+		Err(err) => HTTPError::new(&err.to_string())
+			.with_status(StatusCode::INTERNAL_SERVER_ERROR)
+			.into_response(),
+	}
+}
+
+impl<T> OperationOutput for RoundedJson<T>
+where
+	Json<T>: OperationOutput,
+{
+	type Inner = <Json<T> as OperationOutput>::Inner;
+
+	fn operation_response(ctx: &mut GenContext, operation: &mut Operation) -> Option<ApiResponse> {
+		Json::<T>::operation_response(ctx, operation)
+	}
+
+	fn inferred_responses(
+		ctx: &mut GenContext,
+		operation: &mut Operation,
+	) -> Vec<(Option<u16>, ApiResponse)> {
+		Json::<T>::inferred_responses(ctx, operation)
+	}
+}
+
+fn round(value: &mut Value, factor: f64) {
+	match value {
+		Value::Number(number) if number.is_f64() => {
+			if let Some(rounded) = number
+				.as_f64()
+				.and_then(|float| Number::from_f64((float * factor).round() / factor))
+			{
+				*number = rounded;
+			}
+		},
+		Value::Array(values) => values.iter_mut().for_each(|value| round(value, factor)),
+		Value::Object(map) => map.values_mut().for_each(|value| round(value, factor)),
+		_ => {},
+	}
+}
@@ -0,0 +1,85 @@
+use aide::axum::{
+	routing::{delete, get, put},
+	ApiRouter,
+};
+use axum::{extract::Path, http::StatusCode, Extension};
+use axum_jsonschema::Json;
+use schemars::JsonSchema;
+use std::collections::HashMap;
+
+use crate::{
+	db::{DbExtension, Error as DbError},
+	errors::HTTPError,
+	extract::JsonBody,
+};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new().nest(
+		"/aliases",
+		ApiRouter::new()
+			.api_route("/", get(list_aliases))
+			.api_route("/:alias", put(set_alias))
+			.api_route("/:alias", delete(delete_alias)),
+	)
+}
+
+/// List every alias, along with the collection it points at
+async fn list_aliases(Extension(db): DbExtension) -> Json<HashMap<String, String>> {
+	Json(db.read().await.aliases.clone())
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct SetAlias {
+	/// Collection the alias should point at
+	collection: String,
+}
+
+/// Point an alias at a collection
+///
+/// Requests that name the alias operate on the collection it points at. Repointing an existing alias is a single atomic update.
+async fn set_alias(
+	Path(alias): Path<String>,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<SetAlias>,
+) -> Result<StatusCode, HTTPError> {
+	tracing::trace!("Pointing alias {alias} at {}", req.collection);
+
+	let mut db = db.write().await;
+
+	match db.set_alias(alias, req.collection) {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::UniqueViolation) => Err(HTTPError::new("A collection with that name already exists")
+			.with_status(StatusCode::CONFLICT)),
+		Err(error @ DbError::InvalidName) => {
+			Err(HTTPError::new(&error.to_string()).with_status(StatusCode::BAD_REQUEST))
+		},
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't set alias").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
+	}
+}
+
+/// Delete an alias, keeping the collection it points at
+async fn delete_alias(
+	Path(alias): Path<String>,
+	Extension(db): DbExtension,
+) -> Result<StatusCode, HTTPError> {
+	tracing::trace!("Deleting alias {alias}");
+
+	let mut db = db.write().await;
+
+	match db.delete_alias(&alias) {
+		Ok(_) => Ok(StatusCode::NO_CONTENT),
+		Err(DbError::AliasNotFound) => {
+			Err(HTTPError::new("Alias not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't delete alias").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
+	}
+}
@@ -2,177 +2,1926 @@ use aide::axum::{
 	routing::{delete, get, post, put},
 	ApiRouter,
 };
-use axum::{extract::Path, http::StatusCode, Extension};
+use axum::{
+	body::{Body, Bytes},
+	extract::{BodyStream, DefaultBodyLimit, Path, Query},
+	http::{header, HeaderMap, StatusCode},
+	response::{IntoResponse, Response},
+	Extension,
+};
 use axum_jsonschema::Json;
+use futures_util::StreamExt;
 use schemars::JsonSchema;
-use std::time::Instant;
+use std::{
+	collections::{HashMap, HashSet},
+	sync::Arc,
+	time::Instant,
+};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+use crate::{
+	cache::{QueryCache, QueryCacheExtension},
+	db::{self, AffectedEmbeddings, BatchOperation, Collection, CompactReport, CounterSummary, Db, DbExtension, DeleteSummary, DuplicatePair, Embedding, Exclusions, IdOrder, InsertOutcome, Metadata, MultiCollectionResults, OnConflict, QueryTiming, Error as DbError, SimilarityResult, VerifyReport, WarmupReport, MetadataEqualities, MetadataKey, MetadataNumber, MetadataType, NumberCondition, ValueRange, StringMatch},
+	errors::HTTPError,
+	export,
+	extract::JsonBody,
+	latency::LatencySummary,
+	response::{Format, OnEmpty, Rounding, RoundedJson},
+	similarity::{normalize, Distance, Normalization, Precision},
+};
+#[cfg(feature = "llm")]
+use crate::reembed::{ReembedJobsExtension, ReembedProgress, ReembedRequest};
+
+/// Pairwise duplicate detection is quadratic, so refuse it above this many embeddings
+const MAX_PAIRWISE_EMBEDDINGS: usize = 10_000;
+
+/// Metadata key inserts keep their source `text` under, which re-embedding reads it from unless told otherwise
+const SOURCE_TEXT_KEY: &str = "text";
+
+/// Exported embeddings and streamed query results are sent to the client in chunks of roughly this many bytes
+const EXPORT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// NDJSON imports are inserted in batches of roughly this many bytes of body, which is also the longest line accepted
+const IMPORT_BATCH_BYTES: usize = 4 * 1024 * 1024;
+
+/// Routes under `/collections`, with similarity queries accepting bodies of up to `query_body_limit` bytes
+pub fn handler(query_body_limit: usize) -> ApiRouter {
+	// The innermost body limit wins, so this overrides the general one for queries only
+	let queries = ApiRouter::new()
+		.api_route("/query_multi_collection", post(query_multi_collection))
+		.api_route("/:collection_name", post(query_collection))
+		.api_route("/:collection_name/hybrid", post(hybrid_search))
+		.api_route("/:collection_name/query_sparse", post(query_sparse_collection))
+		.api_route("/:collection_name/query_arithmetic", post(query_arithmetic_collection))
+		.api_route("/:collection_name/query_stream", post(query_stream_collection));
+	#[cfg(feature = "llm")]
+	let queries = queries.api_route("/:collection_name/query_text", post(query_text_collection));
+
+	let collections = ApiRouter::new()
+		.api_route("/", get(list_collections))
+		.api_route("/delete_batch", post(delete_collections))
+		.api_route("/:collection_name", put(create_collection))
+		.api_route("/:collection_name", get(get_collection_info))
+		.api_route("/:collection_name", delete(delete_collection))
+		.api_route("/:collection_name/insert", post(insert_into_collection))
+		.api_route("/:collection_name/insert_batch", post(insert_batch_into_collection))
+		.api_route("/:collection_name/import_csv", post(import_csv))
+		.api_route("/:collection_name/import_ndjson", post(import_ndjson))
+		.api_route("/:collection_name/batch_ops", post(batch_operations))
+		.api_route("/:collection_name/find_duplicates", post(find_duplicates))
+		.api_route("/:collection_name/sample", get(sample_collection))
+		.api_route("/:collection_name/ids", get(list_ids))
+		.api_route("/:collection_name/changes", get(list_changes))
+		.api_route("/:collection_name/export", get(export_collection))
+		.api_route("/:collection_name/export.parquet", get(export_collection_parquet))
+		.api_route("/:collection_name/verify", get(verify_collection))
+		.api_route("/:collection_name/warmup", post(warmup_collection))
+		.api_route("/:collection_name/metadata_keys", get(list_metadata_keys))
+		.api_route("/:collection_name/compact", post(compact_collection))
+		.api_route("/:collection_name/reserve", post(reserve_collection))
+		.api_route("/:collection_name/:id", get(query_id_collection))
+		.api_route("/:collection_name/:id", delete(delete_id_collection))
+		.api_route("/:collection_name/:id/touch", post(touch_id_collection))
+		.api_route("/:collection_name/query", post(query_metadata_string_collection))
+		.api_route("/:collection_name/querynum", post(query_metadata_number_collection))
+		.merge(queries.layer(DefaultBodyLimit::max(query_body_limit)));
+
+	#[cfg(feature = "llm")]
+	let collections = collections
+		.api_route("/:collection_name/reembed", post(reembed_collection))
+		.api_route("/:collection_name/reembed/:job_id", get(reembed_progress));
+
+	ApiRouter::new().nest("/collections", collections)
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct DryRun {
+	/// Report what would be affected without changing anything
+	#[serde(default)]
+	dry_run: bool,
+}
+
+/// Create a new collection
+///
+/// Names may only contain ASCII letters, digits, `-` and `_`, and are at most 64 characters long unless
+/// `COLLECTION_NAME_MAX_LENGTH` says otherwise.
+/// `embeddings` seeds the collection, validated and normalized like inserts, with a later embedding replacing an earlier
+/// one with the same id. If any of them is rejected, the collection isn't created.
+/// When several requests create the same collection at once, exactly one gets a 201 and the others a 409.
+/// `?reserve=` allocates room for that many embeddings up front, so a bulk load doesn't keep reallocating.
+async fn create_collection(
+	Path(collection_name): Path<String>,
+	Query(CreateCollectionQuery { reserve }): Query<CreateCollectionQuery>,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<Collection>,
+) -> Result<StatusCode, HTTPError> {
+	tracing::trace!(
+		"Creating collection {collection_name} with dimension {}",
+		req.dimension
+	);
+
+	let mut db = db.write().await;
+
+	let create_result = db.create_collection(collection_name, req, reserve.unwrap_or_default());
+
+	match create_result {
+		Ok(_) => Ok(StatusCode::CREATED),
+		Err(db::Error::UniqueViolation) => {
+			Err(HTTPError::new("Collection already exists").with_status(StatusCode::CONFLICT))
+		},
+		Err(error @ DbError::InvalidName) => {
+			Err(HTTPError::new(&error.to_string()).with_status(StatusCode::BAD_REQUEST))
+		},
+		Err(error @ DbError::InvalidWeights) => Err(HTTPError::new(&error.to_string())
+			.with_field("/distance")
+			.with_status(StatusCode::BAD_REQUEST)),
+		Err(error @ DbError::HammingNormalization) => Err(HTTPError::new(&error.to_string())
+			.with_field("/normalization")
+			.with_status(StatusCode::BAD_REQUEST)),
+		Err(error @ DbError::InvalidValueRange) => Err(HTTPError::new(&error.to_string())
+			.with_field("/value_range")
+			.with_status(StatusCode::BAD_REQUEST)),
+		Err(DbError::BatchFailed { index, source }) => Err(HTTPError::new(&format!("Embedding {index} was rejected: {source}"))
+			.with_field(format!("/embeddings/{index}"))
+			.with_status(StatusCode::BAD_REQUEST)),
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(error @ DbError::CapacityOverflow(_)) => Err(HTTPError::new(&error.to_string())
+			.with_field("reserve")
+			.with_status(StatusCode::BAD_REQUEST)),
+		Err(_) => Err(HTTPError::new("Couldn't create collection").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
+	}
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct CreateCollectionQuery {
+	/// Number of embeddings to allocate room for
+	reserve: Option<usize>,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct ReserveRequest {
+	/// Number of embeddings beyond the current ones to make room for
+	additional: usize,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct ReserveResponse {
+	/// Embeddings the collection can hold without reallocating
+	capacity: usize,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct QueryCollectionQuery {
+	/// Vector to query with
+	query: Vec<f32>,
+	/// Number of results to return, defaulting to the collection's `default_k`
+	#[schemars(range(min = 1))]
+	k: Option<usize>,
+	/// Ids to leave out of the results, such as the embedding the query vector was taken from
+	#[serde(default)]
+	exclude_ids: Vec<String>,
+	/// Leave out results scoring within this of the query, for similar but not identical matches.
+	/// It's a lower bound on distance for `euclidean`, `weighted` and `hamming`, and an upper bound on score otherwise.
+	exclude_within: Option<f32>,
+	/// Only return results scoring in this percentile (0 to 100) or above among every embedding scored, so `90`
+	/// keeps the best 10%. Combined with `k`, whichever cutoff is stricter applies.
+	percentile: Option<f32>,
+	/// Scale the query vector to unit length before scoring, whatever the collection's distance, to rank a `dot`
+	/// collection the way `cosine` would for vectors of similar magnitude. Stored vectors are left as they are.
+	#[serde(default)]
+	normalize_query: bool,
+	/// Score the collection across threads (`true`, the default) or on a single one (`false`), which can be faster
+	/// for small collections. Only the scan strategy changes, not the results.
+	parallel: Option<bool>,
+	#[serde(flatten)]
+	expect: ExpectDistance,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct Explain {
+	/// Include a breakdown of where the time went alongside the results
+	#[serde(default)]
+	explain: bool,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct ExplainedResults {
+	results: Vec<SimilarityResult>,
+	timing: QueryTiming,
+}
+
+/// Query a collection
+///
+/// The `x-cache` response header reports whether the results came from the query cache, and `x-available` and
+/// `x-truncated` report whether the collection held fewer than `k` embeddings to rank.
+/// With `?explain=true`, the results are wrapped in an object alongside a `timing` breakdown.
+/// `?empty=204` or `?empty=404` changes the response when nothing is returned.
+#[allow(clippy::significant_drop_tightening)]
+async fn query_collection(
+	Path(collection_name): Path<String>,
+	Query(Explain { explain }): Query<Explain>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Query(on_empty): Query<OnEmpty>,
+	Extension(db): DbExtension,
+	Extension(cache): QueryCacheExtension,
+	JsonBody(req): JsonBody<QueryCollectionQuery>,
+) -> Result<Response, HTTPError> {
+	tracing::trace!("Querying collection {collection_name}");
+
+	let request_instant = Instant::now();
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	check_distance(collection, &req.expect)?;
+	if req.query.len() != collection.dimension {
+		return Err(HTTPError::new("Query dimension mismatch").with_status(StatusCode::BAD_REQUEST));
+	}
+	if req.percentile.is_some_and(|percentile| !(0.0..=100.0).contains(&percentile)) {
+		return Err(HTTPError::new("The percentile must be between 0 and 100")
+			.with_field("/percentile")
+			.with_status(StatusCode::BAD_REQUEST));
+	}
+	if req.normalize_query && collection.distance == Distance::Hamming {
+		return Err(HTTPError::new("Hamming distance compares vectors bit by bit, so queries can't be normalized")
+			.with_field("/normalize_query")
+			.with_status(StatusCode::BAD_REQUEST));
+	}
+
+	let k = collection.k(req.k);
+	let availability = availability_headers(collection.searchable_count(), k);
+	let respond = |cache_status: &'static str, results: Vec<SimilarityResult>, mut timing: QueryTiming| {
+		let headers = [("x-cache", cache_status)];
+		let is_empty = results.is_empty();
+		if !explain {
+			return on_empty.respond(is_empty, (headers, availability.clone(), RoundedJson(results, rounding, format)));
+		}
+
+		timing.total_us = db::micros(request_instant.elapsed());
+		on_empty.respond(
+			is_empty,
+			(headers, availability.clone(), RoundedJson(ExplainedResults { results, timing }, rounding, format)),
+		)
+	};
+
+	let exclusions = Exclusions {
+		ids: req.exclude_ids.into_iter().collect(),
+		within: req.exclude_within,
+		threshold: None,
+		percentile: req.percentile,
+	};
+	// The cache is keyed on the query vector and `k` alone, so queries with exclusions or normalization bypass it
+	let cacheable = exclusions.is_empty() && !req.normalize_query;
+
+	// Keyed on the collection an alias points at, so repointing it doesn't serve the previous target's results
+	let cache_key = QueryCache::key(db.resolve(&collection_name), &req.query, k);
+	let cached = if cacheable { cache.get(cache_key, collection.generation) } else { None };
+	if let Some(results) = cached {
+		tracing::trace!("Query to {collection_name} served from cache");
+		let timing = QueryTiming {
+			cache_hit: true,
+			..QueryTiming::default()
+		};
+		return Ok(respond("hit", results, timing));
+	}
+
+	let instant = Instant::now();
+	let query = collection.normalize_query(&req.query);
+	let query = if req.normalize_query { normalize(&query) } else { query };
+	let (results, timing) = collection.explain_similarity(&query, k, &exclusions, req.parallel.unwrap_or(true));
+
+	let elapsed = instant.elapsed();
+	collection.latencies.record(elapsed);
+	tracing::trace!("Query to {collection_name} took {elapsed:?}");
+	if cacheable {
+		cache.insert(cache_key, collection.generation, &results);
+	}
+	Ok(respond("miss", results, timing))
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct SparseVector {
+	/// Positions of the non-zero components, each below the collection's dimension
+	indices: Vec<usize>,
+	/// Value of the component at the matching position of `indices`
+	values: Vec<f32>,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct QuerySparseQuery {
+	/// Vector to query with, listing only its non-zero components
+	query: SparseVector,
+	/// Number of results to return, defaulting to the collection's `default_k`
+	#[schemars(range(min = 1))]
+	k: Option<usize>,
+	#[serde(flatten)]
+	expect: ExpectDistance,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct ExpectDistance {
+	/// Distance the client computed the query for. The query is answered with a 409 if the collection uses another one.
+	/// Only the metric is compared, not the weights of `weighted`.
+	expect_distance: Option<Distance>,
+}
+
+/// Reject a query computed for another distance than the collection's, such as after the collection was recreated
+fn check_distance(collection: &Collection, expect: &ExpectDistance) -> Result<(), HTTPError> {
+	match &expect.expect_distance {
+		Some(expected) if expected.name() != collection.distance.name() => {
+			Err(HTTPError::new(&format!(
+				"The query expects {} distance, but the collection uses {}",
+				expected.name(),
+				collection.distance.name()
+			))
+			.with_field("/expect_distance")
+			.with_status(StatusCode::CONFLICT))
+		},
+		_ => Ok(()),
+	}
+}
+
+/// Expand a sparse vector into a dense one of `dimension` components
+fn densify(sparse: &SparseVector, dimension: usize) -> Result<Vec<f32>, HTTPError> {
+	if sparse.indices.len() != sparse.values.len() {
+		return Err(HTTPError::new("Sparse vectors need exactly one value per index")
+			.with_field("/query/values")
+			.with_status(StatusCode::BAD_REQUEST));
+	}
+
+	let mut dense = vec![0.0; dimension];
+	let mut seen = vec![false; dimension];
+	for (position, (&index, &value)) in sparse.indices.iter().zip(&sparse.values).enumerate() {
+		if index >= dimension || std::mem::replace(&mut seen[index], true) {
+			return Err(HTTPError::new(&format!(
+				"Index {index} is out of bounds or repeated, indices must be distinct and below {dimension}"
+			))
+			.with_field(format!("/query/indices/{position}"))
+			.with_status(StatusCode::BAD_REQUEST));
+		}
+		dense[index] = value;
+	}
+
+	Ok(dense)
+}
+
+/// Query a collection with a sparse vector, given as the positions and values of its non-zero components
+#[allow(clippy::significant_drop_tightening)]
+async fn query_sparse_collection(
+	Path(collection_name): Path<String>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<QuerySparseQuery>,
+) -> Result<([(&'static str, String); 2], RoundedJson<Vec<SimilarityResult>>), HTTPError> {
+	tracing::trace!("Sparse query to collection {collection_name}");
+
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	check_distance(collection, &req.expect)?;
+	let dense = densify(&req.query, collection.dimension)?;
+
+	let instant = Instant::now();
+	let k = collection.k(req.k);
+	let query = collection.normalize_query(&dense);
+	let results = collection.get_similarity(&query, k);
+
+	let elapsed = instant.elapsed();
+	collection.latencies.record(elapsed);
+	tracing::trace!("Sparse query to {collection_name} took {elapsed:?}");
+	Ok((
+		availability_headers(collection.searchable_count(), k),
+		RoundedJson(results, rounding, format),
+	))
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct ArithmeticTerm {
+	/// Embedding whose stored vector the term uses
+	id: Option<String>,
+	/// Vector the term uses, normalized like a query vector. Exactly one of `id` and `vector` has to be given.
+	vector: Option<Vec<f32>>,
+	/// Weight the vector is added with, negative to subtract it
+	#[serde(default = "default_coefficient")]
+	coefficient: f32,
+}
+
+const fn default_coefficient() -> f32 {
+	1.0
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct QueryArithmeticQuery {
+	/// Vectors to add up into the query vector
+	terms: Vec<ArithmeticTerm>,
+	/// Number of results to return, defaulting to the collection's `default_k`
+	#[schemars(range(min = 1))]
+	k: Option<usize>,
+	/// Leave the embeddings `terms` refer to out of the results
+	#[serde(default = "default_exclude_terms")]
+	exclude_terms: bool,
+}
+
+const fn default_exclude_terms() -> bool {
+	true
+}
+
+/// Weighted sum of the terms of an arithmetic query, in the space stored vectors are in
+fn sum_terms(collection: &Collection, terms: &[ArithmeticTerm]) -> Result<Vec<f32>, HTTPError> {
+	if terms.is_empty() {
+		return Err(HTTPError::new("At least one term is needed")
+			.with_field("/terms")
+			.with_status(StatusCode::BAD_REQUEST));
+	}
+
+	let mut sum = vec![0.0; collection.dimension];
+	for (index, term) in terms.iter().enumerate() {
+		let vector = match (&term.id, &term.vector) {
+			(Some(id), None) => {
+				let embedding = collection.get_id(id).ok_or_else(|| {
+					HTTPError::new(&format!("No embedding with id {id}"))
+						.with_field(format!("/terms/{index}/id"))
+						.with_status(StatusCode::NOT_FOUND)
+				})?;
+				if embedding.vector.is_empty() {
+					return Err(HTTPError::new(&format!("Embedding {id} has no vector"))
+						.with_field(format!("/terms/{index}/id"))
+						.with_status(StatusCode::BAD_REQUEST));
+				}
+				embedding.vector.to_f32().into_owned()
+			},
+			(None, Some(vector)) if vector.len() == collection.dimension => collection.normalize_query(vector),
+			(None, Some(_)) => {
+				return Err(HTTPError::new("Query dimension mismatch")
+					.with_field(format!("/terms/{index}/vector"))
+					.with_status(StatusCode::BAD_REQUEST))
+			},
+			_ => {
+				return Err(HTTPError::new("Terms need exactly one of id and vector")
+					.with_field(format!("/terms/{index}"))
+					.with_status(StatusCode::BAD_REQUEST))
+			},
+		};
+
+		for (total, component) in sum.iter_mut().zip(vector) {
+			*total += term.coefficient * component;
+		}
+	}
+
+	// A sum of unit vectors generally isn't one, which cosine needs
+	if collection.distance == Distance::Cosine || collection.normalization == Normalization::L2 {
+		sum = normalize(&sum);
+	}
+	Ok(sum)
+}
+
+/// Query a collection with a weighted sum of vectors, such as `king - man + woman` given as ids
+///
+/// Terms that name an id use the embedding's stored vector, and the ids are left out of the results unless
+/// `exclude_terms` is `false`. A missing id gets a 404.
+#[allow(clippy::significant_drop_tightening)]
+async fn query_arithmetic_collection(
+	Path(collection_name): Path<String>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<QueryArithmeticQuery>,
+) -> Result<([(&'static str, String); 2], RoundedJson<Vec<SimilarityResult>>), HTTPError> {
+	tracing::trace!("Arithmetic query to collection {collection_name}");
+
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	let instant = Instant::now();
+	let query = sum_terms(collection, &req.terms)?;
+	let exclusions = Exclusions {
+		ids: if req.exclude_terms {
+			req.terms.into_iter().filter_map(|term| term.id).collect()
+		} else {
+			HashSet::new()
+		},
+		..Exclusions::default()
+	};
+	let k = collection.k(req.k);
+	let (results, _) = collection.explain_similarity(&query, k, &exclusions, true);
+
+	let elapsed = instant.elapsed();
+	collection.latencies.record(elapsed);
+	tracing::trace!("Arithmetic query to {collection_name} took {elapsed:?}");
+	Ok((
+		availability_headers(collection.searchable_count(), k),
+		RoundedJson(results, rounding, format),
+	))
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct QueryStreamQuery {
+	/// Vector to query with
+	query: Vec<f32>,
+	/// Number of results to return. Every embedding within `threshold` is returned when omitted.
+	#[schemars(range(min = 1))]
+	k: Option<usize>,
+	/// Only return results scoring at least this close to the query, interpreted according to the collection's
+	/// distance metric
+	threshold: Option<f32>,
+	/// Ids to leave out of the results
+	#[serde(default)]
+	exclude_ids: Vec<String>,
+	#[serde(flatten)]
+	expect: ExpectDistance,
+}
+
+/// Query a collection for a possibly large number of results, streamed as newline-delimited JSON, best first
+///
+/// Only the scores are kept while the response streams. Results are built a chunk at a time under a short read lock,
+/// so neither a slow client nor a large result set holds up writes or memory. If the collection changes in between,
+/// the stream is aborted, as the ranking no longer describes it. Without `k` or `threshold`, every embedding in the
+/// collection is returned.
+#[allow(clippy::significant_drop_tightening)]
+async fn query_stream_collection(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<QueryStreamQuery>,
+) -> Result<Response, HTTPError> {
+	tracing::trace!("Streaming query to collection {collection_name}");
+
+	let (name, generation, ranking) = {
+		let db = db.read().await;
+		let collection = db
+			.get_collection(&collection_name)
+			.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+		check_distance(collection, &req.expect)?;
+		if req.query.len() != collection.dimension {
+			return Err(HTTPError::new("Query dimension mismatch").with_status(StatusCode::BAD_REQUEST));
+		}
+
+		let instant = Instant::now();
+		let exclusions = Exclusions {
+			ids: req.exclude_ids.into_iter().collect(),
+			threshold: req.threshold,
+			..Exclusions::default()
+		};
+		let query = collection.normalize_query(&req.query);
+		let ranking = collection.rank(&query, req.k.unwrap_or(usize::MAX), &exclusions);
+
+		let elapsed = instant.elapsed();
+		collection.latencies.record(elapsed);
+		tracing::trace!("Streaming query to {collection_name} ranked {} results in {elapsed:?}", ranking.len());
+
+		(db.resolve(&collection_name).to_string(), collection.generation, ranking)
+	};
+
+	let (mut sender, body) = Body::channel();
+	tokio::spawn(async move {
+		let mut next = 0;
+		while next < ranking.len() {
+			let chunk = {
+				let db = db.read().await;
+				let Some(collection) = db
+					.get_collection(&name)
+					.filter(|collection| collection.generation == generation)
+				else {
+					tracing::debug!("Streaming query to {collection_name} aborted, as the collection changed");
+					sender.abort();
+					return;
+				};
+
+				ndjson_chunk(
+					ranking[next..]
+						.iter()
+						.zip(next + 1..)
+						.map(|(&score, rank)| collection.result(score, rank)),
+				)
+			};
+
+			let (chunk, count) = match chunk {
+				Ok(chunk) => chunk,
+				Err(err) => {
+					tracing::error!(
+						"Couldn't serialize result {} of a streaming query to {collection_name}: {err}",
+						next + 1
+					);
+					sender.abort();
+					return;
+				},
+			};
+			next += count;
+			if sender.send_data(Bytes::from(chunk)).await.is_err() {
+				tracing::debug!("Streaming query to {collection_name} aborted by the client");
+				return;
+			}
+		}
+	});
+
+	Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+#[cfg(feature = "llm")]
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct QueryTextQuery {
+	/// Text to embed and query with
+	text: String,
+	/// Number of results to return, defaulting to the collection's `default_k`
+	#[schemars(range(min = 1))]
+	k: Option<usize>,
+	/// Model to embed the text with, defaults to the primary model
+	model: Option<String>,
+}
+
+/// Query a collection with text, embedded server-side by the LLM
+///
+/// No lock on the database is held while the text is embedded, which runs off the async runtime, so a slow inference
+/// doesn't hold up writes.
+#[cfg(feature = "llm")]
+#[allow(clippy::significant_drop_tightening)]
+async fn query_text_collection(
+	Path(collection_name): Path<String>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Extension(db): DbExtension,
+	Extension(models): crate::rustllm::LLMExtension,
+	JsonBody(req): JsonBody<QueryTextQuery>,
+) -> Result<RoundedJson<Vec<SimilarityResult>>, HTTPError> {
+	tracing::trace!("Text query to collection {collection_name}");
+
+	if models.get(req.model.as_deref()).is_none() {
+		return Err(HTTPError::new("Unknown model").with_status(StatusCode::BAD_REQUEST));
+	}
+	let dimension = db
+		.read()
+		.await
+		.get_collection(&collection_name)
+		.map(|collection| collection.dimension)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+	let permit = models.try_acquire().ok_or_else(|| {
+		HTTPError::new("Too many concurrent inference requests, retry later")
+			.with_status(StatusCode::TOO_MANY_REQUESTS)
+	})?;
+
+	let QueryTextQuery { text, k, model } = req;
+	let embedding = tokio::task::spawn_blocking(move || {
+		let _permit = permit;
+		let model = models
+			.get(model.as_deref())
+			.expect("model was checked above")
+			.blocking_read();
+
+		// Checked before embedding, so a mismatch doesn't cost an inference
+		if model.embedding_dimension != dimension {
+			return Err(model.embedding_dimension);
+		}
+		Ok(model.get_embeddings(&text))
+	})
+	.await
+	.map_err(|_| HTTPError::new("Inference Error").with_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+	let embedding = embedding.map_err(|model_dimension| dimension_mismatch(model_dimension, dimension))?;
+
+	// The collection may have been deleted, or replaced by one of another dimension, while the text was embedded
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+	if embedding.len() != collection.dimension {
+		return Err(dimension_mismatch(embedding.len(), collection.dimension));
+	}
+
+	let instant = Instant::now();
+	let query = collection.normalize_query(&embedding);
+	let results = collection.get_similarity(&query, collection.k(k));
+
+	let elapsed = instant.elapsed();
+	collection.latencies.record(elapsed);
+	tracing::trace!("Text query to {collection_name} took {elapsed:?}");
+	Ok(RoundedJson(results, rounding, format))
+}
+
+#[cfg(feature = "llm")]
+fn dimension_mismatch(model_dimension: usize, collection_dimension: usize) -> HTTPError {
+	HTTPError::new(&format!(
+		"Model produces {model_dimension}-dimensional embeddings but the collection has dimension {collection_dimension}"
+	))
+	.with_status(StatusCode::BAD_REQUEST)
+}
+
+#[cfg(feature = "llm")]
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct ReembedQuery {
+	/// Metadata key holding each embedding's source text, defaulting to `text`, where inserts keep their `text`
+	#[serde(default = "default_text_key")]
+	text_key: String,
+	/// Model to re-embed with, defaults to the primary model
+	model: Option<String>,
+}
+
+#[cfg(feature = "llm")]
+fn default_text_key() -> String {
+	SOURCE_TEXT_KEY.to_string()
+}
+
+#[cfg(feature = "llm")]
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct ReembedStarted {
+	/// Id to follow the job's progress with
+	job_id: String,
+}
+
+/// Regenerate every vector in a collection from the source text stored in its metadata, such as after switching models
+///
+/// Embeddings are only re-embeddable if they were inserted with their source text, as `text` or under a metadata key.
+/// Those without text under `text_key` keep their vector, as do those updated while the job runs. The job runs in the
+/// background and its progress is reported by `GET /collections/:collection_name/reembed/:job_id`.
+#[cfg(feature = "llm")]
+#[allow(clippy::significant_drop_tightening)]
+async fn reembed_collection(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+	Extension(models): crate::rustllm::LLMExtension,
+	Extension(jobs): ReembedJobsExtension,
+	JsonBody(req): JsonBody<ReembedQuery>,
+) -> Result<(StatusCode, Json<ReembedStarted>), HTTPError> {
+	tracing::trace!("Re-embedding collection {collection_name}");
+
+	// Read before locking the database, as it waits for any inference using the model to finish
+	let model_dimension = models
+		.get(req.model.as_deref())
+		.ok_or_else(|| HTTPError::new("Unknown model").with_status(StatusCode::BAD_REQUEST))?
+		.read()
+		.await
+		.embedding_dimension;
+
+	let collection = {
+		let db = db.read().await;
+		if db.read_only {
+			return Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN));
+		}
+		let collection = db
+			.get_collection(&collection_name)
+			.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+		if model_dimension != collection.dimension {
+			return Err(dimension_mismatch(model_dimension, collection.dimension));
+		}
+
+		db.resolve(&collection_name).to_string()
+	};
+
+	let job_id = jobs.start(
+		db,
+		models,
+		ReembedRequest {
+			collection,
+			text_key: req.text_key,
+			model: req.model,
+		},
+	);
+	Ok((
+		StatusCode::ACCEPTED,
+		Json(ReembedStarted {
+			job_id: job_id.to_string(),
+		}),
+	))
+}
+
+/// Get the progress of a re-embedding job
+#[cfg(feature = "llm")]
+async fn reembed_progress(
+	Path((collection_name, job_id)): Path<(String, String)>,
+	Extension(db): DbExtension,
+	Extension(jobs): ReembedJobsExtension,
+) -> Result<Json<ReembedProgress>, HTTPError> {
+	let collection_name = db.read().await.resolve(&collection_name).to_string();
+
+	Uuid::parse_str(&job_id)
+		.ok()
+		.and_then(|id| jobs.get(id))
+		.filter(|progress| progress.collection == collection_name)
+		.map(Json)
+		.ok_or_else(|| HTTPError::new("Job not found").with_status(StatusCode::NOT_FOUND))
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct HybridQuery {
+	/// Vector to query with
+	query: Vec<f32>,
+	/// Text to look for in metadata values, case-insensitively
+	text: String,
+	/// Only match `text` against this metadata key, instead of every value
+	key: Option<String>,
+	/// Number of results to return, defaulting to the collection's `default_k`
+	#[schemars(range(min = 1))]
+	k: Option<usize>,
+	/// Weight of the vector ranking, defaults to 1
+	vector_weight: Option<f32>,
+	/// Weight of the metadata text ranking, defaults to 1
+	text_weight: Option<f32>,
+}
+
+/// Search a collection by both vector similarity and metadata text, fusing the two rankings
+///
+/// Rankings are combined with reciprocal rank fusion: an embedding scores
+/// `vector_weight / (60 + vector_rank) + text_weight / (60 + text_rank)`, with ranks starting at 1,
+/// and nothing from a ranking it doesn't appear in. Scores are fused, not similarities, so higher is always better.
+#[allow(clippy::significant_drop_tightening)]
+async fn hybrid_search(
+	Path(collection_name): Path<String>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<HybridQuery>,
+) -> Result<RoundedJson<Vec<SimilarityResult>>, HTTPError> {
+	tracing::trace!("Hybrid query to collection {collection_name}");
+
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	if req.query.len() != collection.dimension {
+		return Err(HTTPError::new("Query dimension mismatch").with_status(StatusCode::BAD_REQUEST));
+	}
+
+	let instant = Instant::now();
+	let query = collection.normalize_query(&req.query);
+	let results = collection.hybrid_search(
+		&query,
+		&req.text,
+		req.key.as_deref(),
+		collection.k(req.k),
+		(req.vector_weight.unwrap_or(1.0), req.text_weight.unwrap_or(1.0)),
+	);
+
+	tracing::trace!("Hybrid query to {collection_name} took {:?}", instant.elapsed());
+	Ok(RoundedJson(results, rounding, format))
+}
+
+/// `x-available` and `x-truncated` headers, so clients can tell a short result list apart from one that was filtered down
+fn availability_headers(available: usize, k: usize) -> [(&'static str, String); 2] {
+	[
+		("x-available", available.to_string()),
+		("x-truncated", (available < k).to_string()),
+	]
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct QueryMultiCollectionQuery {
+	/// Collections to query, which must all share the query's dimension and distance metric
+	collections: Vec<String>,
+	/// Vector to query with
+	query: Vec<f32>,
+	/// Number of results to return across all collections. Without it, each collection contributes its `default_k`
+	/// results, and the largest of those is returned.
+	#[schemars(range(min = 1))]
+	k: Option<usize>,
+}
+
+/// Query several collections at once, merging the results
+#[allow(clippy::significant_drop_tightening)]
+async fn query_multi_collection(
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<QueryMultiCollectionQuery>,
+) -> Result<RoundedJson<MultiCollectionResults>, HTTPError> {
+	tracing::trace!("Querying collections {:?}", req.collections);
+
+	let db = db.read().await;
+
+	let instant = Instant::now();
+	let results = db
+		.query_collections(&req.collections, &req.query, req.k)
+		.map_err(|err| match err {
+			DbError::DimensionMismatch => {
+				HTTPError::new("Query dimension mismatch").with_status(StatusCode::BAD_REQUEST)
+			},
+			error @ DbError::MixedDistances => {
+				HTTPError::new(&error.to_string()).with_field("/collections").with_status(StatusCode::BAD_REQUEST)
+			},
+			DbError::NotFound => HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND),
+			DbError::ReadOnly => HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN),
+			_ => HTTPError::new("Couldn't query collections").with_status(StatusCode::INTERNAL_SERVER_ERROR),
+		})?;
+
+	tracing::trace!("Query to {:?} took {:?}", req.collections, instant.elapsed());
+	Ok(RoundedJson(results, rounding, format))
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct CollectionInfo {
+	/// Name of the collection
+	name: String,
+	/// Dimension of the embeddings in the collection
+	dimension: usize,
+	/// Distance function used for the collection
+	distance: Distance,
+	/// Whether a higher score means a closer match under the collection's distance function
+	higher_is_better: bool,
+	/// Normalization applied to inserted and query vectors
+	normalization: Normalization,
+	/// Whether stored vectors differ from the ones that were inserted, because of the normalization or cosine distance
+	normalizes_vectors: bool,
+	/// Precision vectors are stored in
+	precision: Precision,
+	/// Bytes a vector's components take up in the collection's precision.
+	/// Memory-mapped vectors are always stored as `f32`s.
+	bytes_per_vector: usize,
+	/// Bytes of memory all of the collection's vectors take up, not counting memory-mapped ones, which the OS pages in
+	vector_memory_bytes: usize,
+	/// Whether embeddings without a vector are accepted
+	allow_empty_vectors: bool,
+	/// Maximum number of embeddings kept before the least recently inserted or touched ones are evicted
+	max_embeddings: Option<usize>,
+	/// Whether distances are accumulated in `f64`
+	high_precision: bool,
+	/// Score within which inserts are rejected as near-duplicates
+	dedup_threshold: Option<f32>,
+	/// Whether short vectors are zero-padded to the dimension on insertion
+	pad_vectors: bool,
+	/// Range every component of inserted vectors has to be in
+	value_range: Option<ValueRange>,
+	/// Largest metadata value accepted, in bytes
+	max_metadata_value_bytes: usize,
+	/// Most metadata keys an embedding can have
+	max_metadata_keys: usize,
+	/// Number of results similarity queries return when they don't give `k`
+	default_k: usize,
+	/// Metadata keys every embedding must have, and the type of their values
+	metadata_schema: Option<HashMap<String, MetadataType>>,
+	/// Number of embeddings in the collection
+	embedding_count: usize,
+	/// Embeddings the collection can hold before it has to reallocate
+	capacity: usize,
+	/// Unix timestamp (in seconds) of when the collection was created
+	created_at: u64,
+	/// Unix timestamp (in seconds) of the last mutation to the collection
+	updated_at: u64,
+	/// Latency of recent similarity queries that weren't served from the cache, since the server started
+	query_latency: Option<LatencySummary>,
+	/// Inserts, deletes and queries since the server started. Queries served from the cache aren't counted.
+	counters: CounterSummary,
+}
+
+impl CollectionInfo {
+	fn new(name: String, collection: &Collection) -> Self {
+		Self {
+			name,
+			distance: collection.distance.clone(),
+			higher_is_better: collection.distance.higher_is_better(),
+			normalization: collection.normalization,
+			normalizes_vectors: collection.normalizes_vectors(),
+			precision: collection.precision,
+			bytes_per_vector: collection.dimension * collection.precision.bytes_per_component(),
+			vector_memory_bytes: collection.embeddings.iter().map(|embedding| embedding.vector.heap_bytes()).sum(),
+			allow_empty_vectors: collection.allow_empty_vectors,
+			max_embeddings: collection.max_embeddings,
+			high_precision: collection.high_precision,
+			dedup_threshold: collection.dedup_threshold,
+			pad_vectors: collection.pad_vectors,
+			value_range: collection.value_range,
+			max_metadata_value_bytes: collection.metadata_value_limit(),
+			max_metadata_keys: collection.metadata_key_limit(),
+			default_k: collection.k(None),
+			metadata_schema: collection.metadata_schema.clone(),
+			dimension: collection.dimension,
+			embedding_count: collection.embeddings.len(),
+			capacity: collection.embeddings.capacity(),
+			created_at: collection.created_at,
+			updated_at: collection.updated_at,
+			query_latency: collection.latencies.summary(),
+			counters: collection.counters.summary(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+	/// Sort by id, numerically when every id is an integer, or by name for collections
+	Id,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct Sort {
+	/// Order to return items in. Defaults to insertion order for embeddings, and no particular order for collections.
+	sort: Option<SortOrder>,
+}
+
+/// List all collections
+async fn list_collections(
+	Query(Sort { sort }): Query<Sort>,
+	Extension(db): DbExtension,
+) -> Json<Vec<CollectionInfo>> {
+	tracing::trace!("Listing collections");
+
+	let db = db.read().await;
+
+	let mut collections: Vec<CollectionInfo> = db
+		.collections
+		.iter()
+		.map(|(name, collection)| CollectionInfo::new(name.clone(), collection))
+		.collect();
+	if sort == Some(SortOrder::Id) {
+		collections.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+	}
+
+	Json(collections)
+}
+
+/// Get collection info
+///
+/// The response carries an `ETag`, and a request whose `If-None-Match` matches it gets an empty `304 Not Modified`.
+/// The tag only changes when the collection does, not when `query_latency` or `counters` do.
+#[allow(clippy::significant_drop_tightening)]
+async fn get_collection_info(
+	Path(collection_name): Path<String>,
+	headers: HeaderMap,
+	Extension(db): DbExtension,
+) -> Result<Response, HTTPError> {
+	tracing::trace!("Getting collection info for {collection_name}");
+
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	// `generation` tells apart mutations within the same second of `updated_at`
+	let etag = format!(
+		"\"{}-{}-{}\"",
+		collection.updated_at,
+		collection.embeddings.len(),
+		collection.generation
+	);
+	let etag_header = [(header::ETAG, etag.clone())];
+
+	let not_modified = headers
+		.get_all(header::IF_NONE_MATCH)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.map(|tag| tag.trim().trim_start_matches("W/"))
+		.any(|tag| tag == "*" || tag == etag);
+	if not_modified {
+		return Ok((StatusCode::NOT_MODIFIED, etag_header).into_response());
+	}
+
+	Ok((etag_header, Json(CollectionInfo::new(collection_name, collection))).into_response())
+}
+
+/// Delete a collection
+///
+/// With `?dry_run=true`, the collection is kept and the embeddings that would be deleted are returned instead.
+async fn delete_collection(
+	Path(collection_name): Path<String>,
+	Query(DryRun { dry_run }): Query<DryRun>,
+	Extension(db): DbExtension,
+) -> Result<Response, HTTPError> {
+	tracing::trace!("Deleting collection {collection_name} (dry run: {dry_run})");
+
+	let mut db = db.write().await;
+
+	let delete_result = db.delete_collection(&collection_name, dry_run);
+
+	match delete_result {
+		Ok(affected) if dry_run => Ok(Json(affected).into_response()),
+		Ok(_) => Ok(StatusCode::NO_CONTENT.into_response()),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't delete collection").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
+	}
+}
+
+/// Delete several collections at once
+///
+/// With `?dry_run=true`, nothing is deleted and the summary reports what would have been.
+async fn delete_collections(
+	Query(DryRun { dry_run }): Query<DryRun>,
+	Extension(db): DbExtension,
+	JsonBody(names): JsonBody<Vec<String>>,
+) -> Result<Json<DeleteSummary>, HTTPError> {
+	tracing::trace!("Deleting {} collections (dry run: {dry_run})", names.len());
+
+	let mut db = db.write().await;
+
+	db.delete_collections(names, dry_run).map(Json).map_err(|_| {
+		HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN)
+	})
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct InsertEmbedding {
+	/// Unique identifier, generated by the server when omitted
+	id: Option<String>,
+	/// Vector to store
+	vector: Vec<f32>,
+	/// Arbitrary metadata attached to the embedding. Values are strings, or lists of strings for multi-valued keys.
+	metadata: Option<Metadata>,
+	/// Source text the vector was embedded from, kept in the metadata under `text`, replacing any value there, so the
+	/// collection can be re-embedded from it after a model change
+	text: Option<String>,
+}
+
+impl From<InsertEmbedding> for Embedding {
+	fn from(embedding: InsertEmbedding) -> Self {
+		let mut metadata = embedding.metadata;
+		if let Some(text) = embedding.text {
+			metadata
+				.get_or_insert_with(HashMap::new)
+				.insert(SOURCE_TEXT_KEY.to_string(), text.into());
+		}
+
+		Self {
+			id: embedding.id.unwrap_or_else(|| Uuid::new_v4().to_string()),
+			vector: embedding.vector.into(),
+			metadata,
+			seq: 0,
+			touched_at: 0,
+		}
+	}
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct InsertResponse {
+	/// Identifier the embedding was stored under
+	id: String,
+	/// Embedding evicted to make room, when the collection is at its `max_embeddings`
+	evicted: Option<String>,
+}
+
+/// Insert a vector into a collection
+async fn insert_into_collection(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<InsertEmbedding>,
+) -> Result<(StatusCode, Json<InsertResponse>), HTTPError> {
+	tracing::trace!("Inserting into collection {collection_name}");
+
+	let embedding = Embedding::from(req);
+	let id = embedding.id.clone();
+
+	let mut db = db.write().await;
+
+	let insert_result = db.insert_into_collection(&collection_name, embedding);
+
+	match insert_result {
+		Ok(evicted) => Ok((StatusCode::CREATED, Json(InsertResponse { id, evicted }))),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::UniqueViolation) => {
+			Err(HTTPError::new("Vector already exists").with_status(StatusCode::CONFLICT))
+		},
+		Err(error @ DbError::NearDuplicate(_)) => {
+			Err(HTTPError::new(&error.to_string()).with_status(StatusCode::CONFLICT))
+		},
+		Err(DbError::DimensionMismatch) => Err(HTTPError::new(
+			"The provided vector has the wrong dimension",
+		).with_status(StatusCode::BAD_REQUEST)),
+		Err(DbError::InvalidVector) => Err(HTTPError::new(
+			"Zero vectors can't be normalized to unit length",
+		).with_status(StatusCode::BAD_REQUEST)),
+		Err(error @ (DbError::NonBinaryVector | DbError::NonFiniteVector)) => {
+			Err(HTTPError::new(&error.to_string()).with_status(StatusCode::BAD_REQUEST))
+		},
+		Err(error @ DbError::ReservedId(_)) => {
+			Err(HTTPError::new(&error.to_string()).with_field("/id").with_status(StatusCode::BAD_REQUEST))
+		},
+		Err(error @ DbError::OutOfRange { dimension, .. }) => Err(HTTPError::new(&error.to_string())
+			.with_field(format!("/vector/{dimension}"))
+			.with_status(StatusCode::BAD_REQUEST)),
+		Err(error @ DbError::InvalidMetadata(_)) => {
+			Err(HTTPError::new(&error.to_string()).with_status(StatusCode::BAD_REQUEST))
+		},
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't insert embedding").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
+	}
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct InsertBatch {
+	embeddings: Vec<InsertEmbedding>,
+	/// What to do with embeddings whose id already exists
+	#[serde(default)]
+	on_conflict: OnConflict,
+}
+
+/// Insert many vectors into a collection, reporting the outcome of each one
+///
+/// Unlike `batch_ops`, records are inserted independently: a failing record is reported and the rest are still inserted.
+async fn insert_batch_into_collection(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<InsertBatch>,
+) -> Result<Json<Vec<InsertOutcome>>, HTTPError> {
+	tracing::trace!(
+		"Inserting {} embeddings into collection {collection_name}",
+		req.embeddings.len()
+	);
+
+	let embeddings = req.embeddings.into_iter().map(Embedding::from).collect();
+
+	let mut db = db.write().await;
+
+	match db.insert_many(&collection_name, embeddings, req.on_conflict) {
+		Ok(outcomes) => Ok(Json(outcomes)),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't insert batch").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
+	}
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct CsvImportQuery {
+	/// Whether the first row is a header, which is skipped
+	#[serde(default)]
+	header: bool,
+	/// Field delimiter, defaults to a comma
+	delimiter: Option<char>,
+	/// Zero-based column holding the metadata as a JSON object, defaults to 1.
+	/// Every column other than the id (the first one) and the metadata is part of the vector.
+	metadata_column: Option<usize>,
+	/// What to do with embeddings whose id already exists
+	#[serde(default)]
+	on_conflict: OnConflict,
+}
+
+/// Import embeddings from a CSV body with rows of the form `id,metadata_json,v0,v1,...,vn`
+///
+/// Nothing is inserted unless every row parses. The rows are then inserted independently, as with `insert_batch`.
+async fn import_csv(
+	Path(collection_name): Path<String>,
+	Query(req): Query<CsvImportQuery>,
+	Extension(db): DbExtension,
+	body: String,
+) -> Result<Json<Vec<InsertOutcome>>, HTTPError> {
+	tracing::trace!("Importing CSV into collection {collection_name}");
 
-use crate::{
-	db::{self, Collection, DbExtension, Embedding, Error as DbError, SimilarityResult, MetadataEqualities},
-	errors::HTTPError,
-	similarity::Distance,
-};
+	let delimiter = u8::try_from(req.delimiter.unwrap_or(','))
+		.ok()
+		.filter(u8::is_ascii)
+		.ok_or_else(|| {
+			HTTPError::new("The delimiter must be an ASCII character")
+				.with_field("delimiter")
+				.with_status(StatusCode::BAD_REQUEST)
+		})?;
+
+	let metadata_column = req.metadata_column.unwrap_or(1);
+	if metadata_column == 0 {
+		return Err(HTTPError::new("The first column holds the id, not the metadata")
+			.with_field("metadata_column")
+			.with_status(StatusCode::BAD_REQUEST));
+	}
+
+	let embeddings = parse_csv(&body, req.header, delimiter, metadata_column)
+		.map_err(|error| HTTPError::new(&error).with_status(StatusCode::BAD_REQUEST))?;
+
+	let mut db = db.write().await;
 
-pub fn handler() -> ApiRouter {
-	ApiRouter::new().nest(
-		"/collections",
-		ApiRouter::new()
-			.api_route("/:collection_name", put(create_collection))
-			.api_route("/:collection_name", post(query_collection))
-			.api_route("/:collection_name", get(get_collection_info))
-			.api_route("/:collection_name", delete(delete_collection))
-			.api_route("/:collection_name/insert", post(insert_into_collection))
-			.api_route("/:collection_name/:id", get(query_id_collection))
-			.api_route("/:collection_name/:id", delete(delete_id_collection))
-			.api_route("/:collection_name/query", post(query_metadata_string_collection))
-			.api_route("/:collection_name/querynum", post(query_metadata_number_collection))
-	)
+	match db.insert_many(&collection_name, embeddings, req.on_conflict) {
+		Ok(outcomes) => Ok(Json(outcomes)),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't import CSV").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
+	}
 }
 
-/// Create a new collection
-async fn create_collection(
+fn parse_csv(body: &str, header: bool, delimiter: u8, metadata_column: usize) -> Result<Vec<Embedding>, String> {
+	let mut reader = csv::ReaderBuilder::new()
+		.has_headers(header)
+		.delimiter(delimiter)
+		.flexible(true)
+		.from_reader(body.as_bytes());
+
+	reader
+		.records()
+		.map(|record| {
+			let record = record.map_err(|err| err.to_string())?;
+			let line = record.position().map_or(0, csv::Position::line);
+
+			parse_csv_record(&record, metadata_column).map_err(|err| format!("Line {line}: {err}"))
+		})
+		.collect()
+}
+
+fn parse_csv_record(record: &csv::StringRecord, metadata_column: usize) -> Result<Embedding, String> {
+	let id = record.get(0).filter(|id| !id.is_empty()).ok_or("missing id")?;
+
+	let metadata = match record.get(metadata_column) {
+		Some(metadata) if !metadata.trim().is_empty() => Some(
+			serde_json::from_str(metadata).map_err(|err| format!("invalid metadata: {err}"))?,
+		),
+		_ => None,
+	};
+
+	let vector = record
+		.iter()
+		.enumerate()
+		.filter(|&(column, _)| column != 0 && column != metadata_column)
+		.map(|(column, value)| {
+			value
+				.trim()
+				.parse::<f32>()
+				.map_err(|err| format!("column {column}: {err}"))
+		})
+		.collect::<Result<Vec<f32>, String>>()?;
+
+	Ok(Embedding {
+		id: id.to_string(),
+		vector: vector.into(),
+		metadata,
+		seq: 0,
+		touched_at: 0,
+	})
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct NdjsonImportQuery {
+	/// What to do with embeddings whose id already exists
+	#[serde(default)]
+	on_conflict: OnConflict,
+}
+
+#[derive(Debug, Default, serde::Serialize, JsonSchema)]
+struct NdjsonImportSummary {
+	inserted: usize,
+	updated: usize,
+	skipped: usize,
+	/// Lines that couldn't be parsed or inserted
+	errors: Vec<NdjsonImportError>,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct NdjsonImportError {
+	/// One-based line number in the body
+	line: usize,
+	reason: String,
+}
+
+/// Embeddings read from an NDJSON import that haven't been inserted yet, with the line each came from
+#[derive(Default)]
+struct PendingImport {
+	embeddings: Vec<Embedding>,
+	lines: Vec<usize>,
+	bytes: usize,
+}
+
+/// Import embeddings from a newline-delimited JSON body, one embedding per line, as `export` writes them
+///
+/// The body is read as it arrives and inserted in batches of about `IMPORT_BATCH_BYTES`, each under its own write lock,
+/// so a backup of any size can be restored without buffering it. Lines are inserted independently, as with
+/// `insert_batch`: those that don't parse or can't be inserted are reported and the rest are still inserted.
+/// `seq` and `touched_at` are set by the server, whatever the line says. Batches inserted before an error that stops
+/// the import, such as a line longer than `IMPORT_BATCH_BYTES`, stay inserted.
+async fn import_ndjson(
+	Path(collection_name): Path<String>,
+	Query(NdjsonImportQuery { on_conflict }): Query<NdjsonImportQuery>,
+	Extension(db): DbExtension,
+	mut body: BodyStream,
+) -> Result<Json<NdjsonImportSummary>, HTTPError> {
+	tracing::trace!("Importing NDJSON into collection {collection_name}");
+
+	if db.read().await.get_collection(&collection_name).is_none() {
+		return Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND));
+	}
+
+	let mut summary = NdjsonImportSummary::default();
+	let mut pending = PendingImport::default();
+	let mut buffer = Vec::new();
+	let mut line = 0;
+	while let Some(chunk) = body.next().await {
+		let chunk = chunk.map_err(|_| {
+			HTTPError::new("Couldn't read the request body").with_status(StatusCode::BAD_REQUEST)
+		})?;
+		buffer.extend_from_slice(&chunk);
+
+		let mut start = 0;
+		while let Some(end) = buffer[start..].iter().position(|&byte| byte == b'\n') {
+			line += 1;
+			pending.push(&buffer[start..start + end], line, &mut summary);
+			start += end + 1;
+		}
+		buffer.drain(..start);
+
+		if buffer.len() > IMPORT_BATCH_BYTES {
+			return Err(HTTPError::new(&format!("Line {} is longer than {IMPORT_BATCH_BYTES} bytes", line + 1))
+				.with_status(StatusCode::BAD_REQUEST));
+		}
+		if pending.bytes >= IMPORT_BATCH_BYTES {
+			pending.insert(&db, &collection_name, on_conflict, &mut summary).await?;
+		}
+	}
+	pending.push(&buffer, line + 1, &mut summary);
+	pending.insert(&db, &collection_name, on_conflict, &mut summary).await?;
+
+	Ok(Json(summary))
+}
+
+impl PendingImport {
+	/// Parse one line of an import, skipping blank ones
+	fn push(&mut self, bytes: &[u8], line: usize, summary: &mut NdjsonImportSummary) {
+		if bytes.iter().all(u8::is_ascii_whitespace) {
+			return;
+		}
+
+		match serde_json::from_slice::<Embedding>(bytes) {
+			Ok(embedding) => {
+				self.embeddings.push(embedding);
+				self.lines.push(line);
+				self.bytes += bytes.len();
+			},
+			Err(err) => summary.errors.push(NdjsonImportError {
+				line,
+				reason: err.to_string(),
+			}),
+		}
+	}
+
+	/// Insert everything pending, adding the outcomes to `summary`
+	async fn insert(
+		&mut self,
+		db: &Arc<RwLock<Db>>,
+		collection_name: &str,
+		on_conflict: OnConflict,
+		summary: &mut NdjsonImportSummary,
+	) -> Result<(), HTTPError> {
+		if self.embeddings.is_empty() {
+			return Ok(());
+		}
+		let Self { embeddings, lines, .. } = std::mem::take(self);
+
+		let result = db.write().await.insert_many(collection_name, embeddings, on_conflict);
+		let outcomes = match result {
+			Ok(outcomes) => outcomes,
+			Err(DbError::NotFound) => {
+				return Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+			},
+			Err(DbError::ReadOnly) => {
+				return Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+			},
+			Err(_) => {
+				return Err(HTTPError::new("Couldn't import NDJSON").with_status(StatusCode::INTERNAL_SERVER_ERROR))
+			},
+		};
+
+		for (outcome, line) in outcomes.into_iter().zip(lines) {
+			match outcome {
+				InsertOutcome::Inserted { .. } => summary.inserted += 1,
+				InsertOutcome::Updated { .. } => summary.updated += 1,
+				InsertOutcome::Skipped { .. } => summary.skipped += 1,
+				InsertOutcome::Error { reason, .. } => summary.errors.push(NdjsonImportError { line, reason }),
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Apply several inserts and deletes atomically: either all of them are applied, or none are
+///
+/// With `?dry_run=true`, the operations are only validated.
+async fn batch_operations(
 	Path(collection_name): Path<String>,
+	Query(DryRun { dry_run }): Query<DryRun>,
 	Extension(db): DbExtension,
-	Json(req): Json<Collection>,
+	JsonBody(operations): JsonBody<Vec<BatchOperation>>,
 ) -> Result<StatusCode, HTTPError> {
 	tracing::trace!(
-		"Creating collection {collection_name} with dimension {}",
-		req.dimension
+		"Applying {} operations to collection {collection_name} (dry run: {dry_run})",
+		operations.len()
 	);
 
 	let mut db = db.write().await;
 
-	let create_result = db.create_collection(collection_name, req.dimension, req.distance);
-
-	match create_result {
-		Ok(_) => Ok(StatusCode::CREATED),
-		Err(db::Error::UniqueViolation) => {
-			Err(HTTPError::new("Collection already exists").with_status(StatusCode::CONFLICT))
+	match db.apply_batch(&collection_name, operations, dry_run) {
+		Ok(()) => Ok(StatusCode::NO_CONTENT),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
 		},
-		Err(_) => Err(HTTPError::new("Couldn't create collection")),
+		Err(error @ DbError::BatchFailed { index, .. }) => Err(HTTPError::new(&error.to_string())
+			.with_field(format!("/{index}"))
+			.with_status(StatusCode::BAD_REQUEST)),
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't apply batch").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
 	}
 }
 
 #[derive(Debug, serde::Deserialize, JsonSchema)]
-struct QueryCollectionQuery {
-	/// Vector to query with
-	query: Vec<f32>,
-	/// Number of results to return
-	k: Option<usize>,
+struct SampleQuery {
+	/// Number of embeddings to sample
+	n: usize,
+	/// Seed for a reproducible sample
+	seed: Option<u64>,
 }
 
-/// Query a collection
+/// Get a uniformly random sample of a collection's embeddings
 #[allow(clippy::significant_drop_tightening)]
-async fn query_collection(
+async fn sample_collection(
 	Path(collection_name): Path<String>,
+	Query(req): Query<SampleQuery>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
 	Extension(db): DbExtension,
-	Json(req): Json<QueryCollectionQuery>,
-) -> Result<Json<Vec<SimilarityResult>>, HTTPError> {
-	tracing::trace!("Querying collection {collection_name}");
+) -> Result<RoundedJson<Vec<Embedding>>, HTTPError> {
+	tracing::trace!("Sampling {} embeddings from {collection_name}", req.n);
 
 	let db = db.read().await;
 	let collection = db
 		.get_collection(&collection_name)
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
-	if req.query.len() != collection.dimension {
-		return Err(HTTPError::new("Query dimension mismatch").with_status(StatusCode::BAD_REQUEST));
+	Ok(RoundedJson(collection.sample(req.n, req.seed), rounding, format))
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct IdsQuery {
+	/// Only return ids sorting after this one, the `next` of the previous page
+	after: Option<String>,
+	/// Maximum number of ids to return, all of them when omitted
+	limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct IdsPage {
+	ids: Vec<String>,
+	/// Pass as `after` to get the next page, absent on the last one
+	next: Option<String>,
+}
+
+/// List the ids in a collection, sorted, without their vectors or metadata
+///
+/// Ids sort numerically when every id in the collection is an integer, and lexicographically otherwise.
+/// Pages are keyed on the last id returned rather than a position, so inserts and deletes between requests
+/// don't make pages skip or repeat ids.
+#[allow(clippy::significant_drop_tightening)]
+async fn list_ids(
+	Path(collection_name): Path<String>,
+	Query(req): Query<IdsQuery>,
+	Extension(db): DbExtension,
+) -> Result<Json<IdsPage>, HTTPError> {
+	tracing::trace!("Listing ids in {collection_name}");
+
+	if req.limit == Some(0) {
+		return Err(HTTPError::new("The limit must be at least 1").with_field("limit").with_status(StatusCode::BAD_REQUEST));
 	}
 
-	let instant = Instant::now();
-	let results = collection.get_similarity(&req.query, req.k.unwrap_or(1));
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	let order = collection.id_order();
+	if order == IdOrder::Numeric && req.after.as_ref().is_some_and(|after| after.parse::<i64>().is_err()) {
+		return Err(HTTPError::new("Ids in this collection are integers, so after must be one too")
+			.with_field("after")
+			.with_status(StatusCode::BAD_REQUEST));
+	}
+
+	let mut ids: Vec<&str> = collection.embeddings.iter().map(|embedding| embedding.id.as_str()).collect();
+	ids.sort_unstable_by(|a, b| order.compare(a, b));
 
+	let start = req
+		.after
+		.as_deref()
+		.map_or(0, |after| ids.partition_point(|id| order.compare(id, after).is_le()));
+	let remaining = &ids[start..];
+	let page = &remaining[..req.limit.unwrap_or(remaining.len()).min(remaining.len())];
+	let next = page
+		.last()
+		.filter(|_| page.len() < remaining.len())
+		.map(ToString::to_string);
+
+	Ok(Json(IdsPage {
+		ids: page.iter().map(ToString::to_string).collect(),
+		next,
+	}))
+}
 
-	tracing::trace!("Query to {collection_name} took {:?}", instant.elapsed());
-	Ok(Json(results))
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct ChangesQuery {
+	/// Only return embeddings inserted or updated after this sequence number, 0 for all of them
+	#[serde(default)]
+	since: u64,
+	/// Maximum number of embeddings to return, all of them when omitted
+	limit: Option<usize>,
 }
 
 #[derive(Debug, serde::Serialize, JsonSchema)]
-struct CollectionInfo {
-	/// Name of the collection
-	name: String,
-	/// Dimension of the embeddings in the collection
-	dimension: usize,
-	/// Distance function used for the collection
-	distance: Distance,
-	/// Number of embeddings in the collection
-	embedding_count: usize,
+struct Changes {
+	/// Embeddings changed since `since`, in the order they changed
+	embeddings: Vec<Embedding>,
+	/// Pass as `since` to get the changes after these
+	next_since: u64,
 }
 
-/// Get collection info
+/// List the embeddings inserted or updated since a sequence number
+///
+/// Every insert or update gives the embedding the collection's next `seq`, so syncing again from `next_since`
+/// picks up exactly what changed in between. Deletes leave nothing behind to report, so they aren't listed.
 #[allow(clippy::significant_drop_tightening)]
-async fn get_collection_info(
+async fn list_changes(
 	Path(collection_name): Path<String>,
+	Query(req): Query<ChangesQuery>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
 	Extension(db): DbExtension,
-) -> Result<Json<CollectionInfo>, HTTPError> {
-	tracing::trace!("Getting collection info for {collection_name}");
+) -> Result<RoundedJson<Changes>, HTTPError> {
+	tracing::trace!("Listing changes to {collection_name} since {}", req.since);
+
+	if req.limit == Some(0) {
+		return Err(HTTPError::new("The limit must be at least 1").with_field("limit").with_status(StatusCode::BAD_REQUEST));
+	}
 
 	let db = db.read().await;
 	let collection = db
 		.get_collection(&collection_name)
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
-	Ok(Json(CollectionInfo {
-		name: collection_name,
-		distance: collection.distance,
-		dimension: collection.dimension,
-		embedding_count: collection.embeddings.len(),
-	}))
+	// Embeddings are kept in the order they were last inserted or updated, which is also `seq` order
+	let start = collection.embeddings.partition_point(|embedding| embedding.seq <= req.since);
+	let changed = &collection.embeddings[start..];
+	let embeddings: Vec<Embedding> = changed.iter().take(req.limit.unwrap_or(changed.len())).cloned().collect();
+	let next_since = match embeddings.last() {
+		Some(last) if embeddings.len() < changed.len() => last.seq,
+		_ => collection.last_seq.max(req.since),
+	};
+
+	Ok(RoundedJson(Changes { embeddings, next_since }, rounding, format))
 }
 
-/// Delete a collection
-async fn delete_collection(
+/// Check a collection's embeddings for corruption, such as wrong dimensions, duplicate ids, non-finite components
+/// or cosine vectors that aren't unit length
+#[allow(clippy::significant_drop_tightening)]
+async fn verify_collection(
 	Path(collection_name): Path<String>,
 	Extension(db): DbExtension,
-) -> Result<StatusCode, HTTPError> {
-	tracing::trace!("Deleting collection {collection_name}");
+) -> Result<Json<VerifyReport>, HTTPError> {
+	tracing::trace!("Verifying collection {collection_name}");
 
-	let mut db = db.write().await;
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
-	let delete_result = db.delete_collection(&collection_name);
+	Ok(Json(collection.verify()))
+}
 
-	match delete_result {
-		Ok(_) => Ok(StatusCode::NO_CONTENT),
+/// Read every vector in a collection ahead of traffic, so the first queries don't pay for cold pages
+///
+/// This matters most for memory-mapped vectors, which the OS otherwise only pages in as queries reach them.
+/// The response reports how long the pass took.
+#[allow(clippy::significant_drop_tightening)]
+async fn warmup_collection(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+) -> Result<Json<WarmupReport>, HTTPError> {
+	tracing::trace!("Warming up collection {collection_name}");
+
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	Ok(Json(collection.warmup()))
+}
+
+/// List the metadata keys used across a collection, with how many embeddings have each and the type its values look like
+#[allow(clippy::significant_drop_tightening)]
+async fn list_metadata_keys(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+) -> Result<Json<Vec<MetadataKey>>, HTTPError> {
+	tracing::trace!("Listing metadata keys of {collection_name}");
+
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	Ok(Json(collection.metadata_keys()))
+}
+
+/// Release memory a collection holds beyond what its embeddings need, such as after many deletes
+///
+/// Setting `COMPACT_AFTER_DELETES` also compacts a collection automatically once that many embeddings were deleted from it.
+async fn compact_collection(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+) -> Result<Json<CompactReport>, HTTPError> {
+	tracing::trace!("Compacting collection {collection_name}");
+
+	let mut db = db.write().await;
+
+	match db.compact_collection(&collection_name) {
+		Ok(report) => Ok(Json(report)),
 		Err(DbError::NotFound) => {
 			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
 		},
-		Err(_) => Err(HTTPError::new("Couldn't delete collection")),
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't compact collection").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
 	}
 }
 
-/// Insert a vector into a collection
-async fn insert_into_collection(
+/// Grow a collection's capacity ahead of a bulk load, so inserts don't keep reallocating
+///
+/// Capacity isn't saved, so it's lost on restart, and compacting releases whatever is left unused.
+/// It's capped at `max_embeddings` for collections that set one.
+async fn reserve_collection(
 	Path(collection_name): Path<String>,
 	Extension(db): DbExtension,
-	Json(embedding): Json<Embedding>,
-) -> Result<StatusCode, HTTPError> {
-	tracing::trace!("Inserting into collection {collection_name}");
+	JsonBody(req): JsonBody<ReserveRequest>,
+) -> Result<Json<ReserveResponse>, HTTPError> {
+	tracing::trace!("Reserving room for {} embeddings in collection {collection_name}", req.additional);
 
 	let mut db = db.write().await;
 
-	let insert_result = db.insert_into_collection(&collection_name, embedding);
-
-	match insert_result {
-		Ok(_) => Ok(StatusCode::CREATED),
+	match db.reserve_collection(&collection_name, req.additional) {
+		Ok(capacity) => Ok(Json(ReserveResponse { capacity })),
 		Err(DbError::NotFound) => {
 			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
 		},
-		Err(DbError::UniqueViolation) => {
-			Err(HTTPError::new("Vector already exists").with_status(StatusCode::CONFLICT))
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
 		},
-		Err(DbError::DimensionMismatch) => Err(HTTPError::new(
-			"The provided vector has the wrong dimension",
-		).with_status(StatusCode::BAD_REQUEST)),
-		Err(_)=>Err(HTTPError::new(
-			"Unknown Error",
-		).with_status(StatusCode::BAD_REQUEST)),
+		Err(error @ DbError::CapacityOverflow(_)) => Err(HTTPError::new(&error.to_string())
+			.with_field("/additional")
+			.with_status(StatusCode::BAD_REQUEST)),
+		Err(_) => Err(HTTPError::new("Couldn't reserve capacity").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
+	}
+}
+
+/// Export every embedding in a collection as newline-delimited JSON, one embedding per line
+///
+/// The collection is read in chunks of about `EXPORT_CHUNK_BYTES`, each serialized under a short read lock that's
+/// released before the chunk is sent, so the export never holds a second copy of the collection and a slow download
+/// doesn't hold up writes. If the collection changes in between, the download is aborted rather than finished with
+/// embeddings missing or repeated, so an interrupted backup can't be mistaken for a complete one.
+/// Embeddings come in insertion order, which upserts change, unless `?sort=id` asks for them sorted by id,
+/// numerically when every id is an integer.
+async fn export_collection(
+	Path(collection_name): Path<String>,
+	Query(Sort { sort }): Query<Sort>,
+	Extension(db): DbExtension,
+) -> Result<Response, HTTPError> {
+	tracing::trace!("Exporting collection {collection_name}");
+
+	let (name, generation, ids) = {
+		let db = db.read().await;
+		let collection = db
+			.get_collection(&collection_name)
+			.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+		// Only the ids are copied to sort, with the position of their embedding
+		let ids = (sort == Some(SortOrder::Id)).then(|| {
+			let ids: Vec<(String, usize)> = collection
+				.embeddings
+				.iter()
+				.enumerate()
+				.map(|(position, embedding)| (embedding.id.clone(), position))
+				.collect();
+			(ids, collection.id_order())
+		});
+		(db.resolve(&collection_name).to_string(), collection.generation, ids)
+	};
+	let order = ids.map(|(mut ids, id_order)| {
+		ids.sort_unstable_by(|(a, _), (b, _)| id_order.compare(a, b));
+		ids.into_iter().map(|(_, position)| position).collect::<Vec<usize>>()
+	});
+
+	let (mut sender, body) = Body::channel();
+	tokio::spawn(async move {
+		let mut next = 0;
+		loop {
+			let chunk = {
+				let db = db.read().await;
+				let Some(collection) = db
+					.get_collection(&name)
+					.filter(|collection| collection.generation == generation)
+				else {
+					tracing::debug!("Export of {collection_name} aborted, as the collection changed");
+					sender.abort();
+					return;
+				};
+
+				let positions = (next..collection.embeddings.len())
+					.map(|position| order.as_ref().map_or(position, |order| order[position]));
+				ndjson_chunk(positions.map(|position| &collection.embeddings[position]))
+			};
+
+			let (chunk, count) = match chunk {
+				Ok((_, 0)) => return,
+				Ok(chunk) => chunk,
+				Err(err) => {
+					tracing::error!("Couldn't serialize an embedding of {collection_name}: {err}");
+					sender.abort();
+					return;
+				},
+			};
+			next += count;
+			if sender.send_data(Bytes::from(chunk)).await.is_err() {
+				tracing::debug!("Export of {collection_name} aborted by the client");
+				return;
+			}
+		}
+	});
+
+	Ok(([(header::CONTENT_TYPE, "application/x-ndjson")], body).into_response())
+}
+
+/// Serialize `items` as newline-delimited JSON until about `EXPORT_CHUNK_BYTES` are written, returning the chunk
+/// and how many items it holds
+fn ndjson_chunk<T: serde::Serialize>(items: impl IntoIterator<Item = T>) -> serde_json::Result<(Vec<u8>, usize)> {
+	let mut chunk = Vec::with_capacity(EXPORT_CHUNK_BYTES);
+	let mut count = 0;
+	for item in items {
+		serde_json::to_writer(&mut chunk, &item)?;
+		chunk.push(b'\n');
+		count += 1;
+
+		if chunk.len() >= EXPORT_CHUNK_BYTES {
+			break;
+		}
 	}
+
+	Ok((chunk, count))
+}
+
+/// Export a collection as a Parquet file, with `id`, a `vector` list column and a `metadata.<key>` column per metadata key
+///
+/// The file is encoded and streamed a row group at a time, each read under a short lock on the database, as with the
+/// JSON export. If the collection changes in between, the download is aborted.
+async fn export_collection_parquet(
+	Path(collection_name): Path<String>,
+	Extension(db): DbExtension,
+) -> Result<Response, HTTPError> {
+	tracing::trace!("Exporting collection {collection_name} as Parquet");
+
+	let (name, generation, schema) = {
+		let db = db.read().await;
+		let collection = db
+			.get_collection(&collection_name)
+			.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+		(db.resolve(&collection_name).to_string(), collection.generation, export::ParquetSchema::new(collection))
+	};
+
+	// A couple of chunks in flight are enough to keep the client busy while the next one is encoded
+	let (sender, mut receiver) = tokio::sync::mpsc::channel(2);
+	tokio::task::spawn_blocking(move || {
+		let out = export::ChannelWriter::new(sender.clone());
+		if let Err(err) = export::write_parquet(&db, &name, generation, &schema, out) {
+			tracing::warn!("Parquet export of {collection_name} stopped: {err:#}");
+			let _ = sender.blocking_send(Err(std::io::Error::new(std::io::ErrorKind::Other, err.to_string())));
+		}
+	});
+
+	let body = Body::wrap_stream(futures_util::stream::poll_fn(move |cx| receiver.poll_recv(cx)));
+	Ok(([(header::CONTENT_TYPE, "application/vnd.apache.parquet")], body).into_response())
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct StoredEmbedding {
+	#[serde(flatten)]
+	embedding: Embedding,
+	/// Whether the vector was normalized on insertion, and so differs from the one that was sent
+	normalized: bool,
 }
 
+/// Get an embedding by id
+///
+/// Vectors are returned as stored: collections using cosine distance or a normalization rescale vectors on insertion,
+/// which `normalized` reports.
 async fn query_id_collection(
 	Path((collection_name, id)): Path<(String, String)>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
 	Extension(db): DbExtension,
-) -> Result<Json<Embedding>, HTTPError> {
+) -> Result<RoundedJson<StoredEmbedding>, HTTPError> {
 	tracing::trace!("Getting query info for {id} in {collection_name}");
 
 	let db = db.read().await;
@@ -185,31 +1934,74 @@ async fn query_id_collection(
 
 	tracing::trace!("Query ID {id} for {collection_name} took {:?}", instant.elapsed());
 	match result {
-		Some(embed) => Ok(Json(embed)),
-		None => Err(HTTPError::new("No item found of ID").with_status(StatusCode::BAD_REQUEST))
+		Some(embedding) => {
+			let normalized = collection.normalizes_vectors() && !embedding.vector.is_empty();
+			Ok(RoundedJson(StoredEmbedding { embedding, normalized }, rounding, format))
+		},
+		None => Err(HTTPError::new("ID not found within specified collection").with_status(StatusCode::NOT_FOUND))
+	}
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+struct TouchResponse {
+	/// Unix timestamp (in seconds) the embedding was touched at
+	touched_at: u64,
+}
+
+/// Mark an embedding as just used, for LRU eviction with `max_embeddings`, without sending it again
+///
+/// Only `touched_at` changes: the embedding keeps its data and its `seq`, so it isn't reported by `/changes`.
+async fn touch_id_collection(
+	Path((collection_name, id)): Path<(String, String)>,
+	Extension(db): DbExtension,
+) -> Result<Json<TouchResponse>, HTTPError> {
+	tracing::trace!("Touching id {id} in {collection_name}");
+
+	let mut db = db.write().await;
+
+	match db.touch_embedding(&collection_name, &id) {
+		Ok(touched_at) => Ok(Json(TouchResponse { touched_at })),
+		Err(DbError::NotFound) => {
+			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::IDNotFound) => {
+			Err(HTTPError::new("ID not found within specified collection").with_status(StatusCode::NOT_FOUND))
+		},
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't touch embedding").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
 	}
 }
 
+/// Delete an embedding from a collection
+///
+/// With `?dry_run=true`, the embedding is kept and reported as the one that would be deleted.
 async fn delete_id_collection(
 	Path((collection_name, id)): Path<(String, String)>,
+	Query(DryRun { dry_run }): Query<DryRun>,
 	Extension(db): DbExtension,
-) -> Result<StatusCode, HTTPError> {
-	tracing::trace!("Deleting id {id} from {collection_name}");
+) -> Result<Response, HTTPError> {
+	tracing::trace!("Deleting id {id} from {collection_name} (dry run: {dry_run})");
 
 	let mut db = db.write().await;
 
 	//let delete_result = db.delete_collection(&collection_name);
-	let delete_result: Result<Embedding, DbError> = db.collection_delete_id(&collection_name, &id);
+	let delete_result: Result<Embedding, DbError> = db.collection_delete_id(&collection_name, &id, dry_run);
 
 	match delete_result {
-		Ok(_) => Ok(StatusCode::NO_CONTENT),
+		Ok(embedding) if dry_run => Ok(Json(AffectedEmbeddings::from_embeddings([&embedding])).into_response()),
+		Ok(_) => Ok(StatusCode::NO_CONTENT.into_response()),
 		Err(DbError::NotFound) => {
 			Err(HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))
 		},
 		Err(DbError::IDNotFound) => {
 			Err(HTTPError::new("ID not found within specified collection").with_status(StatusCode::NOT_FOUND))
 		},
-		Err(_) => Err(HTTPError::new("Couldn't delete ID")),
+		Err(DbError::ReadOnly) => {
+			Err(HTTPError::new("Database is read-only").with_status(StatusCode::FORBIDDEN))
+		},
+		Err(_) => Err(HTTPError::new("Couldn't delete ID").with_status(StatusCode::INTERNAL_SERVER_ERROR)),
 	}
 }
 
@@ -217,14 +2009,20 @@ async fn delete_id_collection(
 struct QueryMetadataString{
 	key: String,
 	value: String,
+	/// How the metadata value is compared against `value`, defaults to an exact match
+	#[serde(default, rename = "match")]
+	match_type: StringMatch,
 	k: Option<usize>,
 }
 
 async fn query_metadata_string_collection(
 	Path(collection_name): Path<String>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Query(on_empty): Query<OnEmpty>,
 	Extension(db): DbExtension,
-	Json(req): Json<QueryMetadataString>,
-) -> Result<Json<Vec<Embedding>>, HTTPError> {
+	JsonBody(req): JsonBody<QueryMetadataString>,
+) -> Result<Response, HTTPError> {
 	tracing::trace!("Metadata query for {collection_name}");
 
 	let db = db.read().await;
@@ -233,26 +2031,70 @@ async fn query_metadata_string_collection(
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
 	let instant = Instant::now();
-	let result = collection.get_metadata_string(&req.key, &req.value, req.k.unwrap_or(5));
+	let k = req.k.unwrap_or(5);
+	let (result, available) = collection.get_metadata_string(&req.key, &req.value, req.match_type, k);
 
 	tracing::trace!("Metadata Query for {collection_name} took {:?}", instant.elapsed());
-	Ok(Json(result))
+	Ok(on_empty.respond(
+		result.is_empty(),
+		(availability_headers(available, k), RoundedJson(result, rounding, format)),
+	))
 }
 
 
 #[derive(Debug, serde::Deserialize, JsonSchema)]
 struct QueryMetadataNumber{
+	key: Option<String>,
+	/// Number or boolean to compare against
+	value: Option<MetadataNumber>,
+	equality: Option<String>,
+	/// Further comparisons, each of which embeddings must satisfy along with `key`, `value` and `equality` if given
+	#[serde(default)]
+	conditions: Vec<NumberConditionQuery>,
+	k: Option<usize>,
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct NumberConditionQuery {
 	key: String,
-	value: f32,
+	/// Number or boolean to compare against
+	value: MetadataNumber,
 	equality: String,
-	k: Option<usize>,
 }
 
+impl NumberConditionQuery {
+	/// Resolve the comparison, reporting errors at `field`
+	fn parse(self, field: &str) -> Result<NumberCondition, HTTPError> {
+		let Some(equality) = MetadataEqualities::from_str(&self.equality) else {
+			return Err(HTTPError::new("Invalid equality string. Acceptable inputs; greater_than, greater_equal_than, lesser_than, lesser_equal_than, equal")
+				.with_field(format!("{field}/equality"))
+				.with_status(StatusCode::BAD_REQUEST));
+		};
+		if matches!(self.value, MetadataNumber::Bool(_)) && !matches!(equality, MetadataEqualities::Equal) {
+			return Err(HTTPError::new("Boolean values only support the equal comparison")
+				.with_field(format!("{field}/value"))
+				.with_status(StatusCode::BAD_REQUEST));
+		}
+
+		Ok(NumberCondition {
+			key: self.key,
+			value: self.value,
+			equality,
+		})
+	}
+}
+
+/// Query a collection for embeddings whose numeric metadata satisfies a comparison
+///
+/// `key`, `value` and `equality` give a single comparison, and `conditions` any number more, all of which have to hold.
 async fn query_metadata_number_collection(
 	Path(collection_name): Path<String>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Query(on_empty): Query<OnEmpty>,
 	Extension(db): DbExtension,
-	Json(req): Json<QueryMetadataNumber>,
-) -> Result<Json<Vec<Embedding>>, HTTPError> {
+	JsonBody(req): JsonBody<QueryMetadataNumber>,
+) -> Result<Response, HTTPError> {
 	tracing::trace!("Metadata query for {collection_name}");
 
 	let db = db.read().await;
@@ -261,12 +2103,100 @@ async fn query_metadata_number_collection(
 		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
 
 	let instant = Instant::now();
-	let eq = match MetadataEqualities::from_str(&req.equality.as_str()){
-		Some(eq) => eq,
-		None => return Err(HTTPError::new("Invalid equality string. Acceptable inputs; greater_than, greater_equal_than, lesser_than, lesser_equal_than, equal").with_status(StatusCode::BAD_REQUEST))
+	let mut conditions = match (req.key, req.value, req.equality) {
+		(Some(key), Some(value), Some(equality)) => vec![NumberConditionQuery { key, value, equality }.parse("")?],
+		(None, None, None) => Vec::new(),
+		_ => {
+			return Err(HTTPError::new("key, value and equality have to be given together")
+				.with_status(StatusCode::BAD_REQUEST))
+		},
 	};
-	let result = collection.get_metadata_number(&req.key, req.value, eq, req.k.unwrap_or(5));
+	for (index, condition) in req.conditions.into_iter().enumerate() {
+		conditions.push(condition.parse(&format!("/conditions/{index}"))?);
+	}
+	if conditions.is_empty() {
+		return Err(HTTPError::new("At least one comparison is needed")
+			.with_field("/conditions")
+			.with_status(StatusCode::BAD_REQUEST));
+	}
+
+	let k = req.k.unwrap_or(5);
+	let (result, available) = collection.get_metadata_number(&conditions, k);
 
 	tracing::trace!("Metadata Query for {collection_name} took {:?}", instant.elapsed());
-	Ok(Json(result))
+	Ok(on_empty.respond(
+		result.is_empty(),
+		(availability_headers(available, k), RoundedJson(result, rounding, format)),
+	))
+}
+
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct FindDuplicatesQuery {
+	/// Score an embedding must reach to count as a duplicate, interpreted according to the collection's distance metric
+	threshold: f32,
+	/// Vector to find duplicates of. When omitted, every pair of embeddings in the collection is compared.
+	vector: Option<Vec<f32>>,
+}
+
+#[derive(Debug, Default, serde::Serialize, JsonSchema)]
+struct FindDuplicatesResponse {
+	/// Embeddings within the threshold of the supplied vector
+	matches: Vec<SimilarityResult>,
+	/// Pairs of embeddings within the threshold of each other, when no vector was supplied
+	pairs: Vec<DuplicatePair>,
+}
+
+/// Find embeddings that are near-duplicates of a vector, or of each other
+#[allow(clippy::significant_drop_tightening)]
+async fn find_duplicates(
+	Path(collection_name): Path<String>,
+	Query(rounding): Query<Rounding>,
+	format: Format,
+	Extension(db): DbExtension,
+	JsonBody(req): JsonBody<FindDuplicatesQuery>,
+) -> Result<RoundedJson<FindDuplicatesResponse>, HTTPError> {
+	tracing::trace!("Finding duplicates in {collection_name}");
+
+	let db = db.read().await;
+	let collection = db
+		.get_collection(&collection_name)
+		.ok_or_else(|| HTTPError::new("Collection not found").with_status(StatusCode::NOT_FOUND))?;
+
+	let instant = Instant::now();
+	let response = match req.vector {
+		Some(vector) => {
+			if vector.len() != collection.dimension {
+				return Err(HTTPError::new("Query dimension mismatch").with_status(StatusCode::BAD_REQUEST));
+			}
+
+			let vector = collection.normalize_query(&vector);
+
+			FindDuplicatesResponse {
+				matches: collection.get_within_threshold(&vector, req.threshold),
+				..FindDuplicatesResponse::default()
+			}
+		},
+		None => {
+			if collection.embeddings.len() > MAX_PAIRWISE_EMBEDDINGS {
+				return Err(HTTPError::new(
+					"Collection is too large for pairwise duplicate detection, supply a vector instead",
+				)
+				.with_status(StatusCode::BAD_REQUEST));
+			}
+
+			tracing::warn!(
+				"Running pairwise duplicate detection over {} embeddings in {collection_name}",
+				collection.embeddings.len()
+			);
+
+			FindDuplicatesResponse {
+				pairs: collection.get_duplicate_pairs(req.threshold),
+				..FindDuplicatesResponse::default()
+			}
+		},
+	};
+
+	tracing::trace!("Duplicate detection for {collection_name} took {:?}", instant.elapsed());
+	Ok(RoundedJson(response, rounding, format))
 }
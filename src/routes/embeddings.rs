@@ -1,35 +1,55 @@
-
-
-use aide::axum::{routing::post, ApiRouter};
-use axum::Extension;
-use axum_jsonschema::Json;
-use schemars::JsonSchema;
-
-use crate::{
-    rustllm::LLMExtension,
-	errors::HTTPError,
-};
-
-pub fn handler() -> ApiRouter {
-	ApiRouter::new()
-		.route("/embeddings", post(query_embeddings))
-}
-
-#[derive(Debug, serde::Deserialize, JsonSchema)]
-struct EmbeddingsQuery {
-    pub query: String,
-}
-
-/// Query a collection
-#[allow(clippy::significant_drop_tightening)]
-async fn query_embeddings(
-	Extension(emb): LLMExtension,
-	Json(req): Json<EmbeddingsQuery>,
-) -> Result<Json<Vec<f32>>, HTTPError> {
-    let query = req.query;
-	tracing::trace!("Getting embeddings for {query}");
-    let emb = emb.write().await;
-    let embeddings: Vec<f32> = emb.get_embeddings(query.as_str());
-	Ok(Json(embeddings))
-}
-
+
+
+use aide::axum::{routing::post, ApiRouter};
+use axum::{http::StatusCode, Extension};
+use axum_jsonschema::Json;
+use schemars::JsonSchema;
+
+use crate::{
+    rustllm::LLMExtension,
+	errors::HTTPError,
+	extract::JsonBody,
+};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new()
+		.route("/embeddings", post(query_embeddings))
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct EmbeddingsQuery {
+    pub query: String,
+    /// Model to use, defaults to the primary model
+    pub model: Option<String>,
+}
+
+/// Query a collection
+async fn query_embeddings(
+	Extension(models): LLMExtension,
+	JsonBody(req): JsonBody<EmbeddingsQuery>,
+) -> Result<Json<Vec<f32>>, HTTPError> {
+    let query = req.query;
+	tracing::trace!("Getting embeddings for {query}");
+    if models.get(req.model.as_deref()).is_none() {
+        return Err(HTTPError::new("Unknown model").with_status(StatusCode::BAD_REQUEST));
+    }
+
+    let permit = models
+        .try_acquire()
+        .ok_or_else(|| HTTPError::new("Too many concurrent inference requests, retry later").with_status(StatusCode::TOO_MANY_REQUESTS))?;
+
+    // Embedding only reads the model, so requests for the same model run side by side, up to the permits
+    let embeddings = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let model = models
+            .get(req.model.as_deref())
+            .expect("model was checked above")
+            .blocking_read();
+        model.get_embeddings(query.as_str())
+    })
+    .await
+    .map_err(|_| HTTPError::new("Inference Error").with_status(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+	Ok(Json(embeddings))
+}
+
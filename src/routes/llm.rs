@@ -1,38 +1,72 @@
-use aide::axum::{routing::post, ApiRouter};
-use axum::{http::StatusCode, Extension};
-use axum_jsonschema::Json;
-use schemars::JsonSchema;
-
-use crate::{
-    rustllm::LLMExtension,
-	errors::HTTPError,
-};
-
-pub fn handler() -> ApiRouter {
-	ApiRouter::new()
-		.route("/llm", post(query_prompt))
-}
-
-#[derive(Debug, serde::Deserialize, JsonSchema)]
-struct PromptQuery {
-    pub query: String,
-}
-
-/// Query a collection
-#[allow(clippy::significant_drop_tightening)]
-async fn query_prompt(
-	Extension(model): LLMExtension,
-	Json(req): Json<PromptQuery>,
-) -> Result<Json<String>, HTTPError> {
-    let query = req.query;
-    let now = std::time::Instant::now();
-	tracing::trace!("Getting embeddings for {query}");
-    let model = model.write().await;
-    let inf_result = model.inference(query.as_str());
-    tracing::info!("\nInference Time: {}ms", now.elapsed().as_millis());
-    match inf_result {
-        Ok(result) => Ok(Json(result)),
-        Err(_) => return Err(HTTPError::new("Inference Error").with_status(StatusCode::BAD_REQUEST))
-    }
-}
-
+use aide::axum::{routing::post, ApiRouter};
+use axum::{http::StatusCode, Extension};
+use axum_jsonschema::Json;
+use schemars::JsonSchema;
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Arc,
+};
+
+use crate::{
+    rustllm::LLMExtension,
+	errors::HTTPError,
+	extract::JsonBody,
+};
+
+pub fn handler() -> ApiRouter {
+	ApiRouter::new()
+		.route("/llm", post(query_prompt))
+}
+
+#[derive(Debug, serde::Deserialize, JsonSchema)]
+struct PromptQuery {
+    pub query: String,
+    /// Model to use, defaults to the primary model
+    pub model: Option<String>,
+}
+
+/// Sets its flag when dropped. Axum drops the handler, and with it this guard, when the client disconnects mid-request.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+	fn drop(&mut self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+}
+
+/// Query a collection
+async fn query_prompt(
+	Extension(models): LLMExtension,
+	JsonBody(req): JsonBody<PromptQuery>,
+) -> Result<Json<String>, HTTPError> {
+    let query = req.query;
+    let now = std::time::Instant::now();
+	tracing::trace!("Getting embeddings for {query}");
+    if models.get(req.model.as_deref()).is_none() {
+        return Err(HTTPError::new("Unknown model").with_status(StatusCode::BAD_REQUEST));
+    }
+
+    let permit = models
+        .try_acquire()
+        .ok_or_else(|| HTTPError::new("Too many concurrent inference requests, retry later").with_status(StatusCode::TOO_MANY_REQUESTS))?;
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _guard = CancelOnDrop(Arc::clone(&cancelled));
+
+    // Generation runs off the async runtime so it keeps going (and can notice the flag) after this future is dropped
+    let inf_result = tokio::task::spawn_blocking(move || {
+        // Held until generation actually stops, even if the client is long gone
+        let _permit = permit;
+        let model = models
+            .get(req.model.as_deref())
+            .expect("model was checked above")
+            .blocking_write();
+        model.inference(query.as_str(), &cancelled)
+    })
+    .await;
+    tracing::info!("\nInference Time: {}ms", now.elapsed().as_millis());
+    match inf_result {
+        Ok(Ok(result)) => Ok(Json(result)),
+        _ => return Err(HTTPError::new("Inference Error").with_status(StatusCode::INTERNAL_SERVER_ERROR))
+    }
+}
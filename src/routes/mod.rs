@@ -1,5 +1,16 @@
 use aide::axum::ApiRouter;
+use axum::{
+	extract::DefaultBodyLimit,
+	http::{header, Request, StatusCode},
+	middleware::{self, Next},
+	response::{IntoResponse, Response},
+};
+use std::time::Duration;
+use tower_http::timeout::TimeoutLayer;
 
+use crate::{db, errors::HTTPError};
+
+mod alias;
 mod collection;
 mod docs;
 mod system;
@@ -7,21 +18,66 @@ mod system;
 mod embeddings;
 #[cfg(feature = "llm")]
 mod llm;
+#[cfg(test)]
+mod tests;
+
+/// Largest request bodies accepted, in bytes, past which requests get a 413.
+///
+/// Limits count the body as it's read, after any gzip or brotli decompression, so chunked bodies without a
+/// `Content-Length` are held to them too, and a compressed body is limited by its decompressed size.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+	/// For every route without a limit of its own
+	pub default: usize,
+	/// For similarity queries, whose bodies grow with the dimension of the collection queried
+	pub query: usize,
+}
 
 #[cfg(feature = "llm")]
-pub fn handler() -> ApiRouter {
+pub fn handler(request_timeout: Option<Duration>, body_limits: BodyLimits) -> ApiRouter {
 	ApiRouter::new()
 		.merge(docs::handler())
 		.merge(system::handler())
-		.merge(collection::handler())
-		.merge(embeddings::handler())
-		.merge(llm::handler())
+		.merge(data_routes(collection::handler(body_limits.query), request_timeout))
+		.merge(data_routes(alias::handler(), request_timeout))
+		.merge(data_routes(embeddings::handler(), request_timeout))
+		.merge(data_routes(llm::handler(), request_timeout))
+		.layer(DefaultBodyLimit::max(body_limits.default))
 }
 
 #[cfg(not(feature = "llm"))]
-pub fn handler() -> ApiRouter {
+pub fn handler(request_timeout: Option<Duration>, body_limits: BodyLimits) -> ApiRouter {
 	ApiRouter::new()
 		.merge(docs::handler())
 		.merge(system::handler())
-		.merge(collection::handler())
-}
\ No newline at end of file
+		.merge(data_routes(collection::handler(body_limits.query), request_timeout))
+		.merge(data_routes(alias::handler(), request_timeout))
+		.layer(DefaultBodyLimit::max(body_limits.default))
+}
+
+/// Routes that read or write the database, which answer with a 503 while the store is loading and are
+/// cut off after `timeout`
+fn data_routes(router: ApiRouter, timeout: Option<Duration>) -> ApiRouter {
+	with_timeout(router, timeout).layer(middleware::from_fn(reject_while_loading))
+}
+
+/// Answer with `408 Request Timeout` once a handler runs past `timeout`.
+/// Async handlers are cancelled, but CPU-bound work like a similarity scan only stops at its next await point.
+fn with_timeout(router: ApiRouter, timeout: Option<Duration>) -> ApiRouter {
+	match timeout {
+		Some(timeout) => router.layer(TimeoutLayer::new(timeout)),
+		None => router,
+	}
+}
+/// Turn requests away until the store has loaded, rather than answering them from the empty placeholder
+async fn reject_while_loading<B>(req: Request<B>, next: Next<B>) -> Response {
+	if !db::is_loading() {
+		return next.run(req).await;
+	}
+
+	(
+		[(header::RETRY_AFTER, "5")],
+		HTTPError::new("The database is still loading").with_status(StatusCode::SERVICE_UNAVAILABLE),
+	)
+		.into_response()
+}
@@ -2,16 +2,48 @@ use aide::axum::{
 	routing::{get, post},
 	ApiRouter,
 };
-use axum::Extension;
+use axum::{
+	http::{header, HeaderMap, StatusCode},
+	Extension,
+};
 use axum_jsonschema::Json;
+use half::f16;
+use lazy_static::lazy_static;
 use schemars::JsonSchema;
+use std::{env, fs};
 
-use crate::shutdown::Agent as Shutdown;
+use crate::{
+	db::{self, DbExtension, PersistenceStats, STORE_PATH},
+	errors::HTTPError,
+	shutdown::Agent as Shutdown,
+	similarity::Vector,
+};
 
 pub fn handler() -> ApiRouter {
 	ApiRouter::new()
 		.api_route("/", get(root))
-		.api_route("/shutdown", post(shutdown))
+		.api_route("/version", get(version))
+		// The original shutdown route, kept for existing clients behind the same token check
+		.api_route("/shutdown", post(drain))
+		.api_route("/system/shutdown", post(drain))
+		.api_route("/system/stats", get(stats))
+		.api_route("/health", get(health))
+}
+
+lazy_static! {
+	/// Bearer token `POST /system/shutdown` and `POST /shutdown` require, from `SHUTDOWN_TOKEN`.
+	/// Both are disabled without one.
+	static ref SHUTDOWN_TOKEN: Option<String> = env::var("SHUTDOWN_TOKEN").ok().filter(|token| !token.is_empty());
+	/// Whether `/health` answers with a 503 when the latest save failed, from `HEALTH_FAIL_ON_SAVE_ERROR`.
+	/// Off by default, so failing saves only show up in the counters it reports.
+	static ref FAIL_ON_SAVE_ERROR: bool = match env::var("HEALTH_FAIL_ON_SAVE_ERROR").as_deref().map(str::parse) {
+		Ok(Ok(fail)) => fail,
+		Err(_) => false,
+		Ok(Err(_)) => {
+			tracing::warn!("HEALTH_FAIL_ON_SAVE_ERROR should be true or false, not failing health checks on save errors");
+			false
+		},
+	};
 }
 
 #[derive(Debug, serde::Serialize, JsonSchema)]
@@ -44,9 +76,133 @@ pub async fn root() -> Json<RootResponse> {
 	})
 }
 
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct VersionResponse {
+	/// Crate version
+	version: String,
+	/// Short hash of the commit the server was built from, if it was built from a git checkout
+	git_sha: Option<String>,
+	/// When the server was built
+	build_time: String,
+	/// Optional cargo features compiled in
+	features: Vec<String>,
+}
+
+/// Get the version and build of the running server
 #[allow(clippy::unused_async)]
-pub async fn shutdown(Extension(shutdown): Extension<Shutdown>) -> Json<String> {
+pub async fn version() -> Json<VersionResponse> {
+	let features = [("llm", cfg!(feature = "llm")), ("grpc", cfg!(feature = "grpc"))]
+		.into_iter()
+		.filter(|&(_, enabled)| enabled)
+		.map(|(feature, _)| feature.to_string())
+		.collect();
+
+	Json(VersionResponse {
+		version: env!("CARGO_PKG_VERSION").to_string(),
+		git_sha: option_env!("GIT_REV").map(ToString::to_string),
+		build_time: env!("STATIC_BUILD_DATE").to_string(),
+		features,
+	})
+}
+
+/// Flush the database, then shut down gracefully, for orchestrators that restart instances over HTTP
+///
+/// Needs `Authorization: Bearer <SHUTDOWN_TOKEN>`, and answers with a 403 when `SHUTDOWN_TOKEN` isn't set.
+/// New requests get a 503 from then on, while in-flight ones finish within `SHUTDOWN_TIMEOUT`.
+/// The database is flushed again once they have, so writes that finish while draining aren't lost.
+pub async fn drain(
+	headers: HeaderMap,
+	Extension(shutdown): Extension<Shutdown>,
+	Extension(db): DbExtension,
+) -> Result<(StatusCode, Json<String>), HTTPError> {
+	let Some(expected) = SHUTDOWN_TOKEN.as_deref() else {
+		return Err(HTTPError::new("Shutting down over HTTP is disabled, as SHUTDOWN_TOKEN isn't set")
+			.with_status(StatusCode::FORBIDDEN));
+	};
+	let token = headers
+		.get(header::AUTHORIZATION)
+		.and_then(|value| value.to_str().ok())
+		.and_then(|value| value.strip_prefix("Bearer "));
+	if !token.is_some_and(|token| constant_time_eq(token.as_bytes(), expected.as_bytes())) {
+		return Err(HTTPError::new("Invalid or missing shutdown token").with_status(StatusCode::UNAUTHORIZED));
+	}
+
+	tracing::info!("Shutdown requested over HTTP, flushing database to store");
+	db.read().await.save();
 	shutdown.start();
 
-	Json("Shutting down...".to_string())
+	Ok((StatusCode::ACCEPTED, Json("Draining and shutting down...".to_string())))
+}
+
+/// Compare secrets without leaking how much of them matched through the time it takes
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct StatsResponse {
+	/// Number of collections in the database
+	pub collection_count: usize,
+	/// Number of embeddings across all collections
+	pub embedding_count: usize,
+	/// Size of the store on disk, in bytes, if it has been written yet
+	pub store_size_bytes: Option<u64>,
+	/// Estimated memory used by vector data, in bytes
+	pub vector_bytes: usize,
+	/// Vector data served from the memory-mapped vector file, in bytes
+	pub mapped_vector_bytes: usize,
+	/// Saves and loads of the store since the server started
+	pub persistence: PersistenceStats,
+}
+
+#[allow(clippy::significant_drop_tightening)]
+pub async fn stats(Extension(db): DbExtension) -> Json<StatsResponse> {
+	let db = db.read().await;
+
+	let embedding_count = db
+		.collections
+		.values()
+		.map(|collection| collection.embeddings.len())
+		.sum();
+	let (vector_bytes, mapped_vector_bytes) = db
+		.collections
+		.values()
+		.flat_map(|collection| &collection.embeddings)
+		.fold((0, 0), |(heap, mapped), embedding| match &embedding.vector {
+			Vector::F32(vector) => (heap + vector.len() * std::mem::size_of::<f32>(), mapped),
+			Vector::F16(vector) => (heap + vector.len() * std::mem::size_of::<f16>(), mapped),
+			Vector::Mapped(vector) => (heap, mapped + vector.len * std::mem::size_of::<f32>()),
+		});
+
+	Json(StatsResponse {
+		embedding_count,
+		vector_bytes,
+		mapped_vector_bytes,
+		collection_count: db.collections.len(),
+		persistence: db::persistence_stats(),
+		store_size_bytes: fs::metadata(STORE_PATH.as_path())
+			.ok()
+			.map(|metadata| metadata.len()),
+	})
+}
+
+#[derive(Debug, serde::Serialize, JsonSchema)]
+pub struct HealthResponse {
+	/// Whether the store is still being loaded, during which data routes answer with a 503
+	pub db_loading: bool,
+	#[serde(flatten)]
+	pub persistence: PersistenceStats,
+}
+
+/// Answer with a 503 while the store is loading, so the instance is kept out of rotation until it's loaded, and with
+/// `HEALTH_FAIL_ON_SAVE_ERROR=true` also when the latest save failed. Either way the response carries the same
+/// persistence stats as `/system/stats`, so failing saves can be alerted on.
+#[allow(clippy::unused_async)]
+pub async fn health() -> (StatusCode, Json<HealthResponse>) {
+	let persistence = db::persistence_stats();
+	let db_loading = db::is_loading();
+	let save_failed = *FAIL_ON_SAVE_ERROR && !persistence.healthy();
+	let status = if db_loading || save_failed { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::OK };
+
+	(status, Json(HealthResponse { db_loading, persistence }))
 }
@@ -0,0 +1,279 @@
+use axum::{
+	body::{Body, HttpBody},
+	http::{Method, Request, StatusCode},
+	Router,
+};
+use serde_json::{json, Value};
+use std::time::Duration;
+use tower::ServiceExt;
+
+use crate::{
+	cache::QueryCache,
+	db::{Collection, Db, Embedding},
+	server::{test_router, test_router_with_cache},
+	similarity::Distance,
+};
+
+/// Send a JSON request to `router`, returning the status and the decoded body, or `null` if there isn't one
+pub async fn send(router: &Router, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+	let body = body.map(|body| body.to_string());
+	let (status, bytes) = send_raw(router, method, uri, "application/json", body).await;
+
+	(status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+}
+
+/// Send a request with a body of any type to `router`, returning the status and the body as it was sent back
+pub async fn send_raw(
+	router: &Router,
+	method: Method,
+	uri: &str,
+	content_type: &str,
+	body: Option<String>,
+) -> (StatusCode, Vec<u8>) {
+	let request = Request::builder()
+		.method(method)
+		.uri(uri)
+		.header("content-type", content_type)
+		.body(body.map_or_else(Body::empty, Body::from))
+		.unwrap();
+	let response = router.clone().oneshot(request).await.unwrap();
+	let status = response.status();
+
+	let mut body = response.into_body();
+	let mut bytes = Vec::new();
+	while let Some(chunk) = body.data().await {
+		bytes.extend_from_slice(&chunk.unwrap());
+	}
+
+	(status, bytes)
+}
+
+#[tokio::test]
+async fn queries_what_was_inserted() {
+	let router = test_router(Db::in_memory());
+
+	let (status, _) = send(
+		&router,
+		Method::PUT,
+		"/collections/cases",
+		Some(json!({ "dimension": 2, "distance": "cosine" })),
+	)
+	.await;
+	assert_eq!(status, StatusCode::CREATED);
+
+	for (id, vector) in [("north", [0.0, 1.0]), ("east", [1.0, 0.0])] {
+		let (status, _) = send(
+			&router,
+			Method::POST,
+			"/collections/cases/insert",
+			Some(json!({ "id": id, "vector": vector })),
+		)
+		.await;
+		assert_eq!(status, StatusCode::CREATED);
+	}
+
+	let (status, results) = send(
+		&router,
+		Method::POST,
+		"/collections/cases",
+		Some(json!({ "query": [0.1, 1.0], "k": 2 })),
+	)
+	.await;
+	assert_eq!(status, StatusCode::OK);
+	assert_eq!(results[0]["embedding"]["id"], "north");
+	assert_eq!(results[1]["embedding"]["id"], "east");
+}
+
+#[tokio::test]
+async fn missing_collections_are_not_found() {
+	let router = test_router(Db::in_memory());
+
+	let (status, _) = send(&router, Method::GET, "/collections/missing", None).await;
+	assert_eq!(status, StatusCode::NOT_FOUND);
+
+	let (status, _) = send(&router, Method::POST, "/collections/missing", Some(json!({ "query": [1.0] }))).await;
+	assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn swapping_an_alias_isnt_answered_from_the_cache() {
+	let mut db = Db::in_memory();
+	for (name, id) in [("cases_blue", "blue"), ("cases_green", "green")] {
+		let mut collection = Collection::new(2, Distance::DotProduct);
+		collection.embeddings.push(Embedding {
+			id: id.to_string(),
+			vector: vec![1.0, 0.0].into(),
+			metadata: None,
+			seq: 1,
+			touched_at: 0,
+		});
+		db.create_collection(name.to_string(), collection, 0).unwrap();
+	}
+	// As collections loaded from a store used to be, before generations were refreshed on load
+	for collection in db.collections.values_mut() {
+		collection.generation = 0;
+	}
+	let router = test_router_with_cache(db, QueryCache::new(Some(Duration::from_secs(60))));
+	let query_alias = || send(&router, Method::POST, "/collections/cases", Some(json!({ "query": [1.0, 0.0] })));
+
+	let (status, _) = send(&router, Method::PUT, "/aliases/cases", Some(json!({ "collection": "cases_blue" }))).await;
+	assert!(status.is_success());
+	let (_, results) = query_alias().await;
+	assert_eq!(results[0]["embedding"]["id"], "blue");
+
+	let (status, _) = send(&router, Method::PUT, "/aliases/cases", Some(json!({ "collection": "cases_green" }))).await;
+	assert!(status.is_success());
+	let (_, results) = query_alias().await;
+	assert_eq!(results[0]["embedding"]["id"], "green");
+}
+
+#[tokio::test]
+async fn zero_vectors_are_a_client_error() {
+	let router = test_router(Db::in_memory());
+	let collection = json!({ "dimension": 2, "distance": "cosine" });
+	send(&router, Method::PUT, "/collections/cases", Some(collection)).await;
+
+	let (status, _) = send(
+		&router,
+		Method::POST,
+		"/collections/cases/insert",
+		Some(json!({ "id": "zero", "vector": [0.0, 0.0] })),
+	)
+	.await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+
+	let (_, info) = send(&router, Method::GET, "/collections/cases", None).await;
+	assert_eq!(info["embedding_count"], 0);
+}
+
+#[tokio::test]
+async fn shutting_down_needs_the_token() {
+	let router = test_router(Db::in_memory());
+
+	// SHUTDOWN_TOKEN isn't set in tests, which disables both routes
+	for uri in ["/shutdown", "/system/shutdown"] {
+		let (status, _) = send(&router, Method::POST, uri, None).await;
+		assert_eq!(status, StatusCode::FORBIDDEN);
+	}
+}
+
+#[tokio::test]
+async fn zero_k_is_a_client_error() {
+	let router = test_router(Db::in_memory());
+	let collection = json!({ "dimension": 2, "distance": "cosine" });
+	send(&router, Method::PUT, "/collections/cases", Some(collection)).await;
+	send(&router, Method::POST, "/collections/cases/insert", Some(json!({ "vector": [1.0, 0.0] }))).await;
+
+	let (status, _) = send(&router, Method::POST, "/collections/cases", Some(json!({ "query": [1.0, 0.0], "k": 0 }))).await;
+	assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn queries_for_another_distance_conflict() {
+	let router = test_router(Db::in_memory());
+	let collection = json!({ "dimension": 2, "distance": "cosine" });
+	send(&router, Method::PUT, "/collections/cases", Some(collection)).await;
+
+	let query = json!({ "query": [1.0, 0.0], "expect_distance": "euclidean" });
+	let (status, _) = send(&router, Method::POST, "/collections/cases", Some(query)).await;
+	assert_eq!(status, StatusCode::CONFLICT);
+
+	let query = json!({ "query": [1.0, 0.0], "expect_distance": "cosine" });
+	let (status, _) = send(&router, Method::POST, "/collections/cases", Some(query)).await;
+	assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn exports_import_back() {
+	let router = test_router(Db::in_memory());
+	for name in ["cases", "restored"] {
+		let collection = json!({ "dimension": 2, "distance": "dot" });
+		send(&router, Method::PUT, &format!("/collections/{name}"), Some(collection)).await;
+	}
+	for (id, vector) in [("3", [3.0, 0.5]), ("1", [1.0, 0.5]), ("2", [2.0, 0.5])] {
+		let embedding = json!({ "id": id, "vector": vector, "metadata": { "region": "north" } });
+		send(&router, Method::POST, "/collections/cases/insert", Some(embedding)).await;
+	}
+
+	let router = &router;
+	let export = move |name: &'static str| async move {
+		let uri = format!("/collections/{name}/export?sort=id");
+		let (status, body) = send_raw(router, Method::GET, &uri, "application/json", None).await;
+		assert_eq!(status, StatusCode::OK);
+
+		String::from_utf8(body).unwrap()
+	};
+	let exported = export("cases").await;
+	assert_eq!(exported.lines().count(), 3);
+
+	let (status, summary) = send_raw(
+		router,
+		Method::POST,
+		"/collections/restored/import_ndjson",
+		"application/x-ndjson",
+		Some(exported.clone()),
+	)
+	.await;
+	assert_eq!(status, StatusCode::OK);
+	let summary: Value = serde_json::from_slice(&summary).unwrap();
+	assert_eq!(summary["inserted"], 3);
+	assert_eq!(summary["errors"], json!([]));
+
+	// `seq` and `touched_at` are the server's own, so only compare what was sent
+	let contents = |export: &str| -> Vec<Value> {
+		export
+			.lines()
+			.map(|line| {
+				let embedding: Value = serde_json::from_str(line).unwrap();
+				json!([embedding["id"], embedding["vector"], embedding["metadata"]])
+			})
+			.collect()
+	};
+	assert_eq!(contents(&export("restored").await), contents(&exported));
+}
+
+#[tokio::test]
+async fn streams_query_results_best_first() {
+	let router = test_router(Db::in_memory());
+	let collection = json!({ "dimension": 2, "distance": "dot" });
+	send(&router, Method::PUT, "/collections/cases", Some(collection)).await;
+	for (id, vector) in [("low", [1.0, 0.0]), ("high", [3.0, 0.0]), ("middle", [2.0, 0.0])] {
+		send(&router, Method::POST, "/collections/cases/insert", Some(json!({ "id": id, "vector": vector }))).await;
+	}
+
+	let query = json!({ "query": [1.0, 0.0] }).to_string();
+	let (status, body) = send_raw(
+		&router,
+		Method::POST,
+		"/collections/cases/query_stream",
+		"application/json",
+		Some(query),
+	)
+	.await;
+	assert_eq!(status, StatusCode::OK);
+
+	let results: Vec<Value> = String::from_utf8(body)
+		.unwrap()
+		.lines()
+		.map(|line| serde_json::from_str(line).unwrap())
+		.collect();
+	let ids: Vec<&Value> = results.iter().map(|result| &result["embedding"]["id"]).collect();
+	assert_eq!(ids, ["high", "middle", "low"]);
+	assert_eq!(results[2]["rank"], 3);
+}
+
+#[tokio::test]
+async fn exports_a_parquet_file() {
+	let router = test_router(Db::in_memory());
+	let collection = json!({ "dimension": 2, "distance": "dot" });
+	send(&router, Method::PUT, "/collections/cases", Some(collection)).await;
+	for id in ["north", "south"] {
+		let embedding = json!({ "id": id, "vector": [1.0, 0.0], "metadata": { "region": id } });
+		send(&router, Method::POST, "/collections/cases/insert", Some(embedding)).await;
+	}
+
+	let (status, file) = send_raw(&router, Method::GET, "/collections/cases/export.parquet", "application/json", None).await;
+	assert_eq!(status, StatusCode::OK);
+	// Parquet files start and end with the same magic number
+	assert!(file.starts_with(b"PAR1") && file.ends_with(b"PAR1"));
+}
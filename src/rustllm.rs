@@ -1,108 +1,189 @@
-
-use axum::Extension;
-use llm::{ModelArchitecture, Model};
-use std::{
-	path::PathBuf,
-	sync::Arc,
-};
-use tokio::sync::RwLock;
-use std::{convert::Infallible, io::Write};
-
-use crate::LLMModelArgs;
-
-
-#[allow(clippy::module_name_repetitions)]
-pub type LLMExtension = Extension<Arc<RwLock<LLMModel>>>;
-
-pub struct LLMModel {
-    pub inference_parameters: llm::InferenceParameters,
-    pub model: Box<dyn Model> 
-}
-
-impl LLMModel {
-	pub fn new(args: LLMModelArgs) -> Self {
-        let tokenizer_source: llm::TokenizerSource = args.to_tokenizer_source();
-        let model_architecture: ModelArchitecture = args.model_architecture.unwrap();
-        let model_path: PathBuf = args.model_path.unwrap();
-        let model_params: llm::ModelParameters = llm::ModelParameters::default();
-        let inference_parameters: llm::InferenceParameters = llm::InferenceParameters::default();
-        let model: Box<dyn Model> = llm::load_dynamic(
-            Some(model_architecture),
-            &model_path,
-            tokenizer_source,
-            model_params,
-            llm::load_progress_callback_stdout,
-        ).unwrap_or_else(|err| {
-            panic!("Failed to load {model_architecture} model from {model_path:?}: {err}")
-        });
-		Self {
-            inference_parameters,
-            model
-		}
-	}
-
-	pub fn extension(self) -> LLMExtension {
-		Extension(Arc::new(RwLock::new(self)))
-	}
-
-    pub fn get_embeddings(
-        &self,
-        query: &str,
-    ) -> Vec<f32> {
-        let mut session = self.model.start_session(Default::default());
-        let mut output_request = llm::OutputRequest {
-            all_logits: None,
-            embeddings: Some(Vec::new()),
-        };
-        let vocab = self.model.tokenizer();
-        let beginning_of_sentence = true;
-        let query_token_ids = vocab
-            .tokenize(query, beginning_of_sentence)
-            .unwrap()
-            .iter()
-            .map(|(_, tok)| *tok)
-            .collect::<Vec<_>>();
-        self.model.evaluate(&mut session, &query_token_ids, &mut output_request);
-        output_request.embeddings.unwrap()
-    }
-
-
-    pub fn inference(&self, prompt: &str) -> Result<String,  llm::InferenceError> {
-        let mut session = self.model.start_session(Default::default());
-        let mut result = String::from("");
-        let _  = session.infer::<Infallible>(
-            self.model.as_ref(),
-            &mut rand::thread_rng(),
-            &llm::InferenceRequest {
-                prompt: prompt.into(),
-                parameters: &llm::InferenceParameters::default(),
-                play_back_previous_tokens: false,
-                maximum_token_count: None,
-            },
-            // OutputRequest
-            &mut Default::default(),
-            |r| match r {
-                llm::InferenceResponse::PromptToken(t) | llm::InferenceResponse::InferredToken(t) => {
-                    print!("{t}");
-                    result.push_str(&t);
-                    std::io::stdout().flush().unwrap();
-
-                    Ok(llm::InferenceFeedback::Continue)
-                }
-                _ => Ok(llm::InferenceFeedback::Continue),
-            },
-        );
-        println!("");
-        Ok(result)
-
-    }
-
-	
-}
-
-impl Drop for LLMModel {
-	fn drop(&mut self) {
-		tracing::info!("Closing llm");
-		//self.save_to_store().ok();
-	}
-}
+
+use axum::Extension;
+use llm::{ModelArchitecture, Model};
+use std::{
+	collections::HashMap,
+	path::Path,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+};
+use tokio::sync::{OwnedSemaphorePermit, RwLock, Semaphore};
+use std::{convert::Infallible, io::Write};
+
+use crate::{db::Db, LLMModelArgs};
+
+/// Text embedded once on load to find out a model's embedding dimension
+const DIMENSION_PROBE: &str = "dimension probe";
+
+
+#[allow(clippy::module_name_repetitions)]
+pub type LLMExtension = Extension<Arc<LLMModels>>;
+
+/// Every loaded model, by the name requests select it with
+pub struct LLMModels {
+    primary: String,
+    models: HashMap<String, RwLock<LLMModel>>,
+    /// Bounds the requests using a model at once, so the excess fails fast instead of queueing on the model locks
+    permits: Arc<Semaphore>,
+}
+
+impl LLMModels {
+    pub fn new(args: &LLMModelArgs) -> Self {
+        let mut models = HashMap::new();
+        let primary = LLMModel::new(
+            args.model_architecture.unwrap(),
+            args.model_path.as_deref().unwrap(),
+            args.to_tokenizer_source(),
+            args,
+        );
+        tracing::info!("Model {} produces {}-dimensional embeddings", args.model_name, primary.embedding_dimension);
+        models.insert(args.model_name.clone(), RwLock::new(primary));
+
+        for spec in &args.extra_models {
+            let model = LLMModel::new(spec.architecture, &spec.path, llm::TokenizerSource::Embedded, args);
+            tracing::info!("Model {} produces {}-dimensional embeddings", spec.name, model.embedding_dimension);
+            models.insert(spec.name.clone(), RwLock::new(model));
+        }
+
+        Self {
+            primary: args.model_name.clone(),
+            models,
+            permits: Arc::new(Semaphore::new(args.max_inference_concurrency)),
+        }
+    }
+
+    /// A slot to use a model in, or `None` when `MAX_INFERENCE_CONCURRENCY` requests already are.
+    /// The slot frees up when the permit is dropped.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.permits).try_acquire_owned().ok()
+    }
+
+    /// The model called `name`, or the primary model when no name is given
+    pub fn get(&self, name: Option<&str>) -> Option<&RwLock<LLMModel>> {
+        self.models.get(name.unwrap_or(&self.primary))
+    }
+
+    /// Warn about collections the primary model can't produce embeddings for, since their dimension differs
+    pub fn warn_dimension_mismatches(&self, db: &Db) {
+        // Nothing else holds the models yet, so this only fails if `primary` is missing
+        let Some(Ok(primary)) = self.models.get(&self.primary).map(RwLock::try_read) else {
+            return;
+        };
+        let dimension = primary.embedding_dimension;
+
+        for (name, collection) in &db.collections {
+            if collection.dimension != dimension {
+                tracing::warn!(
+                    "Collection {name} has dimension {} but model {} produces {dimension}-dimensional embeddings",
+                    collection.dimension,
+                    self.primary,
+                );
+            }
+        }
+    }
+
+	pub fn extension(self) -> LLMExtension {
+		Extension(Arc::new(self))
+	}
+}
+
+pub struct LLMModel {
+    pub inference_parameters: llm::InferenceParameters,
+    /// Length of the vectors `get_embeddings` returns, found by embedding a probe string on load
+    pub embedding_dimension: usize,
+    pub model: Box<dyn Model> 
+}
+
+impl LLMModel {
+	pub fn new(
+        model_architecture: ModelArchitecture,
+        model_path: &Path,
+        tokenizer_source: llm::TokenizerSource,
+        args: &LLMModelArgs,
+    ) -> Self {
+        let model_params: llm::ModelParameters = args.to_model_parameters();
+        let inference_parameters: llm::InferenceParameters = args.to_inference_parameters();
+
+        let model: Box<dyn Model> = llm::load_dynamic(
+            Some(model_architecture),
+            model_path,
+            tokenizer_source,
+            model_params,
+            llm::load_progress_callback_stdout,
+        ).unwrap_or_else(|err| {
+            panic!("Failed to load {model_architecture} model from {model_path:?}: {err}")
+        });
+		let mut model = Self {
+            inference_parameters,
+            embedding_dimension: 0,
+            model
+		};
+        model.embedding_dimension = model.get_embeddings(DIMENSION_PROBE).len();
+        model
+	}
+
+    pub fn get_embeddings(
+        &self,
+        query: &str,
+    ) -> Vec<f32> {
+        let mut session = self.model.start_session(Default::default());
+        let mut output_request = llm::OutputRequest {
+            all_logits: None,
+            embeddings: Some(Vec::new()),
+        };
+        let vocab = self.model.tokenizer();
+        let beginning_of_sentence = true;
+        let query_token_ids = vocab
+            .tokenize(query, beginning_of_sentence)
+            .unwrap()
+            .iter()
+            .map(|(_, tok)| *tok)
+            .collect::<Vec<_>>();
+        self.model.evaluate(&mut session, &query_token_ids, &mut output_request);
+        output_request.embeddings.unwrap()
+    }
+
+
+    /// Generate a completion for `prompt`, stopping early once `cancelled` is set
+    pub fn inference(&self, prompt: &str, cancelled: &AtomicBool) -> Result<String,  llm::InferenceError> {
+        let mut session = self.model.start_session(Default::default());
+        let mut result = String::from("");
+        let _  = session.infer::<Infallible>(
+            self.model.as_ref(),
+            &mut rand::thread_rng(),
+            &llm::InferenceRequest {
+                prompt: prompt.into(),
+                parameters: &self.inference_parameters,
+                play_back_previous_tokens: false,
+                maximum_token_count: None,
+            },
+            // OutputRequest
+            &mut Default::default(),
+            |r| match r {
+                _ if cancelled.load(Ordering::Relaxed) => Ok(llm::InferenceFeedback::Halt),
+                llm::InferenceResponse::PromptToken(t) | llm::InferenceResponse::InferredToken(t) => {
+                    print!("{t}");
+                    result.push_str(&t);
+                    std::io::stdout().flush().unwrap();
+
+                    Ok(llm::InferenceFeedback::Continue)
+                }
+                _ => Ok(llm::InferenceFeedback::Continue),
+            },
+        );
+        println!("");
+        Ok(result)
+
+    }
+
+	
+}
+
+impl Drop for LLMModel {
+	fn drop(&mut self) {
+		tracing::info!("Closing llm");
+		//self.save_to_store().ok();
+	}
+}
@@ -1,54 +1,141 @@
 use aide::openapi::{self, OpenApi};
 use anyhow::Result;
-use axum::{Extension, Server};
-use std::{env, net::SocketAddr};
+use axum::{
+	body::{Body, HttpBody},
+	extract::State,
+	http::{header, Request, StatusCode},
+	middleware::{self, Next},
+	response::{IntoResponse, Response},
+	Extension, Router, Server,
+};
+use std::{
+	env,
+	net::SocketAddr,
+	sync::atomic::{AtomicUsize, Ordering},
+	time::Duration,
+};
+use tokio::task::JoinHandle;
+use tower_http::{
+	compression::CompressionLayer,
+	decompression::{DecompressionBody, RequestDecompressionLayer},
+	map_request_body::MapRequestBodyLayer,
+};
 
-use crate::{db, routes, shutdown::Shutdown};
+use crate::{
+	audit::{self, AuditLog},
+	cache::QueryCache,
+	db::{self, Db, DbExtension},
+	errors::HTTPError,
+	idempotency::{self, Idempotency},
+	replica,
+	routes::{self, BodyLimits},
+	shutdown::{Agent, Shutdown},
+};
 #[cfg(feature = "llm")]
-use crate::{LLMModelArgs, rustllm::LLMModel};
+use crate::{LLMModelArgs, reembed::ReembedJobs, rustllm::LLMModels};
+#[cfg(feature = "grpc")]
+use crate::grpc;
+
+/// Body limit when `BODY_LIMIT_BYTES` isn't set, the same as axum's own default
+const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Number of requests currently being handled
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
 
 #[cfg(feature = "llm")]
 #[allow(clippy::redundant_pub_crate)]
 pub(crate) async fn start(args: LLMModelArgs) -> Result<()> {
-	let mut openapi = OpenApi {
-		info: openapi::Info {
-			title: "CrimeSceneBackend".to_string(),
-			version: env!("CARGO_PKG_VERSION").to_string(),
-			..openapi::Info::default()
-		},
-		..OpenApi::default()
-	};
-
-	let db = db::from_store()?;
 	let shutdown = Shutdown::new()?;
-	let router = routes::handler().finish_api(&mut openapi);
-	let router = router
-		.layer(Extension(openapi))
-		.layer(shutdown.extension())
-		.layer(db.extension());
-	let router = match args.available() {
-		true => {
-			let rustllm = LLMModel::new(args);
-			router.layer(rustllm.extension())
-		},
-		false => router
+	let models = args.available().then(|| LLMModels::new(&args).extension());
+	let (db, loading) = load_store(&shutdown, {
+		let models = models.clone();
+		move |db| {
+			if let Some(Extension(models)) = models {
+				models.warn_dimension_mismatches(db);
+			}
+		}
+	});
+	let router = router(&db, QueryCache::from_env()?, &shutdown, request_timeout()?, body_limits()?);
+	let router = match models {
+		Some(models) => router
+			.layer(models)
+			.layer(ReembedJobs::default().extension()),
+		None => router
 	};
-	let addr = SocketAddr::from((
-		[0, 0, 0, 0],
-		env::var("PORT").map_or(Ok(8000), |p| p.parse())?,
-	));
-	tracing::info!("Starting server on {addr}...");
-	Server::bind(&addr)
-		.serve(router.into_make_service())
-		.with_graceful_shutdown(shutdown.handle())
-		.await?;
 
-	Ok(())
+	serve(router, &shutdown, db).await?;
+	loaded(loading).await
 }
 
 #[cfg(not(feature = "llm"))]
 #[allow(clippy::redundant_pub_crate)]
 pub(crate) async fn start() -> Result<()> {
+	let shutdown = Shutdown::new()?;
+	let (db, loading) = load_store(&shutdown, |_| {});
+	let router = router(&db, QueryCache::from_env()?, &shutdown, request_timeout()?, body_limits()?);
+
+	serve(router, &shutdown, db).await?;
+	loaded(loading).await
+}
+
+/// Load the store in the background, so the server binds straight away and answers health checks while a large one
+/// is read. Until it's loaded, the database served is empty and read-only, and data routes answer with a 503.
+///
+/// `on_load` is handed the database before it's served. If loading fails, shutdown starts and the task resolves to
+/// the error.
+fn load_store(
+	shutdown: &Shutdown,
+	on_load: impl FnOnce(&Db) + Send + 'static,
+) -> (DbExtension, JoinHandle<Result<()>>) {
+	let mut placeholder = Db::new();
+	// Never written back, so shutting down before the load finishes leaves the store as it was
+	placeholder.read_only = true;
+	let db = placeholder.extension();
+	db::set_loading(true);
+
+	let agent = shutdown.agent();
+	let Extension(shared) = db.clone();
+	let task = tokio::spawn(async move {
+		let result: Result<()> = async {
+			let loaded = tokio::task::spawn_blocking(db::from_store).await??;
+			on_load(&loaded);
+			*shared.write().await = loaded;
+			replica::watch_store(&Extension(shared))
+		}
+		.await;
+
+		match &result {
+			Ok(()) => db::set_loading(false),
+			Err(err) => {
+				tracing::error!("Couldn't load the database from the store: {err:#}");
+				agent.start();
+			},
+		}
+		result
+	});
+
+	(db, task)
+}
+
+/// Why the store couldn't be loaded, if it couldn't, once the server has stopped. A load still running is abandoned.
+async fn loaded(loading: JoinHandle<Result<()>>) -> Result<()> {
+	if !loading.is_finished() {
+		loading.abort();
+		return Ok(());
+	}
+
+	loading.await?
+}
+
+/// The API routes with the extensions their handlers expect, apart from the LLM models,
+/// and without the connection-level layers `serve` adds
+fn router(
+	db: &DbExtension,
+	cache: QueryCache,
+	shutdown: &Shutdown,
+	request_timeout: Option<Duration>,
+	body_limits: BodyLimits,
+) -> Router {
 	let mut openapi = OpenApi {
 		info: openapi::Info {
 			title: "CrimeSceneBackend".to_string(),
@@ -58,22 +145,194 @@ pub(crate) async fn start() -> Result<()> {
 		..OpenApi::default()
 	};
 
-	let db = db::from_store()?;
-	let shutdown = Shutdown::new()?;
-	let router = routes::handler().finish_api(&mut openapi);
-	let router = router
+	routes::handler(request_timeout, body_limits)
+		.finish_api(&mut openapi)
 		.layer(Extension(openapi))
 		.layer(shutdown.extension())
-		.layer(db.extension());
+		.layer(cache.extension())
+		.layer(db.clone())
+}
+
+/// A router serving `db` with the default settings, for driving handlers in tests with `tower::ServiceExt::oneshot`.
+///
+/// It reads no environment variables and registers no signal handlers, so with a `Db::in_memory()` nothing touches
+/// the environment or the disk. LLM routes answer with a 500, as no models are loaded.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn test_router(db: Db) -> Router {
+	test_router_with_cache(db, QueryCache::default())
+}
+
+/// Like `test_router`, with similarity queries answered through `cache`
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn test_router_with_cache(db: Db, cache: QueryCache) -> Router {
+	let limits = BodyLimits {
+		default: DEFAULT_BODY_LIMIT,
+		query: DEFAULT_BODY_LIMIT,
+	};
+
+	router(&db.extension(), cache, &Shutdown::detached(), None, limits)
+}
+
+/// Request body limits, from `BODY_LIMIT_BYTES` and `QUERY_BODY_LIMIT_BYTES`.
+/// Queries share the general limit unless they're given their own, so large query vectors don't need it raised everywhere.
+fn body_limits() -> Result<BodyLimits> {
+	let default = env::var("BODY_LIMIT_BYTES")
+		.ok()
+		.map(|limit| limit.parse())
+		.transpose()?
+		.unwrap_or(DEFAULT_BODY_LIMIT);
+	let query = env::var("QUERY_BODY_LIMIT_BYTES")
+		.ok()
+		.map(|limit| limit.parse())
+		.transpose()?
+		.unwrap_or(default);
+
+	Ok(BodyLimits { default, query })
+}
+
+/// Timeout for data routes, from `REQUEST_TIMEOUT_MS`
+fn request_timeout() -> Result<Option<Duration>> {
+	Ok(env::var("REQUEST_TIMEOUT_MS")
+		.ok()
+		.map(|timeout| timeout.parse())
+		.transpose()?
+		.map(Duration::from_millis))
+}
+
+/// Serve `router` until shutdown, abandoning in-flight requests once `SHUTDOWN_TIMEOUT` (in seconds) has passed,
+/// and flush the database before returning.
+///
+/// Connections are tuned with these environment variables, with durations in seconds:
+/// - `HTTP2_ONLY`: only accept HTTP/2 (prior knowledge) connections. Defaults to `false`, serving HTTP/1.1 and HTTP/2 side by side.
+/// - `HTTP1_KEEPALIVE`: keep HTTP/1.1 connections open between requests. Defaults to `true`.
+/// - `HTTP2_KEEPALIVE_INTERVAL`: ping idle HTTP/2 connections this often. Off by default.
+/// - `HTTP2_KEEPALIVE_TIMEOUT`: close HTTP/2 connections whose ping isn't answered in time. Defaults to 20 seconds.
+/// - `TCP_KEEPALIVE`: send TCP keepalive probes after the connection is idle this long. Off by default.
+///
+/// Setting `AUDIT_LOG_PATH` also appends a record of every request to that file.
+/// Builds with the `grpc` feature also serve the gRPC interface on `GRPC_PORT` (50051 by default), until the same shutdown.
+/// Writes with an `Idempotency-Key` header are answered from memory when repeated within `IDEMPOTENCY_TTL_SECS`,
+/// remembering at most `IDEMPOTENCY_MAX_BYTES` of responses.
+/// Once shutdown starts, requests arriving on connections that are still open get a 503.
+/// If the store is still loading, the load is abandoned and nothing is flushed.
+async fn serve(router: Router, shutdown: &Shutdown, Extension(db): DbExtension) -> Result<()> {
 	let addr = SocketAddr::from((
 		[0, 0, 0, 0],
 		env::var("PORT").map_or(Ok(8000), |p| p.parse())?,
 	));
+	let shutdown_timeout = env_secs("SHUTDOWN_TIMEOUT")?;
+	let audit = AuditLog::from_env().await?;
+	let idempotency = Idempotency::from_env()?;
+	#[cfg(feature = "grpc")]
+	let grpc = {
+		let port = env::var("GRPC_PORT").map_or(Ok(50051), |p| p.parse())?;
+		grpc::spawn(db.clone(), SocketAddr::from(([0, 0, 0, 0], port)), shutdown)
+	};
+
+	let mut builder = Server::bind(&addr)
+		.http2_only(env_bool("HTTP2_ONLY")?.unwrap_or(false))
+		.http1_keepalive(env_bool("HTTP1_KEEPALIVE")?.unwrap_or(true))
+		.http2_keep_alive_interval(env_secs("HTTP2_KEEPALIVE_INTERVAL")?)
+		.tcp_keepalive(env_secs("TCP_KEEPALIVE")?);
+	if let Some(timeout) = env_secs("HTTP2_KEEPALIVE_TIMEOUT")? {
+		builder = builder.http2_keep_alive_timeout(timeout);
+	}
+
 	tracing::info!("Starting server on {addr}...");
-	Server::bind(&addr)
-		.serve(router.into_make_service())
-		.with_graceful_shutdown(shutdown.handle())
-		.await?;
+	let server = builder
+		.serve(
+			router
+				// gzip and brotli request bodies are decompressed before handlers see them,
+				// and responses are compressed for clients that accept it
+				.layer(MapRequestBodyLayer::new(into_body))
+				.layer(RequestDecompressionLayer::new())
+				// Inside compression, so replayed responses are encoded for the client asking rather than the first one
+				.layer(middleware::from_fn_with_state(idempotency, idempotency::deduplicate))
+				.layer(CompressionLayer::new())
+				.layer(middleware::from_fn_with_state(audit, audit::record))
+				.layer(middleware::from_fn(track_in_flight))
+				.layer(middleware::from_fn_with_state(shutdown.agent(), reject_while_draining))
+				.into_make_service(),
+		)
+		.with_graceful_shutdown(shutdown.handle());
+
+	match shutdown_timeout {
+		Some(timeout) => {
+			tokio::select! {
+				result = server => result?,
+				() = async {
+					shutdown.handle().await;
+					tokio::time::sleep(timeout).await;
+				} => {
+					tracing::warn!(
+						"Graceful shutdown timed out after {timeout:?}, dropping {} in-flight requests",
+						IN_FLIGHT.load(Ordering::SeqCst)
+					);
+				},
+			}
+		},
+		None => server.await?,
+	}
+
+	// Its in-flight requests get the same grace period as the REST ones, and are dropped along with them
+	#[cfg(feature = "grpc")]
+	match shutdown_timeout {
+		Some(timeout) if tokio::time::timeout(timeout, grpc).await.is_err() => {
+			tracing::warn!("gRPC server didn't shut down within {timeout:?}");
+		},
+		Some(_) => {},
+		None => grpc.await?,
+	}
+
+	tracing::info!("Flushing database to store");
+	db.read().await.save();
 
 	Ok(())
 }
+
+fn env_secs(name: &str) -> Result<Option<Duration>> {
+	Ok(env::var(name)
+		.ok()
+		.map(|secs| secs.parse())
+		.transpose()?
+		.map(Duration::from_secs))
+}
+
+fn env_bool(name: &str) -> Result<Option<bool>> {
+	Ok(env::var(name).ok().map(|value| value.parse()).transpose()?)
+}
+
+/// Hand decompressed request bodies to the routes as a plain `Body`, which is what their extractors are built for
+fn into_body(body: DecompressionBody<Body>) -> Body {
+	Body::wrap_stream(futures_util::stream::unfold(Box::pin(body), |mut body| async move {
+		body.data().await.map(|chunk| (chunk, body))
+	}))
+}
+
+struct InFlightGuard;
+
+impl Drop for InFlightGuard {
+	fn drop(&mut self) {
+		IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+async fn track_in_flight<B>(req: Request<B>, next: Next<B>) -> Response {
+	IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+	let _guard = InFlightGuard;
+
+	next.run(req).await
+}
+
+/// Turn requests away once shutdown has started, asking clients to reconnect elsewhere, while in-flight ones finish
+async fn reject_while_draining<B>(State(shutdown): State<Agent>, req: Request<B>, next: Next<B>) -> Response {
+	if !shutdown.is_draining() {
+		return next.run(req).await;
+	}
+
+	(
+		[(header::CONNECTION, "close"), (header::RETRY_AFTER, "1")],
+		HTTPError::new("The server is shutting down").with_status(StatusCode::SERVICE_UNAVAILABLE),
+	)
+		.into_response()
+}
@@ -4,7 +4,10 @@ use std::{
 	fmt,
 	fmt::Display,
 	future::Future,
-	sync::atomic::{AtomicBool, Ordering},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
 };
 use tokio::{signal, sync::broadcast};
 
@@ -24,17 +27,26 @@ static CREATED: AtomicBool = AtomicBool::new(false);
 #[derive(Debug, Clone)]
 pub struct Shutdown {
 	pub sender: broadcast::Sender<()>,
+	/// Set as soon as shutdown starts, however it was started
+	draining: Arc<AtomicBool>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Agent {
 	sender: broadcast::Sender<()>,
+	draining: Arc<AtomicBool>,
 }
 
 impl Agent {
 	pub fn start(&self) {
+		self.draining.store(true, Ordering::SeqCst);
 		self.sender.send(()).ok();
 	}
+
+	/// Whether shutdown has started, so new requests should be turned away while in-flight ones finish
+	pub fn is_draining(&self) -> bool {
+		self.draining.load(Ordering::SeqCst)
+	}
 }
 
 impl Shutdown {
@@ -45,16 +57,31 @@ impl Shutdown {
 		}
 
 		let (tx, _) = broadcast::channel(1);
+		let draining = Arc::new(AtomicBool::new(false));
 		let handle = register_handlers();
 
-		let tx_for_handle = tx.clone();
+		let agent = Agent {
+			sender: tx.clone(),
+			draining: Arc::clone(&draining),
+		};
 		tokio::spawn(async move {
 			tracing::debug!("Registered shutdown handlers");
 			handle.await;
-			tx_for_handle.send(()).ok();
+			agent.start();
 		});
 
-		Ok(Self { sender: tx })
+		Ok(Self { sender: tx, draining })
+	}
+
+	/// A shutdown only its agents can start, which leaves signals alone and so can be created any number of times
+	#[cfg_attr(not(test), allow(dead_code))]
+	pub fn detached() -> Self {
+		let (sender, _) = broadcast::channel(1);
+
+		Self {
+			sender,
+			draining: Arc::default(),
+		}
 	}
 
 	pub fn handle(&self) -> impl Future<Output = ()> + '_ {
@@ -70,6 +97,7 @@ impl Shutdown {
 	pub fn agent(&self) -> Agent {
 		Agent {
 			sender: self.sender.clone(),
+			draining: Arc::clone(&self.draining),
 		}
 	}
 
@@ -1,55 +1,437 @@
-use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
+use half::f16;
+use memmap2::Mmap;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{borrow::Cow, cmp::Ordering, sync::Arc};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+/// Metric a collection scores query results with, which decides what a result's `score` means.
+///
+/// `cosine` and `dot` are similarities, where higher scores are better matches. `euclidean`, `weighted` and `hamming` are
+/// distances, where lower scores are better matches. Results come best first either way.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq)]
 pub enum Distance {
+	/// `sqrt(sum((a_i - b_i)^2))`, from 0 for identical vectors upwards. Lower is better.
 	#[serde(rename = "euclidean")]
 	Euclidean,
+	/// `sum(a_i * b_i) / (|a| * |b|)`, from -1 for opposite vectors to 1 for vectors pointing the same way. Higher is better.
 	#[serde(rename = "cosine")]
 	Cosine,
+	/// `sum(a_i * b_i)`, unbounded and growing with the vectors' magnitudes. Higher is better.
 	#[serde(rename = "dot")]
 	DotProduct,
+	/// `sqrt(sum(w_i * (a_i - b_i)^2))`, with one non-negative weight `w_i` per dimension, from 0 upwards. Lower is better.
+	#[serde(rename = "weighted")]
+	Weighted(Vec<f32>),
+	/// Number of dimensions where the bits differ, for vectors made of 0s and 1s such as perceptual hashes.
+	/// From 0 for identical bit patterns to the dimension. Lower is better.
+	#[serde(rename = "hamming")]
+	Hamming,
 }
 
-pub fn get_cache_attr(metric: Distance, vec: &[f32]) -> f32 {
+/// Normalization applied to vectors as they're inserted, and to query vectors.
+///
+/// Cosine collections are always L2-normalized after this step, since cosine similarity is computed as the dot product of unit vectors.
+/// - `none`: vectors are stored as given.
+/// - `l2`: vectors are scaled to unit length, turning dot product into cosine similarity and euclidean into chord distance.
+/// - `standardize`: each dimension is z-scored using the collection's running mean and standard deviation.
+///   Vectors are standardized with the statistics at the time of their insertion, so early vectors drift as more data arrives.
+///   The statistics take in each new id's first vector, but not vectors replacing an existing one or rejected ones.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Normalization {
+	#[default]
+	None,
+	L2,
+	Standardize,
+}
+
+/// Running per-dimension mean and variance, maintained with Welford's algorithm
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DimensionStats {
+	count: u64,
+	mean: Vec<f64>,
+	m2: Vec<f64>,
+}
+
+impl DimensionStats {
+	#[allow(clippy::cast_precision_loss)]
+	pub fn update(&mut self, vec: &[f32]) {
+		if self.mean.len() != vec.len() {
+			*self = Self {
+				count: 0,
+				mean: vec![0.0; vec.len()],
+				m2: vec![0.0; vec.len()],
+			};
+		}
+
+		self.count += 1;
+		let count = self.count as f64;
+
+		for ((mean, m2), &value) in self.mean.iter_mut().zip(&mut self.m2).zip(vec) {
+			let value = f64::from(value);
+			let delta = value - *mean;
+
+			*mean += delta / count;
+			*m2 += delta * (value - *mean);
+		}
+	}
+
+	/// These statistics with `vec` added, leaving them as they are
+	#[must_use]
+	pub fn with(&self, vec: &[f32]) -> Self {
+		let mut stats = self.clone();
+		stats.update(vec);
+		stats
+	}
+
+	#[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation)]
+	pub fn standardize(&self, vec: &[f32]) -> Vec<f32> {
+		// Not enough data for a meaningful standard deviation yet
+		if self.count < 2 || self.mean.len() != vec.len() {
+			return vec.to_vec();
+		}
+
+		let count = self.count as f64;
+		vec.iter()
+			.zip(&self.mean)
+			.zip(&self.m2)
+			.map(|((&value, mean), m2)| {
+				let centered = f64::from(value) - mean;
+				let std_dev = (m2 / count).sqrt();
+
+				if std_dev > f64::EPSILON {
+					(centered / std_dev) as f32
+				} else {
+					centered as f32
+				}
+			})
+			.collect()
+	}
+}
+
+/// Precision vectors are stored in. Half precision halves the memory used by vectors,
+/// at the cost of roughly three significant decimal digits per component.
+///
+/// Query vectors are always kept in full precision: stored half-precision components are
+/// up-converted to `f32` while scoring, so only the stored side loses accuracy.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Precision {
+	#[default]
+	F32,
+	F16,
+}
+
+impl Precision {
+	pub const fn bytes_per_component(self) -> usize {
+		match self {
+			Self::F32 => std::mem::size_of::<f32>(),
+			Self::F16 => std::mem::size_of::<f16>(),
+		}
+	}
+
+	pub fn store(self, vec: Vec<f32>) -> Vector {
+		match self {
+			Self::F32 => Vector::F32(vec),
+			Self::F16 => Vector::F16(vec.into_iter().map(f16::from_f32).collect()),
+		}
+	}
+}
+
+/// A stored vector. It's always exchanged with clients as a list of `f32`s, but persisted in its stored precision.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Vector {
+	F32(Vec<f32>),
+	F16(Vec<f16>),
+	/// Lives in the memory-mapped vector file, and is paged in on demand
+	Mapped(MappedVector),
+}
+
+/// A window of `f32`s into the memory-mapped vector file
+#[derive(Debug, Clone)]
+pub struct MappedVector {
+	pub map: Arc<Mmap>,
+	pub offset: usize,
+	pub len: usize,
+}
+
+impl MappedVector {
+	pub fn as_slice(&self) -> &[f32] {
+		bytemuck::cast_slice(&self.map[self.offset..self.offset + self.len * std::mem::size_of::<f32>()])
+	}
+}
+
+impl PartialEq for MappedVector {
+	fn eq(&self, other: &Self) -> bool {
+		self.as_slice() == other.as_slice()
+	}
+}
+
+impl Vector {
+	pub fn len(&self) -> usize {
+		match self {
+			Self::F32(vec) => vec.len(),
+			Self::F16(vec) => vec.len(),
+			Self::Mapped(vec) => vec.len,
+		}
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Bytes of process memory the components take up. Mapped vectors live in the page cache instead, so take none.
+	pub fn heap_bytes(&self) -> usize {
+		match self {
+			Self::F32(vec) => std::mem::size_of_val(vec.as_slice()),
+			Self::F16(vec) => std::mem::size_of_val(vec.as_slice()),
+			Self::Mapped(_) => 0,
+		}
+	}
+
+	/// Drop excess capacity, returning the number of bytes freed. Mapped vectors own no memory to free.
+	pub fn shrink_to_fit(&mut self) -> usize {
+		match self {
+			Self::F32(vec) => shrink_vec(vec),
+			Self::F16(vec) => shrink_vec(vec),
+			Self::Mapped(_) => 0,
+		}
+	}
+
+	pub fn to_f32(&self) -> Cow<'_, [f32]> {
+		match self {
+			Self::F32(vec) => Cow::Borrowed(vec),
+			Self::F16(vec) => Cow::Owned(vec.iter().map(|&val| val.to_f32()).collect()),
+			Self::Mapped(vec) => Cow::Borrowed(vec.as_slice()),
+		}
+	}
+}
+
+/// Shrink `vec` to fit its contents, returning the number of bytes freed
+pub fn shrink_vec<T>(vec: &mut Vec<T>) -> usize {
+	let capacity = vec.capacity();
+	vec.shrink_to_fit();
+	(capacity - vec.capacity()) * std::mem::size_of::<T>()
+}
+
+impl From<Vec<f32>> for Vector {
+	fn from(vec: Vec<f32>) -> Self {
+		Self::F32(vec)
+	}
+}
+
+#[derive(Deserialize)]
+enum StoredVector {
+	F32(Vec<f32>),
+	F16(Vec<f16>),
+}
+
+impl Serialize for Vector {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		if serializer.is_human_readable() {
+			return self.to_f32().serialize(serializer);
+		}
+
+		match self {
+			Self::F32(vec) => serializer.serialize_newtype_variant("StoredVector", 0, "F32", vec),
+			Self::F16(vec) => serializer.serialize_newtype_variant("StoredVector", 1, "F16", vec),
+			Self::Mapped(vec) => {
+				serializer.serialize_newtype_variant("StoredVector", 0, "F32", vec.as_slice())
+			},
+		}
+	}
+}
+
+impl<'de> Deserialize<'de> for Vector {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		if deserializer.is_human_readable() {
+			return Vec::<f32>::deserialize(deserializer).map(Self::F32);
+		}
+
+		Ok(match StoredVector::deserialize(deserializer)? {
+			StoredVector::F32(vec) => Self::F32(vec),
+			StoredVector::F16(vec) => Self::F16(vec),
+		})
+	}
+}
+
+impl JsonSchema for Vector {
+	fn is_referenceable() -> bool {
+		false
+	}
+
+	fn schema_name() -> String {
+		Vec::<f32>::schema_name()
+	}
+
+	fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+		Vec::<f32>::json_schema(gen)
+	}
+}
+
+impl Distance {
+	/// Name the metric goes by in requests
+	pub const fn name(&self) -> &'static str {
+		match self {
+			Self::Euclidean => "euclidean",
+			Self::Cosine => "cosine",
+			Self::DotProduct => "dot",
+			Self::Weighted(_) => "weighted",
+			Self::Hamming => "hamming",
+		}
+	}
+
+	/// Whether a higher score means a closer match for this metric
+	pub const fn higher_is_better(&self) -> bool {
+		!matches!(self, Self::Euclidean | Self::Weighted(_) | Self::Hamming)
+	}
+
+	/// Key ordering scores so that closer matches are always greater, whichever way the metric points
+	pub fn rank_key(&self, score: f32) -> f32 {
+		if self.higher_is_better() {
+			score
+		} else {
+			-score
+		}
+	}
+
+	/// Whether `score` is at least as close a match as `threshold`
+	pub fn within(&self, score: f32, threshold: f32) -> bool {
+		if self.higher_is_better() {
+			score >= threshold
+		} else {
+			score <= threshold
+		}
+	}
+}
+
+pub fn get_cache_attr(metric: &Distance, vec: &[f32]) -> f32 {
 	match metric {
-		// Dot product doesn't allow any caching
-		Distance::DotProduct | Distance::Euclidean => 0.0,
+		// Distances are computed from the differences directly, so only cosine has anything worth caching
+		Distance::DotProduct | Distance::Euclidean | Distance::Weighted(_) | Distance::Hamming => 0.0,
 		// Precompute the magnitude of the vector
 		Distance::Cosine => vec.iter().map(|&x| x.powi(2)).sum::<f32>().sqrt(),
 	}
 }
 
-pub fn get_distance_fn(metric: Distance) -> impl Fn(&[f32], &[f32], f32) -> f32 {
-	match metric {
-		Distance::Euclidean => euclidian_distance,
+/// With `high_precision`, the sums are accumulated in `f64`, which is slower but keeps rounding error
+/// from reordering near-ties in high-dimensional collections.
+pub fn get_distance_fn<'a, T: Copy + Into<f32> + 'a>(
+	metric: &'a Distance,
+	high_precision: bool,
+) -> Box<dyn Fn(&[T], &[f32], f32) -> f32 + Send + Sync + 'a> {
+	match (metric, high_precision) {
+		(Distance::Euclidean, false) => Box::new(euclidian_distance::<T>),
+		(Distance::Euclidean, true) => Box::new(euclidian_distance_f64::<T>),
 		// We use dot product for cosine because we've normalized the vectors on insertion
-		Distance::Cosine | Distance::DotProduct => dot_product,
+		(Distance::Cosine | Distance::DotProduct, false) => Box::new(dot_product::<T>),
+		(Distance::Cosine | Distance::DotProduct, true) => Box::new(dot_product_f64::<T>),
+		(Distance::Weighted(weights), false) => Box::new(move |a: &[T], b: &[f32], _: f32| weighted_distance(a, b, weights)),
+		(Distance::Weighted(weights), true) => Box::new(move |a: &[T], b: &[f32], _: f32| weighted_distance_f64(a, b, weights)),
+		// Bit counts are exact, so there's nothing for higher precision to improve
+		(Distance::Hamming, _) => Box::new(hamming_distance::<T>),
 	}
 }
 
-fn euclidian_distance(a: &[f32], b: &[f32], a_sum_squares: f32) -> f32 {
-	let mut cross_terms = 0.0;
-	let mut b_sum_squares = 0.0;
+/// Distance function scoring a stored vector against a query, whatever precision it's stored in
+pub fn get_vector_distance_fn(
+	metric: &Distance,
+	high_precision: bool,
+) -> impl Fn(&Vector, &[f32], f32) -> f32 + '_ {
+	let full_distance_fn = get_distance_fn::<f32>(metric, high_precision);
+	let half_distance_fn = get_distance_fn::<f16>(metric, high_precision);
 
-	for (i, j) in a.iter().zip(b) {
-		cross_terms += i * j;
-		b_sum_squares += j.powi(2);
+	move |vector, query, memo_attr| match vector {
+		Vector::F32(vector) => full_distance_fn(vector, query, memo_attr),
+		Vector::F16(vector) => half_distance_fn(vector, query, memo_attr),
+		Vector::Mapped(vector) => full_distance_fn(vector.as_slice(), query, memo_attr),
 	}
+}
+
+fn euclidian_distance<T: Copy + Into<f32>>(a: &[T], b: &[f32], _: f32) -> f32 {
+	a.iter()
+		.zip(b)
+		.fold(0.0f32, |acc, (&x, y)| {
+			let diff = x.into() - y;
+			diff.mul_add(diff, acc)
+		})
+		.sqrt()
+}
+
+fn dot_product<T: Copy + Into<f32>>(a: &[T], b: &[f32], _: f32) -> f32 {
+	a.iter().zip(b).fold(0.0, |acc, (&x, y)| acc + x.into() * y)
+}
 
-	2.0f32
-		.mul_add(-cross_terms, a_sum_squares + b_sum_squares)
+#[allow(clippy::cast_possible_truncation)]
+fn euclidian_distance_f64<T: Copy + Into<f32>>(a: &[T], b: &[f32], _: f32) -> f32 {
+	a.iter()
+		.zip(b)
+		.fold(0.0f64, |acc, (&x, &y)| {
+			let diff = f64::from(x.into()) - f64::from(y);
+			diff.mul_add(diff, acc)
+		})
+		.sqrt() as f32
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn dot_product_f64<T: Copy + Into<f32>>(a: &[T], b: &[f32], _: f32) -> f32 {
+	a.iter()
+		.zip(b)
+		.fold(0.0f64, |acc, (&x, &y)| f64::from(x.into()).mul_add(f64::from(y), acc)) as f32
+}
+
+fn weighted_distance<T: Copy + Into<f32>>(a: &[T], b: &[f32], weights: &[f32]) -> f32 {
+	a.iter()
+		.zip(b)
+		.zip(weights)
+		.fold(0.0f32, |acc, ((&x, y), weight)| {
+			let diff = x.into() - y;
+			(weight * diff).mul_add(diff, acc)
+		})
 		.max(0.0)
 		.sqrt()
 }
 
-fn dot_product(a: &[f32], b: &[f32], _: f32) -> f32 {
-	a.iter().zip(b).fold(0.0, |acc, (x, y)| acc + x * y)
+#[allow(clippy::cast_possible_truncation)]
+fn weighted_distance_f64<T: Copy + Into<f32>>(a: &[T], b: &[f32], weights: &[f32]) -> f32 {
+	a.iter()
+		.zip(b)
+		.zip(weights)
+		.fold(0.0f64, |acc, ((&x, &y), &weight)| {
+			let diff = f64::from(x.into()) - f64::from(y);
+			(f64::from(weight) * diff).mul_add(diff, acc)
+		})
+		.max(0.0)
+		.sqrt() as f32
+}
+
+/// Query vectors aren't validated like stored ones, so components count as set from 0.5 up
+#[allow(clippy::cast_precision_loss)]
+fn hamming_distance<T: Copy + Into<f32>>(a: &[T], b: &[f32], _: f32) -> f32 {
+	a.iter()
+		.zip(b)
+		.filter(|&(&x, &y)| (x.into() >= 0.5) != (y >= 0.5))
+		.count() as f32
+}
+
+/// Whether every component is exactly 0 or 1, as Hamming distance expects
+#[allow(clippy::float_cmp)]
+pub fn is_binary(vec: &[f32]) -> bool {
+	vec.iter().all(|&x| x == 0.0 || x == 1.0)
+}
+
+/// Whether no component is infinite or NaN, which would make every score computed against the vector meaningless
+pub fn is_finite(vec: &[f32]) -> bool {
+	vec.iter().all(|x| x.is_finite())
+}
+
+pub fn magnitude(vec: &[f32]) -> f32 {
+	(vec.iter().fold(0.0, |acc, &val| val.mul_add(val, acc))).sqrt()
 }
 
+/// Scales `vec` to unit length. Vectors with (near-)zero magnitude are returned unchanged rather than divided by zero.
 pub fn normalize(vec: &[f32]) -> Vec<f32> {
-	let magnitude = (vec.iter().fold(0.0, |acc, &val| val.mul_add(val, acc))).sqrt();
+	let magnitude = magnitude(vec);
 
 	if magnitude > std::f32::EPSILON {
 		vec.iter().map(|&val| val / magnitude).collect()
@@ -58,6 +440,7 @@ pub fn normalize(vec: &[f32]) -> Vec<f32> {
 	}
 }
 
+#[derive(Clone, Copy)]
 pub struct ScoreIndex {
 	pub score: f32,
 	pub index: usize,
@@ -65,7 +448,7 @@ pub struct ScoreIndex {
 
 impl PartialEq for ScoreIndex {
 	fn eq(&self, other: &Self) -> bool {
-		self.score.eq(&other.score)
+		self.cmp(other) == Ordering::Equal
 	}
 }
 
@@ -73,13 +456,81 @@ impl Eq for ScoreIndex {}
 
 impl PartialOrd for ScoreIndex {
 	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-		// The comparison is intentionally reversed here to make the heap a min-heap
-		other.score.partial_cmp(&self.score)
+		Some(self.cmp(other))
 	}
 }
 
 impl Ord for ScoreIndex {
 	fn cmp(&self, other: &Self) -> Ordering {
-		self.partial_cmp(other).unwrap_or(Ordering::Equal)
+		// The comparison is intentionally reversed here to make the heap a min-heap.
+		// NaN ranks after every number whatever its sign, which keeps the order total, and adding 0.0 turns -0.0 into
+		// 0.0 so they tie as they compare. Ties go to the lower index, so the ranking doesn't depend on how it was
+		// selected.
+		self.score
+			.is_nan()
+			.cmp(&other.score.is_nan())
+			.then_with(|| (other.score + 0.0).total_cmp(&(self.score + 0.0)))
+			.then(self.index.cmp(&other.index))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn nan_scores_rank_last() {
+		let mut scores: Vec<ScoreIndex> = [f32::NAN, 1.0, -f32::NAN, 2.0, -0.0, 0.0]
+			.into_iter()
+			.enumerate()
+			.map(|(index, score)| ScoreIndex { score, index })
+			.collect();
+		scores.sort_unstable();
+
+		let indices: Vec<usize> = scores.iter().map(|score| score.index).collect();
+		assert_eq!(indices, [3, 1, 4, 5, 0, 2]);
+	}
+
+	/// A candidate that's far off in one dimension and slightly off in 100 more, and one that's only far off in the
+	/// first. Past 1e8 an `f32` sum can't grow by 1, so it ties them, which leaves their order to the index.
+	fn far_candidates() -> (Vec<f32>, Vec<f32>, Vec<f32>) {
+		let query = vec![0.0; 101];
+		let mut nearer = vec![0.0; 101];
+		nearer[0] = 1e4;
+		let mut farther = vec![1.0; 101];
+		farther[0] = 1e4;
+
+		(query, nearer, farther)
+	}
+
+	#[test]
+	#[allow(clippy::float_cmp)]
+	fn normalizing_a_zero_vector_leaves_it_as_zeros() {
+		let normalized = normalize(&[0.0; 4]);
+
+		assert!(normalized.iter().all(|&value| value == 0.0));
+	}
+
+	#[test]
+	#[allow(clippy::float_cmp)]
+	fn high_precision_euclidean_orders_what_f32_ties() {
+		let (query, nearer, farther) = far_candidates();
+
+		let f32_distance = get_distance_fn::<f32>(&Distance::Euclidean, false);
+		assert_eq!(f32_distance(&nearer, &query, 0.0), f32_distance(&farther, &query, 0.0));
+
+		let f64_distance = get_distance_fn::<f32>(&Distance::Euclidean, true);
+		assert!(f64_distance(&nearer, &query, 0.0) < f64_distance(&farther, &query, 0.0));
+	}
+
+	#[test]
+	fn euclidean_is_the_distance_between_vectors() {
+		for high_precision in [false, true] {
+			let distance = get_distance_fn::<f32>(&Distance::Euclidean, high_precision);
+
+			assert!((distance(&[3.0, 4.0], &[0.0, 0.0], 0.0) - 5.0).abs() < 1e-6);
+			// Large norms once clamped to 0, as the stored vector's own norm was left out
+			assert!((distance(&[1000.0, 0.0], &[999.0, 0.0], 0.0) - 1.0).abs() < 1e-6);
+		}
 	}
 }